@@ -231,9 +231,15 @@ const getConfigPaths = (workingDirectory?: string) => ({
   customPath: CUSTOM_CONFIG_FILE
 });
 
+// Drop a leading U+FEFF (Notepad and friends save UTF-8 that way) so parseJsonc and the `---` match in
+// parseMdFile see the real first character. Matches the web server's read path; the web server also
+// rejects invalid UTF-8, allows blank lines before `---` and strips the mark from {include:} files.
+// Those are web-only: here bad bytes decode to U+FFFD and frontmatter must start on the first line.
+const stripBom = (content: string): string => (content.charCodeAt(0) === 0xfeff ? content.slice(1) : content);
+
 const readConfigFile = (filePath?: string | null): Record<string, unknown> => {
   if (!filePath || !fs.existsSync(filePath)) return {};
-  const content = fs.readFileSync(filePath, 'utf8');
+  const content = stripBom(fs.readFileSync(filePath, 'utf8'));
   const normalized = content.trim();
   if (!normalized) return {};
   return parseJsonc(normalized, [], { allowTrailingComma: true }) as Record<string, unknown>;
@@ -341,7 +347,7 @@ const getJsonWriteTarget = (
 };
 
 const parseMdFile = (filePath: string): { frontmatter: Record<string, unknown>; body: string } => {
-  const content = fs.readFileSync(filePath, 'utf8');
+  const content = stripBom(fs.readFileSync(filePath, 'utf8'));
   const match = content.match(/^---\r?\n([\s\S]*?)\r?\n---\r?\n([\s\S]*)$/);
   if (!match) return { frontmatter: {}, body: content.trim() };
   let frontmatter: Record<string, unknown> = {};
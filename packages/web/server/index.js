@@ -2711,6 +2711,8 @@ async function startOpenCode() {
   // Note: SDK starts in current process CWD. openCodeWorkingDirectory is tracked but not used for spawn in SDK.
 
   try {
+    const { configPaths } = await import('./lib/opencode-config.js');
+    const { profile, configDir } = configPaths();
     const serverInstance = await createOpencodeServer({
       hostname: '127.0.0.1',
       port: desiredPort,
@@ -2718,6 +2720,8 @@ async function startOpenCode() {
       env: {
         ...process.env,
         // Pass minimal config to avoid pollution, but inherit PATH etc
+        // Point OpenCode at the active profile's config dir so it loads what we edit
+        ...(profile ? { OPENCODE_CONFIG_DIR: configDir } : {})
       }
     });
 
@@ -3876,7 +3880,9 @@ async function main(options = {}) {
       if (!directory) {
         return res.status(400).json({ error });
       }
-      const sources = getAgentSources(agentName, directory);
+      const sources = getAgentSources(agentName, directory, {
+        includeFrontmatter: req.query.includeFrontmatter === 'true'
+      });
 
       const scope = sources.md.exists
         ? sources.md.scope
@@ -3979,7 +3985,11 @@ async function main(options = {}) {
         return res.status(400).json({ error });
       }
 
-      deleteAgent(agentName, directory);
+      // Only the dependents check can be skipped from here; locks still apply
+      deleteAgent(agentName, directory, {
+        ignoreDependents: req.query.ignoreDependents === 'true',
+        clearReferences: req.query.clearReferences === 'true'
+      });
       await refreshOpenCodeAfterConfigChange('agent deletion');
 
       res.json({
@@ -3989,6 +3999,9 @@ async function main(options = {}) {
         reloadDelayMs: CLIENT_RELOAD_DELAY_MS,
       });
     } catch (error) {
+      if (error?.code === 'HAS_DEPENDENTS') {
+        return res.status(409).json({ error: error.message, dependents: error.dependents });
+      }
       console.error('Failed to delete agent:', error);
       res.status(500).json({ error: error.message || 'Failed to delete agent' });
     }
@@ -4032,7 +4045,7 @@ async function main(options = {}) {
       console.log('[Server] Config received:', JSON.stringify(config, null, 2));
       console.log('[Server] Scope:', scope, 'Working directory:', directory);
 
-      createCommand(commandName, config, directory, scope);
+      const { scope: savedScope, scopeFallback, warnings } = createCommand(commandName, config, directory, scope);
       await refreshOpenCodeAfterConfigChange('command creation', {
         commandName
       });
@@ -4042,6 +4055,9 @@ async function main(options = {}) {
         requiresReload: true,
         message: `Command ${commandName} created successfully. Reloading interface…`,
         reloadDelayMs: CLIENT_RELOAD_DELAY_MS,
+        scope: savedScope,
+        scopeFallback,
+        warnings,
       });
     } catch (error) {
       console.error('Failed to create command:', error);
@@ -4062,7 +4078,7 @@ async function main(options = {}) {
       console.log('[Server] Updates:', JSON.stringify(updates, null, 2));
       console.log('[Server] Working directory:', directory);
 
-      updateCommand(commandName, updates, directory);
+      const { warnings } = updateCommand(commandName, updates, directory);
       await refreshOpenCodeAfterConfigChange('command update');
 
       console.log(`[Server] Command ${commandName} updated successfully`);
@@ -4072,6 +4088,7 @@ async function main(options = {}) {
         requiresReload: true,
         message: `Command ${commandName} updated successfully. Reloading interface…`,
         reloadDelayMs: CLIENT_RELOAD_DELAY_MS,
+        warnings,
       });
     } catch (error) {
       console.error('[Server] Failed to update command:', error);
@@ -7850,6 +7867,16 @@ Context:
 
   if (attachSignals && !signalsAttached) {
     const handleSignal = async () => {
+      // Debounced config writes would otherwise be lost when the process exits
+      try {
+        const { flushPendingWrites } = await import('./lib/opencode-config.js');
+        const { failed } = flushPendingWrites();
+        for (const { path: failedPath, error } of failed) {
+          console.error(`Failed to flush pending config write to ${failedPath}:`, error);
+        }
+      } catch (error) {
+        console.error('Failed to flush pending config writes:', error);
+      }
       await gracefulShutdown();
     };
     process.on('SIGTERM', handleSignal);
@@ -0,0 +1,55 @@
+export const AGENT_TEMPLATES = [
+  {
+    name: 'code-reviewer',
+    description: 'Reviews changes for bugs, readability and missing tests',
+    frontmatter: {
+      description: 'Reviews changes for bugs, readability and missing tests',
+      mode: 'subagent',
+    },
+    prompt: [
+      'You are a careful code reviewer.',
+      '',
+      'Review the requested changes and report, in order of severity:',
+      '- Bugs and behavioral regressions',
+      '- Missing or insufficient tests',
+      '- Readability and naming issues',
+      '',
+      'Reference files and line numbers. Do not rewrite code unless asked.',
+    ].join('\n'),
+  },
+  {
+    name: 'docs-writer',
+    description: 'Writes and updates project documentation',
+    frontmatter: {
+      description: 'Writes and updates project documentation',
+      mode: 'subagent',
+    },
+    prompt: [
+      'You are a technical writer.',
+      '',
+      'Write clear, concise documentation that matches the tone and structure of the existing docs.',
+      'Prefer short examples over long explanations and keep headings consistent.',
+    ].join('\n'),
+  },
+  {
+    name: 'test-writer',
+    description: 'Adds focused tests for existing code',
+    frontmatter: {
+      description: 'Adds focused tests for existing code',
+      mode: 'subagent',
+    },
+    prompt: [
+      'You write tests.',
+      '',
+      'Follow the project\'s existing test layout and helpers.',
+      'Cover the main behavior and the edge cases most likely to break, and keep each test focused on one thing.',
+    ].join('\n'),
+  },
+];
+
+export function getAgentTemplates() {
+  return AGENT_TEMPLATES.map((template) => ({
+    ...template,
+    frontmatter: { ...template.frontmatter },
+  }));
+}
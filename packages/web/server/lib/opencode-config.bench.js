@@ -0,0 +1,69 @@
+// Compares the frontmatter-only .md read with the full parse, and the name-only listings with the full
+// listings, over directories of large-bodied agents and commands.
+// Run with: bun server/lib/opencode-config.bench.js
+import fs from 'fs';
+import os from 'os';
+import path from 'path';
+
+const AGENT_COUNT = 200;
+const BODY_BYTES = 256 * 1024;
+
+// Config paths are resolved when the module loads, so HOME must point at a sandbox before importing it
+const HOME = fs.mkdtempSync(path.join(os.tmpdir(), 'openchamber-config-bench-'));
+process.env.HOME = HOME;
+const config = await import('./opencode-config.js');
+
+const agentDir = path.join(HOME, '.config', 'opencode', 'agents');
+const commandDir = path.join(HOME, '.config', 'opencode', 'commands');
+fs.mkdirSync(agentDir, { recursive: true });
+fs.mkdirSync(commandDir, { recursive: true });
+const body = `${'You are a careful reviewer. '.repeat(Math.ceil(BODY_BYTES / 28)).slice(0, BODY_BYTES)}\n`;
+const agentNames = [];
+for (let index = 0; index < AGENT_COUNT; index++) {
+  const name = `agent-${index}`;
+  agentNames.push(name);
+  fs.writeFileSync(path.join(agentDir, `${name}.md`), `---\ndescription: Agent ${index}\nmodel: a/b\n---\n${body}`);
+  fs.writeFileSync(path.join(commandDir, `command-${index}.md`), `---\ndescription: Command ${index}\n---\n${body}`);
+}
+
+// Count bytes pulled from disk through the calls the config module uses
+let bytesRead = 0;
+const { readSync, readFileSync } = fs;
+fs.readSync = (...args) => {
+  const count = readSync(...args);
+  bytesRead += count;
+  return count;
+};
+fs.readFileSync = (...args) => {
+  const content = readFileSync(...args);
+  bytesRead += Buffer.byteLength(content);
+  return content;
+};
+
+const measure = (label, run) => {
+  bytesRead = 0;
+  const start = performance.now();
+  run();
+  const elapsed = performance.now() - start;
+  console.log(`${label.padEnd(32)} ${elapsed.toFixed(1).padStart(8)} ms ${(bytesRead / 1024 / 1024).toFixed(2).padStart(9)} MiB read`);
+};
+
+const sections = [
+  ['full parse (getAgentConfig)', () => agentNames.forEach((name) => config.getAgentConfig(name))],
+  ['frontmatter only', () => agentNames.forEach((name) => config.parseMdFrontmatterOnly(path.join(agentDir, `${name}.md`)))],
+  ['listAgents', () => config.listAgents()],
+  ['listAgentNames', () => config.listAgentNames()],
+  ['listCommands', () => config.listCommands()],
+  ['listCommandNames', () => config.listCommandNames()]
+];
+
+console.log(`${AGENT_COUNT} agents and ${AGENT_COUNT} commands with ${BODY_BYTES / 1024} KiB bodies`);
+try {
+  for (const [label, run] of sections) {
+    measure(label, run);
+  }
+} finally {
+  fs.readSync = readSync;
+  fs.readFileSync = readFileSync;
+  fs.rmSync(HOME, { recursive: true, force: true });
+}
@@ -1,18 +1,75 @@
+import { AsyncLocalStorage } from 'async_hooks';
+import { spawnSync } from 'child_process';
+import crypto from 'crypto';
 import fs from 'fs';
 import path from 'path';
 import os from 'os';
 import yaml from 'yaml';
-import { parse as parseJsonc } from 'jsonc-parser';
-
-const OPENCODE_CONFIG_DIR = path.join(os.homedir(), '.config', 'opencode');
+import { parse as parseJsonc, visit as visitJsonc, modify as modifyJsonc, applyEdits as applyJsoncEdits } from 'jsonc-parser';
+import { getAgentTemplates } from './agent-templates.js';
+
+const CONFIG_HOME_DIR = path.join(os.homedir(), '.config');
+const PROFILE_DIR_PREFIX = 'opencode-';
+const PROFILE_NAME_PATTERN = /^[A-Za-z0-9][\w.-]*$/;
+// OPENCODE_PROFILE=work targets ~/.config/opencode-work; every derived path follows it
+const ACTIVE_PROFILE = resolveProfileName(process.env.OPENCODE_PROFILE);
+const OPENCODE_CONFIG_DIR = getProfileConfigDir(ACTIVE_PROFILE);
 const AGENT_DIR = path.join(OPENCODE_CONFIG_DIR, 'agents');
 const COMMAND_DIR = path.join(OPENCODE_CONFIG_DIR, 'commands');
 const SKILL_DIR = path.join(OPENCODE_CONFIG_DIR, 'skills');
 const CONFIG_FILE = path.join(OPENCODE_CONFIG_DIR, 'opencode.json');
+const CONFIG_FILE_JSONC = path.join(OPENCODE_CONFIG_DIR, 'opencode.jsonc');
 const CUSTOM_CONFIG_FILE = process.env.OPENCODE_CONFIG
   ? path.resolve(process.env.OPENCODE_CONFIG)
   : null;
 const PROMPT_FILE_PATTERN = /^\{file:(.+)\}$/i;
+// Optional BOM and blank lines may precede the opening `---`; the frontmatter itself may be empty
+const FRONTMATTER_PATTERN = /^\uFEFF?(?:[ \t]*\r?\n)*---\r?\n(?:([\s\S]*?)\r?\n)?---\r?\n([\s\S]*)$/;
+const TEMPLATE_PLACEHOLDER_PATTERN = /\{\{\s*([A-Za-z_][\w-]*)\s*\}\}/g;
+const PROMPT_INCLUDE_PATTERN = /^\s*\{include:(.+)\}\s*$/i;
+const MAX_PROMPT_INCLUDE_DEPTH = 8;
+const MD_READ_CHUNK_SIZE = 4096;
+const BACKUP_SUFFIX = '.openchamber.backup';
+const TRASH_DIR = path.join(OPENCODE_CONFIG_DIR, '.openchamber-trash');
+const TRASH_MANIFEST = 'manifest.json';
+const DEFAULT_OPERATION_LOG_FILE = path.join(OPENCODE_CONFIG_DIR, 'openchamber-operations.jsonl');
+// Locks live in a sidecar OpenCode never reads, keyed by entity kind and lock scope:
+// { agent: { user: [names], '/abs/project': [names] }, command: { ... } }
+const LOCK_FILE = path.join(OPENCODE_CONFIG_DIR, 'openchamber-locks.json');
+const USER_LOCK_SCOPE = 'user';
+const AGENT_EXPORT_FORMAT = 'openchamber-agent';
+const AGENT_EXPORT_VERSION = 1;
+let backupDirOverride = null;
+const preWriteHooks = [];
+const postWriteHooks = [];
+const pendingConfigWrites = new Map();
+let configWriteDelayMs = 500;
+let requiredFieldsPolicy = { agent: [], command: [] };
+let agentSearchPathOverride = null;
+// Generous defaults: normal use never comes close, a runaway write loop does
+let writeLimits = { maxBytes: 8 * 1024 * 1024, maxFieldsPerEntity: 500 };
+let operationLogPath = null;
+let configValidator = null;
+// Staging state of the running applyBatch call; only code called from inside that call sees it
+const batchContext = new AsyncLocalStorage();
+// > 0 while a forced operation runs; the write-path lock checks let it through
+let lockOverrideDepth = 0;
+
+function resolveProfileName(value) {
+  const profile = typeof value === 'string' ? value.trim() : '';
+  if (!profile) {
+    return null;
+  }
+  if (!PROFILE_NAME_PATTERN.test(profile)) {
+    console.warn(`Ignoring invalid OPENCODE_PROFILE "${value}", using the default config dir`);
+    return null;
+  }
+  return profile;
+}
+
+function getProfileConfigDir(profile) {
+  return path.join(CONFIG_HOME_DIR, profile ? `${PROFILE_DIR_PREFIX}${profile}` : 'opencode');
+}
 
 // Scope types (shared by agents and commands)
 const AGENT_SCOPE = {
@@ -30,18 +87,134 @@ const SKILL_SCOPE = {
   PROJECT: 'project'
 };
 
-function ensureDirs() {
-  if (!fs.existsSync(OPENCODE_CONFIG_DIR)) {
-    fs.mkdirSync(OPENCODE_CONFIG_DIR, { recursive: true });
-  }
-  if (!fs.existsSync(AGENT_DIR)) {
-    fs.mkdirSync(AGENT_DIR, { recursive: true });
+// Entity kinds that can be stored across .md and opencode.json
+const ENTITY_KIND = {
+  AGENT: 'agent',
+  COMMAND: 'command'
+};
+
+const STORAGE_TARGET = {
+  MD: 'md',
+  JSON: 'json'
+};
+
+// Error codes attached to errors thrown by this module (error.code)
+const CONFIG_ERROR = {
+  MISSING_REQUIRED_FIELD: 'MISSING_REQUIRED_FIELD',
+  INVALID_SETTING: 'INVALID_SETTING',
+  LIMIT_EXCEEDED: 'LIMIT_EXCEEDED',
+  INVALID_POINTER: 'INVALID_POINTER',
+  PRECONDITION_FAILED: 'PRECONDITION_FAILED',
+  INVALID_FIELD_TYPE: 'INVALID_FIELD_TYPE',
+  NOT_FOUND: 'NOT_FOUND',
+  EMPTY: 'EMPTY',
+  LOCKED: 'LOCKED',
+  INVALID_ENCODING: 'INVALID_ENCODING',
+  OUT_OF_RANGE: 'OUT_OF_RANGE',
+  NOT_WRITABLE: 'NOT_WRITABLE',
+  CONCURRENT_DELETION: 'CONCURRENT_DELETION',
+  HAS_DEPENDENTS: 'HAS_DEPENDENTS',
+  INVALID_PATCH: 'INVALID_PATCH',
+  VERIFICATION_FAILED: 'VERIFICATION_FAILED',
+  ALREADY_EXISTS: 'ALREADY_EXISTS'
+};
+
+// Known field types used to coerce stringified UI values before writing
+const FIELD_TYPES = {
+  agent: {
+    disable: 'boolean',
+    temperature: 'number',
+    top_p: 'number',
+    maxSteps: 'number'
+  },
+  command: {
+    description: 'string',
+    'argument-hint': 'string',
+    agent: 'string',
+    model: 'string',
+    subtask: 'boolean'
   }
-  if (!fs.existsSync(COMMAND_DIR)) {
-    fs.mkdirSync(COMMAND_DIR, { recursive: true });
+};
+
+// Output styles for serializeConfig
+const SERIALIZE_STYLE = {
+  PRETTY: 'pretty',
+  COMPACT: 'compact',
+  SORTED: 'sorted'
+};
+
+// Valid ranges for numeric agent fields (inclusive)
+const AGENT_NUMERIC_RANGES = {
+  temperature: { min: 0, max: 2 },
+  top_p: { min: 0, max: 1 },
+  maxSteps: { min: 1, max: Infinity, integer: true }
+};
+
+// Keys OpenCode has renamed. `section` null is top-level, 'agent'/'command' applies to every entry
+// (json and .md frontmatter); `mapValue` converts the old value when the meaning changed too.
+const DEPRECATED_KEY_RENAMES = [
+  { section: null, from: 'mode', to: 'agent' },
+  { section: null, from: 'autoshare', to: 'share', mapValue: (value) => (value === true ? 'auto' : 'manual') }
+];
+
+// Agents and commands bundled with OpenCode (used when no explicit list is provided)
+const BUILTIN_AGENT_NAMES = ['build', 'plan', 'general', 'explore', 'title', 'summary', 'compaction'];
+// Built-ins users pick or invoke; title/summary/compaction are internal and must stay enabled
+const USER_FACING_BUILTIN_AGENT_NAMES = ['build', 'plan', 'general', 'explore'];
+const BUILTIN_COMMAND_NAMES = ['init', 'review'];
+const BUILTIN_THEME_NAMES = [
+  'system', 'opencode', 'tokyonight', 'everforest', 'ayu', 'catppuccin',
+  'gruvbox', 'kanagawa', 'nord', 'matrix', 'one-dark'
+];
+
+// Group key for commands without a namespace prefix
+const UNGROUPED_COMMAND_KEY = '(ungrouped)';
+
+function createConfigError(code, message, details = {}) {
+  const error = new Error(message);
+  error.code = code;
+  Object.assign(error, details);
+  return error;
+}
+
+function createNotWritableError(dir, cause) {
+  return createConfigError(
+    CONFIG_ERROR.NOT_WRITABLE,
+    `Config directory ${dir} is not writable (${cause.code}). Check its ownership and permissions, ` +
+      `e.g. after a sudo install: sudo chown -R "$USER" "${dir}"`,
+    { path: dir }
+  );
+}
+
+/**
+ * Map a permission failure from writing filePath to NOT_WRITABLE naming the directory that refused the write,
+ * so the UI can show an actionable message instead of a raw EACCES. Other errors are returned unchanged.
+ */
+function toNotWritableError(error, filePath) {
+  if (error?.code === 'EACCES' || error?.code === 'EPERM' || error?.code === 'EROFS') {
+    return createNotWritableError(path.dirname(error.path ?? filePath), error);
   }
-  if (!fs.existsSync(SKILL_DIR)) {
-    fs.mkdirSync(SKILL_DIR, { recursive: true });
+  return error;
+}
+
+/**
+ * Create the user config directories used by mutations. Read paths never call this.
+ * Best effort: a directory that can't be created (read-only or root-owned config, e.g. after a sudo install
+ * or baked into a container) is skipped with a warning, so project-scope writes still work. Writability is
+ * checked only for the directory a write actually targets, when it happens (NOT_WRITABLE from writeConfig/writeMdFile).
+ */
+function ensureDirs() {
+  for (const dir of [OPENCODE_CONFIG_DIR, AGENT_DIR, COMMAND_DIR, SKILL_DIR]) {
+    if (fs.existsSync(dir)) continue;
+    try {
+      recordBatchDir(dir);
+      fs.mkdirSync(dir, { recursive: true });
+    } catch (error) {
+      if (!['EACCES', 'EPERM', 'EROFS'].includes(error?.code)) {
+        throw error;
+      }
+      console.warn(`Cannot create config directory ${dir} (${error.code}), continuing`);
+    }
   }
 }
 
@@ -53,10 +226,12 @@ function ensureDirs() {
 function ensureProjectAgentDir(workingDirectory) {
   const projectAgentDir = path.join(workingDirectory, '.opencode', 'agents');
   if (!fs.existsSync(projectAgentDir)) {
+    recordBatchDir(projectAgentDir);
     fs.mkdirSync(projectAgentDir, { recursive: true });
   }
   const legacyProjectAgentDir = path.join(workingDirectory, '.opencode', 'agent');
   if (!fs.existsSync(legacyProjectAgentDir)) {
+    recordBatchDir(legacyProjectAgentDir);
     fs.mkdirSync(legacyProjectAgentDir, { recursive: true });
   }
   return projectAgentDir;
@@ -72,14 +247,65 @@ function getProjectAgentPath(workingDirectory, agentName) {
   return pluralPath;
 }
 
+/**
+ * Set additional user-level agent directories searched after the primary agent dir (null resets to env/default)
+ */
+function setAgentSearchPath(dirs) {
+  agentSearchPathOverride = Array.isArray(dirs) ? dirs.map((dir) => path.resolve(dir)) : null;
+}
+
+/**
+ * Additional agent directories: setter > OPENCHAMBER_AGENT_DIRS (path-delimiter separated) > none
+ */
+function getExtraAgentDirs() {
+  if (agentSearchPathOverride) {
+    return agentSearchPathOverride;
+  }
+  const fromEnv = process.env.OPENCHAMBER_AGENT_DIRS;
+  if (!fromEnv) {
+    return [];
+  }
+  return fromEnv.split(path.delimiter).map((dir) => dir.trim()).filter(Boolean).map((dir) => path.resolve(dir));
+}
+
+/**
+ * Agent dirs this module writes to: project and user dirs, without the extra (possibly shared) search dirs
+ */
+function getOwnedAgentDirs(workingDirectory) {
+  const extraDirs = getExtraAgentDirs();
+  return getAgentDirs(workingDirectory).filter(({ dir }) => !extraDirs.includes(dir));
+}
+
+/**
+ * Extra search dirs are read-only. Returns the extra dir holding filePath, or null.
+ */
+function getReadOnlyAgentDir(filePath) {
+  const resolved = path.resolve(filePath);
+  return getExtraAgentDirs().find((dir) => isWithinDirectory(resolved, dir)) ?? null;
+}
+
+/**
+ * The first .md file defining an agent in an extra search dir, or null
+ */
+function findReadOnlyAgentFile(agentName) {
+  for (const dir of getExtraAgentDirs()) {
+    const extraPath = path.join(dir, `${agentName}.md`);
+    if (fs.existsSync(extraPath)) return extraPath;
+  }
+  return null;
+}
+
 /**
  * Get user-level agent path
+ * Existing agents in extra search dirs are read from there; writeMdFile copies them to the primary agent dir
+ * on write, and that copy then takes precedence. New agents go to the primary agent dir.
  */
 function getUserAgentPath(agentName) {
   const pluralPath = path.join(AGENT_DIR, `${agentName}.md`);
   const legacyPath = path.join(OPENCODE_CONFIG_DIR, 'agent', `${agentName}.md`);
-  if (fs.existsSync(legacyPath) && !fs.existsSync(pluralPath)) return legacyPath;
-  return pluralPath;
+  if (fs.existsSync(pluralPath)) return pluralPath;
+  if (fs.existsSync(legacyPath)) return legacyPath;
+  return findReadOnlyAgentFile(agentName) ?? pluralPath;
 }
 
 /**
@@ -127,6 +353,53 @@ function getAgentWritePath(agentName, workingDirectory, requestedScope) {
   };
 }
 
+/**
+ * Get agent directories in precedence order (project > user, plural > legacy > extra search dirs)
+ */
+function getAgentDirs(workingDirectory) {
+  const dirs = [];
+  if (workingDirectory) {
+    dirs.push({ scope: AGENT_SCOPE.PROJECT, dir: path.join(workingDirectory, '.opencode', 'agents') });
+    dirs.push({ scope: AGENT_SCOPE.PROJECT, dir: path.join(workingDirectory, '.opencode', 'agent') });
+  }
+  dirs.push({ scope: AGENT_SCOPE.USER, dir: AGENT_DIR });
+  dirs.push({ scope: AGENT_SCOPE.USER, dir: path.join(OPENCODE_CONFIG_DIR, 'agent') });
+  for (const dir of getExtraAgentDirs()) {
+    dirs.push({ scope: AGENT_SCOPE.USER, dir });
+  }
+  return dirs;
+}
+
+/**
+ * Collect .md entities from directories in precedence order (first found wins)
+ * With { recursive: true }, nested files are named by their relative path (e.g. "git/commit")
+ * Returns: Map<name, { name, scope, path }>
+ */
+function listMdEntities(dirs, options = {}) {
+  const entities = new Map();
+
+  const walk = (scope, dir, prefix) => {
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+      if (entry.isDirectory() && options.recursive) {
+        walk(scope, path.join(dir, entry.name), `${prefix}${entry.name}/`);
+        continue;
+      }
+      if (!entry.isFile() || !entry.name.endsWith('.md')) continue;
+      const name = `${prefix}${entry.name.slice(0, -'.md'.length)}`;
+      if (!entities.has(name)) {
+        entities.set(name, { name, scope, path: path.join(dir, entry.name) });
+      }
+    }
+  };
+
+  for (const { scope, dir } of dirs) {
+    if (fs.existsSync(dir)) {
+      walk(scope, dir, '');
+    }
+  }
+  return entities;
+}
+
 /**
  * Detect where an agent's permission field is currently defined
  * Priority: project .md > user .md > project JSON > user JSON
@@ -137,7 +410,7 @@ function getAgentPermissionSource(agentName, workingDirectory) {
   if (workingDirectory) {
     const projectMdPath = getProjectAgentPath(workingDirectory, agentName);
     if (fs.existsSync(projectMdPath)) {
-      const { frontmatter } = parseMdFile(projectMdPath);
+      const { frontmatter } = parseMdFrontmatterOnly(projectMdPath);
       if (frontmatter.permission !== undefined) {
         return { source: 'md', scope: AGENT_SCOPE.PROJECT, path: projectMdPath };
       }
@@ -147,7 +420,7 @@ function getAgentPermissionSource(agentName, workingDirectory) {
   // Check user-level .md
   const userMdPath = getUserAgentPath(agentName);
   if (fs.existsSync(userMdPath)) {
-    const { frontmatter } = parseMdFile(userMdPath);
+    const { frontmatter } = parseMdFrontmatterOnly(userMdPath);
     if (frontmatter.permission !== undefined) {
       return { source: 'md', scope: AGENT_SCOPE.USER, path: userMdPath };
     }
@@ -185,10 +458,12 @@ function getAgentPermissionSource(agentName, workingDirectory) {
 function ensureProjectCommandDir(workingDirectory) {
   const projectCommandDir = path.join(workingDirectory, '.opencode', 'commands');
   if (!fs.existsSync(projectCommandDir)) {
+    recordBatchDir(projectCommandDir);
     fs.mkdirSync(projectCommandDir, { recursive: true });
   }
   const legacyProjectCommandDir = path.join(workingDirectory, '.opencode', 'command');
   if (!fs.existsSync(legacyProjectCommandDir)) {
+    recordBatchDir(legacyProjectCommandDir);
     fs.mkdirSync(legacyProjectCommandDir, { recursive: true });
   }
   return projectCommandDir;
@@ -214,6 +489,20 @@ function getUserCommandPath(commandName) {
   return pluralPath;
 }
 
+/**
+ * Get command directories in precedence order (project > user, plural > legacy)
+ */
+function getCommandDirs(workingDirectory) {
+  const dirs = [];
+  if (workingDirectory) {
+    dirs.push({ scope: COMMAND_SCOPE.PROJECT, dir: path.join(workingDirectory, '.opencode', 'commands') });
+    dirs.push({ scope: COMMAND_SCOPE.PROJECT, dir: path.join(workingDirectory, '.opencode', 'command') });
+  }
+  dirs.push({ scope: COMMAND_SCOPE.USER, dir: COMMAND_DIR });
+  dirs.push({ scope: COMMAND_SCOPE.USER, dir: path.join(OPENCODE_CONFIG_DIR, 'command') });
+  return dirs;
+}
+
 /**
  * Determine command scope based on where the .md file exists
  * Priority: project level > user level > null (built-in only)
@@ -471,28 +760,162 @@ function isPromptFileReference(value) {
   return PROMPT_FILE_PATTERN.test(value.trim());
 }
 
-function resolvePromptFilePath(reference) {
+function resolvePromptFilePath(reference, baseDir = OPENCODE_CONFIG_DIR) {
   const match = typeof reference === 'string' ? reference.trim().match(PROMPT_FILE_PATTERN) : null;
   if (!match) {
     return null;
   }
-  let target = match[1].trim();
+  return resolveConfigRelativePath(match[1].trim(), baseDir);
+}
+
+/**
+ * OpenCode resolves a relative {file:...} against the directory of the config file that contains it
+ */
+function getPromptFileBaseDir(configPath) {
+  return configPath ? path.dirname(configPath) : OPENCODE_CONFIG_DIR;
+}
+
+/**
+ * Canonical stored form of a {file:...} reference, so `x`, `./x` and `<config dir>/x` are all written as
+ * `{file:./x}`. References using ~ or $VAR, or pointing outside the config directory, keep their target as written.
+ * Anything that is not a file reference is returned unchanged.
+ */
+function normalizePromptReference(reference) {
+  if (!isPromptFileReference(reference)) {
+    return reference;
+  }
+  const target = reference.trim().match(PROMPT_FILE_PATTERN)[1].trim();
+  if (/^[~$]/.test(target)) {
+    return `{file:${target}}`;
+  }
+  const resolvedPath = path.resolve(resolveConfigRelativePath(target));
+  if (resolvedPath === OPENCODE_CONFIG_DIR || !isWithinDirectory(resolvedPath, OPENCODE_CONFIG_DIR)) {
+    return `{file:${target}}`;
+  }
+  return `{file:./${path.relative(OPENCODE_CONFIG_DIR, resolvedPath).split(path.sep).join('/')}}`;
+}
+
+/**
+ * Expand $VAR / ${VAR} and a leading ~ in a configured path.
+ * Undefined variables are left as written rather than collapsing to an unexpected path.
+ */
+function expandPathVariables(target) {
+  const expanded = target.replace(/\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))/g, (match, braced, bare) => {
+    const value = process.env[braced || bare];
+    return value === undefined ? match : value;
+  });
+  if (expanded === '~' || expanded.startsWith('~/') || expanded.startsWith('~\\')) {
+    return path.join(os.homedir(), expanded.slice(1));
+  }
+  return expanded;
+}
+
+/**
+ * Resolve a path from a {file:...} or {include:...} directive relative to baseDir, the config dir by default
+ * (after ~ and environment variable expansion)
+ */
+function resolveConfigRelativePath(target, baseDir = OPENCODE_CONFIG_DIR) {
   if (!target) {
     return null;
   }
 
+  target = expandPathVariables(target);
   if (target.startsWith('./')) {
     target = target.slice(2);
-    target = path.join(OPENCODE_CONFIG_DIR, target);
+    target = path.join(baseDir, target);
   } else if (!path.isAbsolute(target)) {
-    target = path.join(OPENCODE_CONFIG_DIR, target);
+    target = path.join(baseDir, target);
   }
 
   return target;
 }
 
+/**
+ * Expand {include:...} lines in a prompt. Included files may include further files,
+ * up to MAX_PROMPT_INCLUDE_DEPTH levels; cycles are rejected.
+ * @param {string} content - Prompt content
+ * @param {string[]} stack - Files currently being expanded (outermost first)
+ */
+function expandPromptIncludes(content, stack = []) {
+  return content.split(/\r?\n/).map((line) => {
+    const match = line.match(PROMPT_INCLUDE_PATTERN);
+    if (!match) {
+      return line;
+    }
+
+    const includePath = resolveConfigRelativePath(match[1].trim());
+    if (!includePath) {
+      return line;
+    }
+    if (stack.includes(includePath)) {
+      throw new Error(`Prompt include cycle detected: ${[...stack, includePath].join(' -> ')}`);
+    }
+    if (stack.length >= MAX_PROMPT_INCLUDE_DEPTH) {
+      throw new Error(`Prompt includes exceed maximum depth of ${MAX_PROMPT_INCLUDE_DEPTH}`);
+    }
+    if (!fs.existsSync(includePath)) {
+      throw new Error(`Prompt include not found: ${includePath}`);
+    }
+
+    const included = stripBom(readTextFile(includePath)).replace(/\r?\n$/, '');
+    return expandPromptIncludes(included, [...stack, includePath]);
+  }).join('\n');
+}
+
+function isPathWritable(targetPath) {
+  try {
+    fs.accessSync(targetPath, fs.constants.W_OK);
+    return true;
+  } catch {
+    return false;
+  }
+}
+
+function isWithinDirectory(targetPath, directory) {
+  const relative = path.relative(directory, targetPath);
+  return relative === '' || (!relative.startsWith('..') && !path.isAbsolute(relative));
+}
+
+/**
+ * Inspect a {file:...} prompt reference before offering to edit it
+ * Returns: { resolvedPath, exists, writable, withinConfigDir, safe }
+ * A missing file is writable when its nearest existing parent directory is.
+ */
+function getPromptReferenceStatus(reference) {
+  const resolvedPath = resolvePromptFilePath(reference);
+  if (!resolvedPath) {
+    throw new Error(`Not a prompt file reference: ${reference}`);
+  }
+
+  const exists = fs.existsSync(resolvedPath);
+  let writable;
+  if (exists) {
+    writable = fs.statSync(resolvedPath).isFile() && isPathWritable(resolvedPath);
+  } else {
+    let parent = path.dirname(resolvedPath);
+    while (!fs.existsSync(parent) && path.dirname(parent) !== parent) {
+      parent = path.dirname(parent);
+    }
+    writable = isPathWritable(parent);
+  }
+
+  const withinConfigDir = isWithinDirectory(path.resolve(resolvedPath), OPENCODE_CONFIG_DIR);
+  const target = reference.trim().match(PROMPT_FILE_PATTERN)[1];
+  const hasTraversal = target.split(/[\\/]/).includes('..');
+
+  return {
+    resolvedPath,
+    exists,
+    writable,
+    withinConfigDir,
+    safe: withinConfigDir && !hasTraversal
+  };
+}
+
 function writePromptFile(filePath, content) {
   const dir = path.dirname(filePath);
+  recordBatchDir(dir);
+  recordBatchWrite(filePath);
   fs.mkdirSync(dir, { recursive: true });
   fs.writeFileSync(filePath, content ?? '', 'utf8');
   console.log(`Updated prompt file: ${filePath}`);
@@ -619,20 +1042,131 @@ function mergePermissionWithNonWildcards(newPermission, permissionSource, agentN
   return merged;
 }
 
+/**
+ * User config file in use: opencode.json, or opencode.jsonc when only that exists.
+ * Writes go back to the same file so the user's chosen extension is preserved.
+ */
+function getUserConfigFile() {
+  if (!fs.existsSync(CONFIG_FILE) && fs.existsSync(CONFIG_FILE_JSONC)) {
+    return CONFIG_FILE_JSONC;
+  }
+  return CONFIG_FILE;
+}
+
+/**
+ * Report which user config variant is in use ('json' | 'jsonc'); 'json' when neither exists yet
+ */
+function configFileVariant() {
+  return getUserConfigFile() === CONFIG_FILE_JSONC ? 'jsonc' : 'json';
+}
+
 function getConfigPaths(workingDirectory) {
   return {
-    userPath: CONFIG_FILE,
+    userPath: getUserConfigFile(),
     projectPath: getProjectConfigPath(workingDirectory),
     customPath: CUSTOM_CONFIG_FILE
   };
 }
 
+function createEncodingError(filePath) {
+  return createConfigError(
+    CONFIG_ERROR.INVALID_ENCODING,
+    `${filePath} is not valid UTF-8 (re-save it as UTF-8)`,
+    { path: filePath }
+  );
+}
+
+/**
+ * Read a text file as strict UTF-8. Throws INVALID_ENCODING naming the file instead of
+ * silently substituting replacement characters.
+ */
+function readTextFile(filePath) {
+  const buffer = fs.readFileSync(filePath);
+  try {
+    return new TextDecoder('utf-8', { fatal: true }).decode(buffer);
+  } catch {
+    throw createEncodingError(filePath);
+  }
+}
+
+/**
+ * Recovery read for files rejected with INVALID_ENCODING: invalid bytes become U+FFFD.
+ * Returns: { content, replacedOffsets } - byte offsets of the sequences that were replaced
+ */
+function readTextFileLossy(filePath) {
+  const buffer = fs.readFileSync(filePath);
+  // Offsets follow the WHATWG UTF-8 decoder the content comes from, so overlong forms and surrogates count
+  // and there is one offset per U+FFFD it emits
+  const replacedOffsets = [];
+  let needed = 0;
+  let seen = 0;
+  let lower = 0x80;
+  let upper = 0xbf;
+  let sequenceStart = 0;
+  for (let index = 0; index < buffer.length; index++) {
+    const byte = buffer[index];
+    if (needed === 0) {
+      sequenceStart = index;
+      if (byte <= 0x7f) continue;
+      if (byte >= 0xc2 && byte <= 0xdf) {
+        needed = 1;
+      } else if (byte >= 0xe0 && byte <= 0xef) {
+        if (byte === 0xe0) lower = 0xa0;
+        if (byte === 0xed) upper = 0x9f;
+        needed = 2;
+      } else if (byte >= 0xf0 && byte <= 0xf4) {
+        if (byte === 0xf0) lower = 0x90;
+        if (byte === 0xf4) upper = 0x8f;
+        needed = 3;
+      } else {
+        replacedOffsets.push(index);
+      }
+      continue;
+    }
+    if (byte < lower || byte > upper) {
+      // The truncated sequence is replaced and this byte starts over as a new sequence
+      replacedOffsets.push(sequenceStart);
+      needed = 0;
+      seen = 0;
+      lower = 0x80;
+      upper = 0xbf;
+      index -= 1;
+      continue;
+    }
+    lower = 0x80;
+    upper = 0xbf;
+    seen += 1;
+    if (seen === needed) {
+      needed = 0;
+      seen = 0;
+    }
+  }
+  if (needed > 0) {
+    replacedOffsets.push(sequenceStart);
+  }
+  return { content: new TextDecoder('utf-8', { fatal: false }).decode(buffer), replacedOffsets };
+}
+
+function stripBom(content) {
+  // Some Windows editors prefix UTF-8 files with a BOM, which breaks JSON parsing and frontmatter matching
+  return typeof content === 'string' && content.charCodeAt(0) === 0xfeff ? content.slice(1) : content;
+}
+
 function readConfigFile(filePath) {
+  const batch = getActiveBatch();
+  if (batch?.json.has(filePath)) {
+    return structuredClone(batch.json.get(filePath));
+  }
+  // A scheduled write is what the file will hold; reading it keeps read-modify-write callers from undoing it
+  const pending = pendingConfigWrites.get(filePath);
+  if (pending) {
+    return structuredClone(pending.config);
+  }
   if (!filePath || !fs.existsSync(filePath)) {
     return {};
   }
   try {
-    const content = fs.readFileSync(filePath, 'utf8');
+    const content = stripBom(readTextFile(filePath));
     const normalized = content.trim();
     if (!normalized) {
       return {};
@@ -640,6 +1174,9 @@ function readConfigFile(filePath) {
     // jsonc-parser handles comments, trailing commas, unquoted keys
     return parseJsonc(normalized, [], { allowTrailingComma: true });
   } catch (error) {
+    if (error?.code === CONFIG_ERROR.INVALID_ENCODING) {
+      throw error;
+    }
     console.error(`Failed to read config file: ${filePath}`, error);
     throw new Error('Failed to read OpenCode configuration');
   }
@@ -689,799 +1226,5446 @@ function readConfig(workingDirectory) {
   return readConfigLayers(workingDirectory).mergedConfig;
 }
 
-function getConfigForPath(layers, targetPath) {
-  if (!targetPath) {
-    return layers.userConfig;
-  }
-  if (layers.paths.customPath && targetPath === layers.paths.customPath) {
-    return layers.customConfig;
-  }
-  if (layers.paths.projectPath && targetPath === layers.paths.projectPath) {
-    return layers.projectConfig;
-  }
-  return layers.userConfig;
+function escapeJsonPointerSegment(segment) {
+  return String(segment).replace(/~/g, '~0').replace(/\//g, '~1');
 }
 
-function writeConfig(config, filePath = CONFIG_FILE) {
-  try {
-    if (fs.existsSync(filePath)) {
-      const backupFile = `${filePath}.openchamber.backup`;
-      fs.copyFileSync(filePath, backupFile);
-      console.log(`Created config backup: ${backupFile}`);
+/**
+ * Structural diff between two configs. Objects are compared key by key, other values (incl. arrays) as a whole.
+ * Returns: [{ pointer, type: 'added'|'removed'|'changed', before, after }]
+ */
+function diffConfigs(previous, next, pointer = '') {
+  if (isPlainObject(previous) && isPlainObject(next)) {
+    const changes = [];
+    const keys = new Set([...Object.keys(previous), ...Object.keys(next)]);
+    for (const key of keys) {
+      const childPointer = `${pointer}/${escapeJsonPointerSegment(key)}`;
+      if (!(key in next)) {
+        changes.push({ pointer: childPointer, type: 'removed', before: previous[key], after: undefined });
+      } else if (!(key in previous)) {
+        changes.push({ pointer: childPointer, type: 'added', before: undefined, after: next[key] });
+      } else {
+        changes.push(...diffConfigs(previous[key], next[key], childPointer));
+      }
     }
+    return changes;
+  }
 
-    fs.mkdirSync(path.dirname(filePath), { recursive: true });
-    fs.writeFileSync(filePath, JSON.stringify(config, null, 2), 'utf8');
-    console.log(`Successfully wrote config file: ${filePath}`);
-  } catch (error) {
-    console.error(`Failed to write config file: ${filePath}`, error);
-    throw new Error('Failed to write OpenCode configuration');
+  if (JSON.stringify(previous) === JSON.stringify(next)) {
+    return [];
   }
+  return [{ pointer, type: 'changed', before: previous, after: next }];
 }
 
-function getJsonEntrySource(layers, sectionKey, entryName) {
-  const { userConfig, projectConfig, customConfig, paths } = layers;
-  const customSection = customConfig?.[sectionKey]?.[entryName];
-  if (customSection !== undefined) {
-    return { section: customSection, config: customConfig, path: paths.customPath, exists: true };
-  }
+function toJsonPointer(segments) {
+  return segments.map((segment) => `/${escapeJsonPointerSegment(segment)}`).join('');
+}
 
-  const projectSection = projectConfig?.[sectionKey]?.[entryName];
-  if (projectSection !== undefined) {
-    return { section: projectSection, config: projectConfig, path: paths.projectPath, exists: true };
+/**
+ * Split an RFC 6901 JSON pointer into unescaped segments ('' is the whole document)
+ */
+function parseJsonPointer(pointer) {
+  if (pointer === '') {
+    return [];
   }
-
-  const userSection = userConfig?.[sectionKey]?.[entryName];
-  if (userSection !== undefined) {
-    return { section: userSection, config: userConfig, path: paths.userPath, exists: true };
+  if (typeof pointer !== 'string' || !pointer.startsWith('/')) {
+    throw createConfigError(CONFIG_ERROR.INVALID_POINTER, `Invalid JSON pointer: ${pointer}`, { pointer });
   }
-
-  return { section: null, config: null, path: null, exists: false };
+  return pointer.slice(1).split('/').map((segment) => segment.replace(/~1/g, '/').replace(/~0/g, '~'));
 }
 
-function getJsonWriteTarget(layers, preferredScope) {
-  const { userConfig, projectConfig, customConfig, paths } = layers;
-  if (paths.customPath) {
-    return { config: customConfig, path: paths.customPath };
-  }
-  if (preferredScope === AGENT_SCOPE.PROJECT && paths.projectPath) {
-    return { config: projectConfig, path: paths.projectPath };
+function stripCommentMarkers(comment) {
+  if (comment.startsWith('//')) {
+    return comment.slice(2).trim();
   }
-  if (paths.projectPath) {
-    return { config: projectConfig, path: paths.projectPath };
-  }
-  return { config: userConfig, path: paths.userPath };
+  return comment.replace(/^\/\*+/, '').replace(/\*+\/$/, '').split(/\r?\n/)
+    .map((line) => line.replace(/^\s*\*\s?/, '').trim())
+    .join('\n')
+    .trim();
 }
 
-function parseMdFile(filePath) {
-  const content = fs.readFileSync(filePath, 'utf8');
-  const match = content.match(/^---\r?\n([\s\S]*?)\r?\n---\r?\n([\s\S]*)$/);
-
-  if (!match) {
-    return { frontmatter: {}, body: content.trim() };
+/**
+ * Read a config file along with its comments, each attached to the JSON pointer of the property it annotates.
+ * A comment on the same line after a property trails that property; otherwise it leads the next property.
+ * Comments after the last property are reported as 'dangling' on the root pointer.
+ * Returns: { config, comments: [{ pointer, position: 'leading'|'trailing'|'dangling', text }] }
+ */
+function readConfigAnnotated(filePath = getUserConfigFile()) {
+  const config = readConfigFile(filePath);
+  if (!filePath || !fs.existsSync(filePath)) {
+    return { config, comments: [] };
   }
 
-  let frontmatter = {};
-  try {
-    frontmatter = yaml.parse(match[1]) || {};
-  } catch (error) {
-    console.warn(`Failed to parse markdown frontmatter ${filePath}, treating as empty:`, error);
-    frontmatter = {};
-  }
+  const content = stripBom(readTextFile(filePath));
+  const properties = [];
+  const rawComments = [];
+  visitJsonc(content, {
+    onObjectProperty: (property, offset, _length, startLine, _startCharacter, pathSupplier) => {
+      properties.push({ pointer: toJsonPointer([...pathSupplier(), property]), offset, line: startLine });
+    },
+    onComment: (offset, length, startLine) => {
+      rawComments.push({ offset, line: startLine, text: content.slice(offset, offset + length) });
+    }
+  });
+
+  const comments = rawComments.map((comment) => {
+    const text = stripCommentMarkers(comment.text);
+    const trailing = properties
+      .filter((property) => property.line === comment.line && property.offset < comment.offset)
+      .pop();
+    if (trailing) {
+      return { pointer: trailing.pointer, position: 'trailing', text };
+    }
+    const leading = properties.find((property) => property.offset > comment.offset);
+    if (leading) {
+      return { pointer: leading.pointer, position: 'leading', text };
+    }
+    return { pointer: '', position: 'dangling', text };
+  });
 
-  const body = match[2].trim();
-  return { frontmatter, body };
+  return { config, comments };
 }
 
-function writeMdFile(filePath, frontmatter, body) {
-  try {
-    // Filter out null/undefined values - OpenCode expects keys to be omitted rather than set to null
-    const cleanedFrontmatter = Object.fromEntries(
-      Object.entries(frontmatter).filter(([, value]) => value != null)
-    );
-    const yamlStr = yaml.stringify(cleanedFrontmatter);
-    const content = `---\n${yamlStr}---\n\n${body}`;
-    fs.writeFileSync(filePath, content, 'utf8');
-    console.log(`Successfully wrote markdown file: ${filePath}`);
-  } catch (error) {
-    console.error(`Failed to write markdown file ${filePath}:`, error);
-    throw new Error('Failed to write agent markdown file');
+/**
+ * Strict variant of readConfigFile for callers that need a real config (validation, export).
+ * Throws NOT_FOUND for a missing file and EMPTY for a blank one instead of returning {}.
+ */
+function readConfigStrict(filePath = getUserConfigFile()) {
+  if (!filePath || !fs.existsSync(filePath)) {
+    throw createConfigError(CONFIG_ERROR.NOT_FOUND, `Config file not found: ${filePath}`, { path: filePath });
   }
+  if (!stripBom(readTextFile(filePath)).trim()) {
+    throw createConfigError(CONFIG_ERROR.EMPTY, `Config file is empty: ${filePath}`, { path: filePath });
+  }
+  return readConfigFile(filePath);
 }
 
-function getAgentSources(agentName, workingDirectory) {
-  // Check project level first (takes precedence)
-  const projectPath = workingDirectory ? getProjectAgentPath(workingDirectory, agentName) : null;
-  const projectExists = projectPath && fs.existsSync(projectPath);
-  
-  // Then check user level
-  const userPath = getUserAgentPath(agentName);
-  const userExists = fs.existsSync(userPath);
-  
-  // Determine which md file to use (project takes precedence)
-  const mdPath = projectExists ? projectPath : (userExists ? userPath : null);
-  const mdExists = !!mdPath;
-  const mdScope = projectExists ? AGENT_SCOPE.PROJECT : (userExists ? AGENT_SCOPE.USER : null);
-
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
-  const jsonSection = jsonSource.section;
-  const jsonPath = jsonSource.path || layers.paths.customPath || layers.paths.projectPath || layers.paths.userPath;
-  const jsonScope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+/**
+ * Read a config file plus non-fatal diagnostics. readConfigFile stays the plain variant.
+ * Duplicate keys are reported because the parser silently keeps the last value. Comments are reported
+ * only for files writeConfig re-serializes; .jsonc files are edited in place and keep them.
+ * Returns: { config, diagnostics: [{ type: 'duplicate-key', pointer, line, message } | { type: 'comments', count, message }] }
+ */
+function readConfigWithDiagnostics(filePath = getUserConfigFile()) {
+  const config = readConfigFile(filePath);
+  if (!filePath || !fs.existsSync(filePath)) {
+    return { config, diagnostics: [] };
+  }
 
-  const sources = {
-    md: {
-      exists: mdExists,
-      path: mdPath,
-      scope: mdScope,
-      fields: []
+  const content = stripBom(readTextFile(filePath));
+  const diagnostics = [];
+  const objectKeys = [];
+  let commentCount = 0;
+  visitJsonc(content, {
+    onObjectBegin: () => {
+      objectKeys.push(new Set());
     },
-    json: {
-      exists: jsonSource.exists,
-      path: jsonPath,
-      scope: jsonSource.exists ? jsonScope : null,
-      fields: []
+    onObjectEnd: () => {
+      objectKeys.pop();
     },
-    // Additional info about both levels
-    projectMd: {
-      exists: projectExists,
-      path: projectPath
+    onObjectProperty: (property, _offset, _length, startLine, _startCharacter, pathSupplier) => {
+      const keys = objectKeys[objectKeys.length - 1];
+      if (!keys) return;
+      if (keys.has(property)) {
+        const pointer = toJsonPointer([...pathSupplier(), property]);
+        diagnostics.push({
+          type: 'duplicate-key',
+          pointer,
+          line: startLine + 1,
+          message: `Duplicate key ${pointer} on line ${startLine + 1}; only the last value is kept`
+        });
+      }
+      keys.add(property);
     },
-    userMd: {
-      exists: userExists,
-      path: userPath
-    }
-  };
-
-  if (mdExists) {
-    const { frontmatter, body } = parseMdFile(mdPath);
-    sources.md.fields = Object.keys(frontmatter);
-    if (body) {
-      sources.md.fields.push('prompt');
+    onComment: () => {
+      commentCount += 1;
     }
+  });
+
+  if (commentCount > 0 && !filePath.endsWith('.jsonc')) {
+    diagnostics.push({
+      type: 'comments',
+      count: commentCount,
+      message: `${commentCount} comment(s) will be dropped when this file is rewritten`
+    });
   }
 
-  if (jsonSection) {
-    sources.json.fields = Object.keys(jsonSection);
-  }
-
-  return sources;
+  return { config, diagnostics };
 }
 
-function getAgentConfig(agentName, workingDirectory) {
-  // Prefer markdown agents (project > user)
-  const projectPath = workingDirectory ? getProjectAgentPath(workingDirectory, agentName) : null;
-  const projectExists = projectPath && fs.existsSync(projectPath);
-
-  const userPath = getUserAgentPath(agentName);
-  const userExists = fs.existsSync(userPath);
-
-  if (projectExists || userExists) {
-    const mdPath = projectExists ? projectPath : userPath;
-    const { frontmatter, body } = parseMdFile(mdPath);
+/**
+ * Re-read the merged config from disk and diff it against a previously read config
+ * Returns: { config, changes }
+ */
+function refreshAndDiff(previous, workingDirectory) {
+  const config = readConfig(workingDirectory);
+  return { config, changes: diffConfigs(previous || {}, config) };
+}
 
-    return {
-      source: 'md',
-      scope: projectExists ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER,
-      config: {
-        ...frontmatter,
-        ...(typeof body === 'string' && body.length > 0 ? { prompt: body } : {}),
-      },
-    };
+function getConfigForPath(layers, targetPath) {
+  if (!targetPath) {
+    return layers.userConfig;
+  }
+  if (layers.paths.customPath && targetPath === layers.paths.customPath) {
+    return layers.customConfig;
+  }
+  if (layers.paths.projectPath && targetPath === layers.paths.projectPath) {
+    return layers.projectConfig;
   }
+  return layers.userConfig;
+}
 
-  // Then fall back to opencode.json (highest-precedence entry)
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+// ============== WRITE LIMITS ==============
 
-  if (jsonSource.exists && jsonSource.section) {
-    const scope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
-    return {
-      source: 'json',
-      scope,
-      config: { ...jsonSource.section },
-    };
+/**
+ * Override the write guardrails. Accepts { maxBytes, maxFieldsPerEntity }; omitted keys keep their value.
+ */
+function setWriteLimits(limits = {}) {
+  const next = { ...writeLimits, ...limits };
+  for (const [key, value] of Object.entries(next)) {
+    if (!Number.isInteger(value) || value <= 0) {
+      throw new Error(`Write limit ${key} must be a positive integer`);
+    }
   }
-
-  return {
-    source: 'none',
-    scope: null,
-    config: {},
-  };
+  writeLimits = next;
 }
 
-function createAgent(agentName, config, workingDirectory, scope) {
-  ensureDirs();
+function getWriteLimits() {
+  return { ...writeLimits };
+}
 
-  // Check if agent already exists at either level
-  const projectPath = workingDirectory ? getProjectAgentPath(workingDirectory, agentName) : null;
-  const userPath = getUserAgentPath(agentName);
-  
-  if (projectPath && fs.existsSync(projectPath)) {
-    throw new Error(`Agent ${agentName} already exists as project-level .md file`);
+/**
+ * Throw LIMIT_EXCEEDED before anything reaches disk when content or an entity grows past the limits
+ * @param {string} content - Serialized file content
+ * @param {Array<{name: string, fieldCount: number}>} entities - Entities contained in the file
+ */
+function assertWriteLimits(filePath, content, entities) {
+  const bytes = Buffer.byteLength(content, 'utf8');
+  if (bytes > writeLimits.maxBytes) {
+    throw createConfigError(
+      CONFIG_ERROR.LIMIT_EXCEEDED,
+      `Refusing to write ${filePath}: ${bytes} bytes exceeds the limit of ${writeLimits.maxBytes}`,
+      { limit: writeLimits.maxBytes, actual: bytes }
+    );
   }
-  
-  if (fs.existsSync(userPath)) {
-    throw new Error(`Agent ${agentName} already exists as user-level .md file`);
+  for (const entity of entities) {
+    if (entity.fieldCount > writeLimits.maxFieldsPerEntity) {
+      throw createConfigError(
+        CONFIG_ERROR.LIMIT_EXCEEDED,
+        `Refusing to write ${filePath}: ${entity.name} has ${entity.fieldCount} fields, limit is ${writeLimits.maxFieldsPerEntity}`,
+        { limit: writeLimits.maxFieldsPerEntity, actual: entity.fieldCount }
+      );
+    }
   }
+}
 
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
-  if (jsonSource.exists) {
-    throw new Error(`Agent ${agentName} already exists in opencode.json`);
+function getConfigEntityFieldCounts(config) {
+  const entities = [];
+  for (const sectionKey of ['agent', 'command']) {
+    const section = config?.[sectionKey];
+    if (!isPlainObject(section)) continue;
+    for (const [name, entry] of Object.entries(section)) {
+      if (isPlainObject(entry)) {
+        entities.push({ name: `${sectionKey}.${name}`, fieldCount: Object.keys(entry).length });
+      }
+    }
   }
+  return entities;
+}
 
-  // Determine target path based on requested scope
-  let targetPath;
-  let targetScope;
-  
-  if (scope === AGENT_SCOPE.PROJECT && workingDirectory) {
-    ensureProjectAgentDir(workingDirectory);
-    targetPath = projectPath;
-    targetScope = AGENT_SCOPE.PROJECT;
-  } else {
-    targetPath = userPath;
-    targetScope = AGENT_SCOPE.USER;
-  }
+// ============== WRITE HOOKS ==============
 
-  // Extract scope and prompt from config - scope is only used for path determination, not written to file
-  const { prompt, scope: _scopeFromConfig, ...frontmatter } = config;
+/**
+ * Register a hook that runs before a config is written (before the backup).
+ * The hook receives (config, filePath) and may throw to veto the write.
+ * Returns a function that unregisters the hook.
+ */
+function registerPreWriteHook(hook) {
+  preWriteHooks.push(hook);
+  return () => {
+    const index = preWriteHooks.indexOf(hook);
+    if (index !== -1) preWriteHooks.splice(index, 1);
+  };
+}
 
-  writeMdFile(targetPath, frontmatter, prompt || '');
-  console.log(`Created new agent: ${agentName} (scope: ${targetScope}, path: ${targetPath})`);
+/**
+ * Register a hook that runs after a config was written successfully.
+ * Like pre-write hooks it receives (config, filePath). Returns a function that unregisters the hook.
+ */
+function registerPostWriteHook(hook) {
+  postWriteHooks.push(hook);
+  return () => {
+    const index = postWriteHooks.indexOf(hook);
+    if (index !== -1) postWriteHooks.splice(index, 1);
+  };
 }
 
-function updateAgent(agentName, updates, workingDirectory) {
-  ensureDirs();
+// ============== WRITE VERIFICATION ==============
 
-  // Determine correct path: project level takes precedence
-  const { scope, path: mdPath } = getAgentWritePath(agentName, workingDirectory);
-  const mdExists = mdPath && fs.existsSync(mdPath);
-  
-  // Check if agent exists in opencode.json across all config layers
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
-  const jsonSection = jsonSource.section;
-  const hasJsonFields = jsonSource.exists && jsonSection && Object.keys(jsonSection).length > 0;
-  const jsonTarget = jsonSource.exists
-    ? { config: jsonSource.config, path: jsonSource.path }
-    : getJsonWriteTarget(layers, AGENT_SCOPE.USER);
-  let config = jsonTarget.config || {};
-  
-  // Determine if we should create a new md file:
-  // Only for built-in agents (no md file AND no json config)
-  const isBuiltinOverride = !mdExists && !hasJsonFields;
-  
-  let targetPath = mdPath;
-  let targetScope = scope;
-  
-  if (!mdExists && isBuiltinOverride) {
-    // Built-in agent override - create at user level
-    targetPath = getUserAgentPath(agentName);
-    targetScope = AGENT_SCOPE.USER;
+/**
+ * Default validator: ask the `opencode` binary on PATH to load the config (`opencode debug config`).
+ * When no binary is found the write is accepted with a warning.
+ * Returns: { ok, message }
+ */
+function opencodeCliValidator(filePath) {
+  let cwd = path.dirname(filePath);
+  if (path.basename(cwd) === '.opencode') cwd = path.dirname(cwd);
+  const result = spawnSync('opencode', ['debug', 'config'], { cwd, encoding: 'utf8', timeout: 15000 });
+  if (result.error?.code === 'ENOENT') {
+    console.warn('Skipping config verification: opencode binary not found on PATH');
+    return { ok: true, message: 'opencode binary not found' };
   }
+  if (result.error) {
+    return { ok: false, message: result.error.message };
+  }
+  const output = `${result.stderr || ''}${result.stdout || ''}`.trim();
+  return { ok: result.status === 0, message: output };
+}
 
-  // Only create md data for existing md files or built-in overrides
-  let mdData = mdExists ? parseMdFile(mdPath) : (isBuiltinOverride ? { frontmatter: {}, body: '' } : null);
-
-  let mdModified = false;
-  let jsonModified = false;
-  // Only create new md if it's a built-in override
-  let creatingNewMd = isBuiltinOverride;
+/**
+ * Replace the validator used by writeConfig(..., { verifyWithOpencode: true }), e.g. with a fake in tests.
+ * The validator receives the written file path and returns { ok, message }. Pass null to restore the default.
+ */
+function setConfigValidator(validator) {
+  if (validator !== null && typeof validator !== 'function') {
+    throw new Error('Config validator must be a function or null');
+  }
+  configValidator = validator;
+}
 
-  for (const [field, value] of Object.entries(updates)) {
+function sortKeysDeep(value) {
+  if (Array.isArray(value)) {
+    return value.map(sortKeysDeep);
+  }
+  if (!isPlainObject(value)) {
+    return value;
+  }
+  return Object.fromEntries(
+    Object.keys(value).sort().map((key) => [key, sortKeysDeep(value[key])])
+  );
+}
 
-    if (field === 'prompt') {
-      const normalizedValue = typeof value === 'string' ? value : (value == null ? '' : String(value));
+/**
+ * Serialize a config object
+ * @param {object} config - Config to serialize
+ * @param {string} style - One of SERIALIZE_STYLE (pretty by default)
+ */
+function serializeConfig(config, style = SERIALIZE_STYLE.PRETTY) {
+  switch (style) {
+    case SERIALIZE_STYLE.PRETTY:
+      return JSON.stringify(config, null, 2);
+    case SERIALIZE_STYLE.COMPACT:
+      return JSON.stringify(config);
+    case SERIALIZE_STYLE.SORTED:
+      return JSON.stringify(sortKeysDeep(config), null, 2);
+    default:
+      throw new Error(`Unknown serialize style: ${style}`);
+  }
+}
 
-      if (mdExists || creatingNewMd) {
-        if (mdData) {
-          mdData.body = normalizedValue;
-          mdModified = true;
-        }
-        continue;
-      } else if (isPromptFileReference(jsonSection?.prompt)) {
-        const promptFilePath = resolvePromptFilePath(jsonSection.prompt);
-        if (!promptFilePath) {
-          throw new Error(`Invalid prompt file reference for agent ${agentName}`);
-        }
-        writePromptFile(promptFilePath, normalizedValue);
-        continue;
-      } else if (isPromptFileReference(normalizedValue)) {
-        if (!config.agent) config.agent = {};
-        if (!config.agent[agentName]) config.agent[agentName] = {};
-        config.agent[agentName].prompt = normalizedValue;
-        jsonModified = true;
-        continue;
-      }
+const JSONC_EDIT_OPTIONS = { formattingOptions: { insertSpaces: true, tabSize: 2, eol: '\n' } };
 
-      // For JSON-only agents, store prompt inline in JSON
-      if (!config.agent) config.agent = {};
-      if (!config.agent[agentName]) config.agent[agentName] = {};
-      config.agent[agentName].prompt = normalizedValue;
-      jsonModified = true;
-      continue;
-    }
+/**
+ * Serialize a config as edits to the existing .jsonc text, so the user's comments and layout survive.
+ * Only the paths that differ from the file on disk are rewritten.
+ * Returns null when there is no cleanly parseable previous version to edit.
+ */
+function serializeJsoncEdits(config, filePath) {
+  if (!fs.existsSync(filePath)) {
+    return null;
+  }
+  const original = stripBom(readTextFile(filePath));
+  const errors = [];
+  const previous = parseJsonc(original, errors, { allowTrailingComma: true });
+  if (errors.length > 0 || !isPlainObject(previous)) {
+    return null;
+  }
 
-    // Special handling for permission field - uses location detection and preserves non-wildcards
-    if (field === 'permission') {
-      const permissionSource = getAgentPermissionSource(agentName, workingDirectory);
-      const newPermission = mergePermissionWithNonWildcards(value, permissionSource, agentName, workingDirectory);
-
-      if (permissionSource.source === 'md') {
-        // Write to existing .md file
-        const existingMdData = parseMdFile(permissionSource.path);
-        existingMdData.frontmatter.permission = newPermission;
-        writeMdFile(permissionSource.path, existingMdData.frontmatter, existingMdData.body);
-        console.log(`Updated permission in .md file: ${permissionSource.path}`);
-      } else if (permissionSource.source === 'json') {
-        // Write to existing JSON location
-        const existingConfig = readConfigFile(permissionSource.path);
-        if (!existingConfig.agent) existingConfig.agent = {};
-        if (!existingConfig.agent[agentName]) existingConfig.agent[agentName] = {};
-        existingConfig.agent[agentName].permission = newPermission;
-        writeConfig(existingConfig, permissionSource.path);
-        console.log(`Updated permission in JSON: ${permissionSource.path}`);
-      } else {
-        // Permission not defined anywhere - use agent's source location
-        if ((mdExists || creatingNewMd) && mdData) {
-          mdData.frontmatter.permission = newPermission;
-          mdModified = true;
-        } else if (hasJsonFields) {
-          // Agent exists in JSON - add permission there
-          if (!config.agent) config.agent = {};
-          if (!config.agent[agentName]) config.agent[agentName] = {};
-          config.agent[agentName].permission = newPermission;
-          jsonModified = true;
-        } else {
-          // Built-in agent with no config - write to project JSON if available, else user JSON
-          const writeTarget = workingDirectory
-            ? { config: layers.projectConfig || {}, path: layers.paths.projectPath || layers.paths.userPath }
-            : { config: layers.userConfig || {}, path: layers.paths.userPath };
-          if (!writeTarget.config.agent) writeTarget.config.agent = {};
-          if (!writeTarget.config.agent[agentName]) writeTarget.config.agent[agentName] = {};
-          writeTarget.config.agent[agentName].permission = newPermission;
-          writeConfig(writeTarget.config, writeTarget.path);
-          console.log(`Created permission in JSON: ${writeTarget.path}`);
+  let text = original;
+  const applyDiff = (jsonPath, before, after) => {
+    if (isPlainObject(before) && isPlainObject(after)) {
+      for (const key of Object.keys(before)) {
+        if (!Object.hasOwn(after, key)) {
+          applyDiff([...jsonPath, key], before[key], undefined);
         }
       }
-      continue;
-    }
-
-    const inMd = mdData?.frontmatter?.[field] !== undefined;
-    const inJson = jsonSection?.[field] !== undefined;
-
-    if (value === null) {
-      // Treat null as a request to remove the field.
-      if (mdData && inMd) {
-        delete mdData.frontmatter[field];
-        mdModified = true;
+      for (const key of Object.keys(after)) {
+        applyDiff([...jsonPath, key], before[key], after[key]);
       }
+      return;
+    }
+    if (JSON.stringify(before) === JSON.stringify(after)) {
+      return;
+    }
+    text = applyJsoncEdits(text, modifyJsonc(text, jsonPath, after, JSONC_EDIT_OPTIONS));
+  };
+  applyDiff([], previous, config);
+  return text;
+}
 
-      if (inJson) {
-        if (config.agent?.[agentName]) {
-          delete config.agent[agentName][field];
+/**
+ * Write a config file, keeping a backup of the previous version.
+ * .jsonc files are edited in place (comments kept, { style } ignored) rather than re-serialized.
+ * @param {object} options - { style } serialize style; { verifyWithOpencode } has OpenCode load the result
+ *   and restores the previous version (throwing VERIFICATION_FAILED) if it is rejected. Off by default.
+ */
+function writeConfig(config, filePath = getUserConfigFile(), options = {}) {
+  assertConfigWriteUnlocked(config, filePath);
+  const batch = getActiveBatch();
+  if (batch) {
+    recordBatchWrite(filePath);
+    batch.json.set(filePath, structuredClone(config));
+    return;
+  }
 
-          if (Object.keys(config.agent[agentName]).length === 0) {
-            delete config.agent[agentName];
-          }
-          if (Object.keys(config.agent).length === 0) {
-            delete config.agent;
-          }
+  for (const hook of preWriteHooks) {
+    hook(config, filePath);
+  }
 
-          jsonModified = true;
-        }
-      }
+  const jsoncContent = filePath.endsWith('.jsonc') ? serializeJsoncEdits(config, filePath) : null;
+  const content = jsoncContent ?? serializeConfig(config, options.style);
+  assertWriteLimits(filePath, content, getConfigEntityFieldCounts(config));
 
-      continue;
+  const backupFile = getBackupPath(filePath);
+  let backedUp = false;
+  try {
+    if (fs.existsSync(filePath)) {
+      fs.mkdirSync(path.dirname(backupFile), { recursive: true });
+      fs.copyFileSync(filePath, backupFile);
+      backedUp = true;
+      console.log(`Created config backup: ${backupFile}`);
     }
 
-    // JSON takes precedence over md, so update JSON first if field exists there
-    if (inJson) {
-      if (!config.agent) config.agent = {};
-      if (!config.agent[agentName]) config.agent[agentName] = {};
-      config.agent[agentName][field] = value;
-      jsonModified = true;
-    } else if (inMd || creatingNewMd) {
-      if (mdData) {
-        mdData.frontmatter[field] = value;
-        mdModified = true;
-      }
-    } else {
-      // New field - add to the appropriate location based on agent source
-      if ((mdExists || creatingNewMd) && mdData) {
-        mdData.frontmatter[field] = value;
-        mdModified = true;
+    fs.mkdirSync(path.dirname(filePath), { recursive: true });
+    fs.writeFileSync(filePath, content, 'utf8');
+    console.log(`Successfully wrote config file: ${filePath}`);
+  } catch (error) {
+    console.error(`Failed to write config file: ${filePath}`, error);
+    const notWritable = toNotWritableError(error, filePath);
+    throw notWritable !== error ? notWritable : new Error('Failed to write OpenCode configuration');
+  }
+
+  if (options.verifyWithOpencode) {
+    const result = (configValidator || opencodeCliValidator)(filePath);
+    if (!result?.ok) {
+      if (backedUp) {
+        fs.copyFileSync(backupFile, filePath);
       } else {
-        // JSON-only agent or has JSON fields - add to JSON
-        if (!config.agent) config.agent = {};
-        if (!config.agent[agentName]) config.agent[agentName] = {};
-        config.agent[agentName][field] = value;
-        jsonModified = true;
+        fs.rmSync(filePath, { force: true });
       }
+      console.warn(`OpenCode rejected ${filePath}, restored the previous version`);
+      throw createConfigError(
+        CONFIG_ERROR.VERIFICATION_FAILED,
+        `OpenCode rejected the written config ${filePath}${result?.message ? `: ${result.message}` : ''}`,
+        { path: filePath, output: result?.message ?? '' }
+      );
     }
   }
 
-  if (mdModified && mdData) {
-    writeMdFile(targetPath, mdData.frontmatter, mdData.body);
+  // This write supersedes any scheduled one for the file, which would otherwise land later with older content
+  const pending = pendingConfigWrites.get(filePath);
+  if (pending) {
+    clearTimeout(pending.timer);
+    pendingConfigWrites.delete(filePath);
   }
 
-  if (jsonModified) {
-    writeConfig(config, jsonTarget.path || CONFIG_FILE);
+  for (const hook of postWriteHooks) {
+    hook(config, filePath);
   }
-
-  console.log(`Updated agent: ${agentName} (scope: ${targetScope}, md: ${mdModified}, json: ${jsonModified})`);
 }
 
-function deleteAgent(agentName, workingDirectory) {
-  let deleted = false;
+// ============== DEBOUNCED WRITES ==============
 
-  // Check project level first (takes precedence)
-  if (workingDirectory) {
-    const projectPath = getProjectAgentPath(workingDirectory, agentName);
-    if (fs.existsSync(projectPath)) {
-      fs.unlinkSync(projectPath);
-      console.log(`Deleted project-level agent .md file: ${projectPath}`);
-      deleted = true;
-    }
+/**
+ * Set the window (ms) in which scheduled writes to the same file are coalesced
+ */
+function setConfigWriteDelay(delayMs) {
+  if (!Number.isFinite(delayMs) || delayMs < 0) {
+    throw new Error('Config write delay must be a non-negative number');
+  }
+  configWriteDelayMs = delayMs;
+}
+
+/**
+ * Schedule a config write. Writes to the same file within the delay window are coalesced
+ * and only the last config is written (one backup, one write) at the trailing edge.
+ * The config is copied, so later changes by the caller aren't written. Until the write lands, reads of
+ * the file return the pending config and a direct writeConfig to it replaces the pending write.
+ * A failed write stays pending so flushPendingWrites can retry it. Timers don't keep the process alive.
+ */
+function scheduleConfigWrite(config, filePath = getUserConfigFile()) {
+  const pending = pendingConfigWrites.get(filePath);
+  if (pending) {
+    clearTimeout(pending.timer);
+  }
+
+  const timer = setTimeout(() => {
+    const entry = pendingConfigWrites.get(filePath);
+    if (!entry) return;
+    try {
+      writeConfig(entry.config, filePath);
+      if (pendingConfigWrites.get(filePath) === entry) {
+        pendingConfigWrites.delete(filePath);
+      }
+    } catch (error) {
+      console.error(`Scheduled config write failed, keeping it pending: ${filePath}`, error);
+    }
+  }, configWriteDelayMs);
+  timer.unref?.();
+
+  pendingConfigWrites.set(filePath, { config: structuredClone(config), timer });
+}
+
+/**
+ * Immediately perform all scheduled writes (e.g. on shutdown). A failed write doesn't stop the
+ * others; it stays pending and is reported.
+ * Returns: { written: string[], failed: [{ path, error }] }
+ */
+function flushPendingWrites() {
+  const written = [];
+  const failed = [];
+  for (const [filePath, entry] of Array.from(pendingConfigWrites)) {
+    clearTimeout(entry.timer);
+    try {
+      writeConfig(entry.config, filePath);
+      pendingConfigWrites.delete(filePath);
+      written.push(filePath);
+    } catch (error) {
+      console.error(`Failed to flush config write: ${filePath}`, error);
+      failed.push({ path: filePath, error });
+    }
+  }
+  return { written, failed };
+}
+
+// ============== OPERATION LOG ==============
+
+/**
+ * Enable or disable the append-only JSONL operation log (off by default)
+ */
+function setOperationLog(enabled, filePath = DEFAULT_OPERATION_LOG_FILE) {
+  operationLogPath = enabled ? filePath : null;
+}
+
+function getOperationLogPath() {
+  return operationLogPath;
+}
+
+/**
+ * Append one operation to the log. Best effort: a failed log write never aborts the operation.
+ * Inside a batch the entry is held back until the batch commits, so rolled-back ops are never logged.
+ */
+function logOperation(operation, entity, name, fields = []) {
+  if (!operationLogPath) {
+    return;
+  }
+  const entry = { timestamp: new Date().toISOString(), operation, entity, name, fields };
+  const batch = getActiveBatch();
+  if (batch) {
+    batch.log.push(entry);
+    return;
+  }
+  appendOperationLogEntry(entry);
+}
+
+function appendOperationLogEntry(entry) {
+  try {
+    fs.mkdirSync(path.dirname(operationLogPath), { recursive: true });
+    fs.appendFileSync(operationLogPath, `${JSON.stringify(entry)}\n`, 'utf8');
+  } catch (error) {
+    console.warn(`Failed to write operation log ${operationLogPath}:`, error);
+  }
+}
+
+// ============== BATCH ==============
+
+/**
+ * Capture the current bytes of each file (or its absence) so a multi-file write can be undone.
+ * Returns: { restore(label) } which puts every file back, logging (not throwing) restore failures.
+ */
+function snapshotFiles(filePaths) {
+  const snapshot = new Map();
+  for (const filePath of filePaths) {
+    snapshot.set(filePath, fs.existsSync(filePath) ? fs.readFileSync(filePath) : null);
+  }
+  return {
+    restore(label) {
+      restoreFileContents(snapshot, label);
+    }
+  };
+}
+
+function restoreFileContents(snapshot, label) {
+  for (const [filePath, content] of snapshot) {
+    try {
+      if (content === null) {
+        if (fs.existsSync(filePath)) fs.unlinkSync(filePath);
+      } else {
+        fs.mkdirSync(path.dirname(filePath), { recursive: true });
+        fs.writeFileSync(filePath, content);
+      }
+    } catch (restoreError) {
+      console.error(`Failed to restore ${filePath} after ${label} failure:`, restoreError);
+    }
+  }
+}
+
+function getActiveBatch() {
+  const batch = batchContext.getStore();
+  return batch && !batch.closed ? batch : null;
+}
+
+/**
+ * Called by every write path before it touches a file: inside a batch, the first call for a path
+ * captures its current bytes (or absence) so the batch can put it back. No-op outside a batch.
+ */
+function recordBatchWrite(filePath) {
+  const batch = getActiveBatch();
+  if (!batch || batch.files.has(filePath)) return;
+  batch.files.set(filePath, fs.existsSync(filePath) ? fs.readFileSync(filePath) : null);
+}
+
+/**
+ * Like recordBatchWrite for a directory about to be created: missing ancestors are removed on rollback
+ */
+function recordBatchDir(dir) {
+  const batch = getActiveBatch();
+  if (!batch) return;
+  const missing = [];
+  for (let current = path.resolve(dir); !fs.existsSync(current); current = path.dirname(current)) {
+    missing.unshift(current);
+    if (path.dirname(current) === current) break;
+  }
+  batch.dirs.push(...missing);
+}
+
+function rollBackBatch(batch) {
+  restoreFileContents(batch.files, 'batch');
+  // Newest first, so children go before their parents; a dir that isn't empty is left alone
+  for (const dir of [...batch.dirs].reverse()) {
+    try {
+      fs.rmdirSync(dir);
+    } catch {
+      // Not empty or already gone
+    }
+  }
+}
+
+function applyBatchOp(op, workingDirectory) {
+  if (op.op === 'setting') {
+    return setGeneralSetting(op.key, op.value);
+  }
+
+  const isAgent = op.entity === ENTITY_KIND.AGENT;
+  if (!isAgent && op.entity !== ENTITY_KIND.COMMAND) {
+    throw new Error(`Unknown entity kind: ${op.entity}`);
+  }
+  switch (op.op) {
+    case 'create':
+      return isAgent
+        ? createAgent(op.name, op.config || {}, workingDirectory, op.scope)
+        : createCommand(op.name, op.config || {}, workingDirectory, op.scope);
+    case 'update':
+      return isAgent
+        ? updateAgent(op.name, op.config || {}, workingDirectory, op.options)
+        : updateCommand(op.name, op.config || {}, workingDirectory, op.options);
+    case 'delete':
+      return isAgent
+        ? deleteAgent(op.name, workingDirectory, op.options)
+        : deleteCommand(op.name, workingDirectory, op.options);
+    default:
+      throw new Error(`Unknown batch operation: ${op.op}`);
+  }
+}
+
+/**
+ * Apply several operations all-or-nothing. Each op is one of
+ *   { op: 'create'|'update'|'delete', entity: 'agent'|'command', name, config?, scope?, options? }
+ *   { op: 'setting', key, value }
+ * Json changes are applied in memory and each config file is written once at the end; .md and prompt
+ * files are written as ops run and restored if any op (or the final json write) fails.
+ * Returns: { applied: [{ index, op, entity, name, result }] }
+ */
+function applyBatch(ops, workingDirectory) {
+  if (getActiveBatch()) {
+    throw new Error('A batch is already being applied');
+  }
+  if (!Array.isArray(ops)) {
+    throw new Error('Batch operations must be an array');
+  }
+
+  // files/dirs: what the ops wrote, recorded as they go; json: staged config writes; log: held-back log entries
+  const batch = { files: new Map(), dirs: [], json: new Map(), log: [], closed: false };
+  const applied = [];
+  try {
+    batchContext.run(batch, () => {
+      ops.forEach((op, index) => {
+        try {
+          const result = applyBatchOp(op, workingDirectory);
+          applied.push({ index, op: op.op, entity: op.entity ?? null, name: op.name ?? op.key, result });
+        } catch (error) {
+          error.batchIndex = index;
+          throw error;
+        }
+      });
+    });
+  } catch (error) {
+    batch.closed = true;
+    rollBackBatch(batch);
+    throw error;
+  }
+
+  // Closed before flushing, so these writes (and anything the ops left scheduled) go straight to disk
+  batch.closed = true;
+  try {
+    for (const [filePath, config] of batch.json) {
+      writeConfig(config, filePath);
+    }
+  } catch (error) {
+    rollBackBatch(batch);
+    throw error;
+  }
+  for (const entry of batch.log) {
+    appendOperationLogEntry(entry);
+  }
+
+  console.log(`Applied batch of ${applied.length} operation(s)`);
+  return { applied };
+}
+
+// ============== JSON PATCH ==============
+
+const ARRAY_INDEX_PATTERN = /^(?:0|[1-9]\d*)$/;
+
+function getPatchValue(document, segments, fail) {
+  let current = document;
+  for (const segment of segments) {
+    if (Array.isArray(current) && ARRAY_INDEX_PATTERN.test(segment) && Number(segment) < current.length) {
+      current = current[Number(segment)];
+    } else if (isPlainObject(current) && Object.prototype.hasOwnProperty.call(current, segment)) {
+      current = current[segment];
+    } else {
+      fail(`path ${toJsonPointer(segments)} does not exist`);
+    }
+  }
+  return current;
+}
+
+/**
+ * Apply one RFC 6902 operation to `document` in place (the root itself is replaced via the returned value)
+ */
+function applyPatchOperation(document, operation, fail) {
+  if (!isPlainObject(operation) || typeof operation.op !== 'string' || typeof operation.path !== 'string') {
+    fail('each operation needs string "op" and "path" members');
+  }
+  let segments;
+  try {
+    segments = parseJsonPointer(operation.path);
+  } catch {
+    fail(`invalid path ${JSON.stringify(operation.path)}`);
+  }
+  const needsValue = ['add', 'replace', 'test'].includes(operation.op);
+  if (needsValue && !Object.prototype.hasOwnProperty.call(operation, 'value')) {
+    fail('missing "value"');
+  }
+
+  const readFrom = () => {
+    let fromSegments;
+    try {
+      fromSegments = parseJsonPointer(operation.from);
+    } catch {
+      fail(`invalid from ${JSON.stringify(operation.from)}`);
+    }
+    return { fromSegments, value: structuredClone(getPatchValue(document, fromSegments, fail)) };
+  };
+
+  const remove = (targetSegments) => {
+    if (targetSegments.length === 0) fail('cannot remove the whole document');
+    const parent = getPatchValue(document, targetSegments.slice(0, -1), fail);
+    const key = targetSegments[targetSegments.length - 1];
+    getPatchValue(document, targetSegments, fail);
+    if (Array.isArray(parent)) parent.splice(Number(key), 1);
+    else delete parent[key];
+  };
+
+  const add = (targetSegments, value) => {
+    if (targetSegments.length === 0) return value;
+    const parent = getPatchValue(document, targetSegments.slice(0, -1), fail);
+    const key = targetSegments[targetSegments.length - 1];
+    if (Array.isArray(parent)) {
+      if (key === '-') {
+        parent.push(value);
+      } else if (ARRAY_INDEX_PATTERN.test(key) && Number(key) <= parent.length) {
+        parent.splice(Number(key), 0, value);
+      } else {
+        fail(`array index ${key} is out of bounds`);
+      }
+    } else if (isPlainObject(parent)) {
+      parent[key] = value;
+    } else {
+      fail(`parent of ${toJsonPointer(targetSegments)} is not an object or array`);
+    }
+    return document;
+  };
+
+  switch (operation.op) {
+    case 'add':
+      return add(segments, structuredClone(operation.value));
+    case 'remove':
+      remove(segments);
+      return document;
+    case 'replace': {
+      getPatchValue(document, segments, fail);
+      if (segments.length === 0) return structuredClone(operation.value);
+      // Assign in place so the key keeps its position in the file
+      const parent = getPatchValue(document, segments.slice(0, -1), fail);
+      const key = segments[segments.length - 1];
+      parent[Array.isArray(parent) ? Number(key) : key] = structuredClone(operation.value);
+      return document;
+    }
+    case 'move': {
+      const { fromSegments, value } = readFrom();
+      if (toJsonPointer(segments).startsWith(`${toJsonPointer(fromSegments)}/`)) {
+        fail('cannot move a value into one of its own children');
+      }
+      remove(fromSegments);
+      return add(segments, value);
+    }
+    case 'copy':
+      return add(segments, readFrom().value);
+    case 'test': {
+      const actual = getPatchValue(document, segments, fail);
+      if (JSON.stringify(sortKeysDeep(actual)) !== JSON.stringify(sortKeysDeep(operation.value))) {
+        throw createConfigError(
+          CONFIG_ERROR.PRECONDITION_FAILED,
+          `JSON patch test failed at ${operation.path}`,
+          { pointer: operation.path, expected: operation.value, actual }
+        );
+      }
+      return document;
+    }
+    default:
+      fail(`unknown op "${operation.op}"`);
+  }
+  return document;
+}
+
+/**
+ * Apply an RFC 6902 JSON patch to a config file (default: the user opencode.json) through writeConfig.
+ * All operations are applied to a copy first; if any path is invalid (INVALID_PATCH) or a `test` op fails
+ * (PRECONDITION_FAILED) nothing is written. `error.opIndex` names the failing operation.
+ * @param {object} options - forwarded to writeConfig (e.g. { verifyWithOpencode })
+ * Returns: { changes } as reported by diffConfigs
+ */
+function applyJsonPatch(patch, filePath = getUserConfigFile(), options = {}) {
+  if (!Array.isArray(patch)) {
+    throw createConfigError(CONFIG_ERROR.INVALID_PATCH, 'JSON patch must be an array of operations');
+  }
+
+  const before = readConfigFile(filePath);
+  let document = structuredClone(before);
+  patch.forEach((operation, opIndex) => {
+    const fail = (reason) => {
+      throw createConfigError(
+        CONFIG_ERROR.INVALID_PATCH,
+        `JSON patch operation ${opIndex} (${operation?.op} ${operation?.path}): ${reason}`,
+        { opIndex }
+      );
+    };
+    try {
+      document = applyPatchOperation(document, operation, fail);
+    } catch (error) {
+      if (error?.opIndex === undefined) error.opIndex = opIndex;
+      throw error;
+    }
+  });
+
+  if (!isPlainObject(document)) {
+    throw createConfigError(CONFIG_ERROR.INVALID_PATCH, 'JSON patch must leave the config an object');
+  }
+  const changes = diffConfigs(before, document);
+  if (changes.length > 0) {
+    writeConfig(document, filePath, options);
+  }
+  return { changes };
+}
+
+// ============== CONFIG BACKUPS ==============
+
+/**
+ * Override the backup directory (null restores the default of writing backups next to the config)
+ */
+function setBackupDirectory(dir) {
+  backupDirOverride = dir ? path.resolve(dir) : null;
+}
+
+/**
+ * Resolve the backup directory: setter > OPENCHAMBER_BACKUP_DIR > null (in-place)
+ */
+function getBackupDirectory() {
+  if (backupDirOverride) {
+    return backupDirOverride;
+  }
+  const fromEnv = process.env.OPENCHAMBER_BACKUP_DIR;
+  return fromEnv && fromEnv.trim() ? path.resolve(fromEnv.trim()) : null;
+}
+
+/**
+ * Get the backup path for a config file.
+ * In a dedicated backup directory the source path is encoded into the file name to keep backups distinct.
+ */
+function getBackupPath(filePath) {
+  const backupDir = getBackupDirectory();
+  if (!backupDir) {
+    return `${filePath}${BACKUP_SUFFIX}`;
+  }
+  return path.join(backupDir, `${encodeURIComponent(path.resolve(filePath))}${BACKUP_SUFFIX}`);
+}
+
+function getBackupSourcePath(backupPath) {
+  const baseName = backupPath.slice(0, -BACKUP_SUFFIX.length);
+  const backupDir = getBackupDirectory();
+  if (backupDir && path.dirname(backupPath) === backupDir) {
+    try {
+      return decodeURIComponent(path.basename(baseName));
+    } catch {
+      return baseName;
+    }
+  }
+  return baseName;
+}
+
+/**
+ * Directories that may hold config backups (the backup directory, or user/project/custom config locations)
+ */
+function getBackupSearchDirs(workingDirectory) {
+  const backupDir = getBackupDirectory();
+  if (backupDir) {
+    return [backupDir];
+  }
+
+  const dirs = new Set([OPENCODE_CONFIG_DIR]);
+  for (const candidate of getProjectConfigCandidates(workingDirectory)) {
+    dirs.add(path.dirname(candidate));
+  }
+  if (CUSTOM_CONFIG_FILE) {
+    dirs.add(path.dirname(CUSTOM_CONFIG_FILE));
+  }
+  return Array.from(dirs);
+}
+
+/**
+ * List config backups written by writeConfig, newest first
+ * Returns: [{ path, sourcePath, timestamp, size }]
+ */
+function listBackups(workingDirectory) {
+  const backups = [];
+
+  for (const dir of getBackupSearchDirs(workingDirectory)) {
+    if (!fs.existsSync(dir)) continue;
+
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+      if (!entry.isFile() || !entry.name.endsWith(BACKUP_SUFFIX)) continue;
+
+      const backupPath = path.join(dir, entry.name);
+      const stats = fs.statSync(backupPath);
+      backups.push({
+        path: backupPath,
+        sourcePath: getBackupSourcePath(backupPath),
+        timestamp: stats.mtimeMs,
+        size: stats.size
+      });
+    }
+  }
+
+  return backups.sort((a, b) => b.timestamp - a.timestamp);
+}
+
+/**
+ * Find the newest backup taken at or before `time` (Date or epoch ms), optionally only for one config file.
+ * Returns the backup entry as listed by listBackups, or null when every backup is newer.
+ */
+function findBackupAtOrBefore(time, workingDirectory, sourcePath) {
+  const cutoff = time instanceof Date ? time.getTime() : Number(time);
+  if (!Number.isFinite(cutoff)) {
+    throw new Error(`Invalid backup time: ${time}`);
+  }
+  const source = sourcePath ? path.resolve(sourcePath) : null;
+
+  // listBackups is sorted newest first
+  return listBackups(workingDirectory).find((backup) => (
+    backup.timestamp <= cutoff && (!source || path.resolve(backup.sourcePath) === source)
+  )) || null;
+}
+
+/**
+ * Delete all but the newest `keep` backups. Live config files are never touched.
+ * Returns the list of deleted backup paths.
+ */
+function pruneBackups(keep, workingDirectory) {
+  const keepCount = Number.isInteger(keep) && keep > 0 ? keep : 0;
+  const { userPath, projectPath, customPath } = getConfigPaths(workingDirectory);
+  const livePaths = new Set(
+    [userPath, projectPath, customPath, ...getProjectConfigCandidates(workingDirectory)]
+      .filter(Boolean)
+      .map((filePath) => path.resolve(filePath))
+  );
+
+  const deleted = [];
+  for (const backup of listBackups(workingDirectory).slice(keepCount)) {
+    if (livePaths.has(path.resolve(backup.path))) {
+      continue;
+    }
+    fs.unlinkSync(backup.path);
+    console.log(`Deleted config backup: ${backup.path}`);
+    deleted.push(backup.path);
+  }
+
+  return deleted;
+}
+
+/**
+ * Restore a config file from its backup
+ */
+function restoreConfigBackup(filePath = getUserConfigFile()) {
+  const backupFile = getBackupPath(filePath);
+  if (!fs.existsSync(backupFile)) {
+    throw new Error(`No backup found for ${filePath}`);
+  }
+  fs.mkdirSync(path.dirname(filePath), { recursive: true });
+  fs.copyFileSync(backupFile, filePath);
+  console.log(`Restored config from backup: ${backupFile}`);
+}
+
+function getJsonEntrySource(layers, sectionKey, entryName) {
+  const { userConfig, projectConfig, customConfig, paths } = layers;
+  const customSection = customConfig?.[sectionKey]?.[entryName];
+  if (customSection !== undefined) {
+    return { section: customSection, config: customConfig, path: paths.customPath, exists: true };
+  }
+
+  const projectSection = projectConfig?.[sectionKey]?.[entryName];
+  if (projectSection !== undefined) {
+    return { section: projectSection, config: projectConfig, path: paths.projectPath, exists: true };
+  }
+
+  const userSection = userConfig?.[sectionKey]?.[entryName];
+  if (userSection !== undefined) {
+    return { section: userSection, config: userConfig, path: paths.userPath, exists: true };
+  }
+
+  return { section: null, config: null, path: null, exists: false };
+}
+
+function getJsonWriteTarget(layers, preferredScope) {
+  const { userConfig, projectConfig, customConfig, paths } = layers;
+  if (paths.customPath) {
+    return { config: customConfig, path: paths.customPath };
+  }
+  if (preferredScope === AGENT_SCOPE.PROJECT && paths.projectPath) {
+    return { config: projectConfig, path: paths.projectPath };
+  }
+  if (paths.projectPath) {
+    return { config: projectConfig, path: paths.projectPath };
+  }
+  return { config: userConfig, path: paths.userPath };
+}
+
+/**
+ * Distinct json config layers that exist as write targets, lowest precedence first (user > project > custom)
+ */
+function getJsonLayerList(layers) {
+  const { userPath, projectPath, customPath } = layers.paths;
+  return [
+    { config: layers.userConfig, path: userPath, scope: AGENT_SCOPE.USER },
+    { config: layers.projectConfig, path: projectPath, scope: AGENT_SCOPE.PROJECT },
+    { config: layers.customConfig, path: customPath, scope: 'custom' }
+  ].filter((layer, index, all) => layer.path && all.findIndex((other) => other.path === layer.path) === index);
+}
+
+/**
+ * Parse an .md file into frontmatter and body.
+ * `hasFrontmatter` is false when the file has no `---` block; `frontmatterError` is set when the block
+ * exists but isn't valid YAML (frontmatter is then `{}`, so callers that rewrite the file must skip it).
+ * Returns: { frontmatter, body, hasFrontmatter, frontmatterError }
+ */
+function parseMdFile(filePath) {
+  const content = stripBom(readTextFile(filePath));
+  const match = content.match(FRONTMATTER_PATTERN);
+
+  if (!match) {
+    return { frontmatter: {}, body: content.trim(), hasFrontmatter: false, frontmatterError: null };
+  }
+
+  let frontmatter = {};
+  let frontmatterError = null;
+  try {
+    frontmatter = yaml.parse(match[1] ?? '') || {};
+  } catch (error) {
+    console.warn(`Failed to parse markdown frontmatter ${filePath}, treating as empty:`, error);
+    frontmatter = {};
+    frontmatterError = error?.message || String(error);
+  }
+
+  const body = match[2].trim();
+  return { frontmatter, body, hasFrontmatter: true, frontmatterError };
+}
+
+/**
+ * Read only the frontmatter of an .md file, stopping at the closing `---` instead of loading the body.
+ * Yields the same frontmatter as parseMdFile; `hasBody` tells whether a non-empty body follows.
+ * Returns: { frontmatter, hasBody }
+ */
+function parseMdFrontmatterOnly(filePath) {
+  const fd = fs.openSync(filePath, 'r');
+  try {
+    const decoder = new TextDecoder('utf-8', { fatal: true });
+    const buffer = Buffer.alloc(MD_READ_CHUNK_SIZE);
+    let text = '';
+    let eof = false;
+    const readMore = () => {
+      const bytesRead = fs.readSync(fd, buffer, 0, buffer.length, null);
+      eof = bytesRead === 0;
+      try {
+        text += decoder.decode(buffer.subarray(0, bytesRead), { stream: !eof });
+      } catch {
+        throw createEncodingError(filePath);
+      }
+    };
+
+    for (;;) {
+      const match = text.match(FRONTMATTER_PATTERN);
+      if (match) {
+        // Only need to know whether anything but whitespace follows the closing fence
+        const bodyStart = match[0].length - match[2].length;
+        while (!/\S/.test(text.slice(bodyStart)) && !eof) {
+          readMore();
+        }
+        let frontmatter = {};
+        try {
+          frontmatter = yaml.parse(match[1] ?? '') || {};
+        } catch (error) {
+          console.warn(`Failed to parse markdown frontmatter ${filePath}, treating as empty:`, error);
+        }
+        return { frontmatter, hasBody: /\S/.test(text.slice(bodyStart)) };
+      }
+
+      // The first non-blank line decides whether the file starts with a frontmatter fence
+      const head = stripBom(text).replace(/^(?:[ \t]*\r?\n)*/, '');
+      const firstLineEnd = head.indexOf('\n');
+      if ((firstLineEnd !== -1 && head.slice(0, firstLineEnd).replace(/\r$/, '') !== '---') || eof) {
+        return { frontmatter: {}, hasBody: eof ? /\S/.test(text) : true };
+      }
+
+      readMore();
+    }
+  } finally {
+    fs.closeSync(fd);
+  }
+}
+
+/**
+ * parseMdFrontmatterOnly for list and bulk operations: a file that can't be read (e.g. INVALID_ENCODING)
+ * yields empty frontmatter plus `error: { code, message }` instead of failing the whole listing.
+ */
+function readListingFrontmatter(filePath) {
+  try {
+    return { ...parseMdFrontmatterOnly(filePath), error: null };
+  } catch (error) {
+    return { frontmatter: {}, hasBody: false, error: { code: error.code ?? null, message: error.message } };
+  }
+}
+
+function cleanFrontmatter(frontmatter) {
+  // Filter out null/undefined values - OpenCode expects keys to be omitted rather than set to null
+  return Object.fromEntries(
+    Object.entries(frontmatter).filter(([, value]) => value != null)
+  );
+}
+
+function renderMdFile(frontmatter, body) {
+  // Literal block scalars (|) keep multi-line values human-editable instead of quoted with escaped newlines
+  let yamlStr = yaml.stringify(cleanFrontmatter(frontmatter), { blockQuote: 'literal' });
+  if (yamlStr && !yamlStr.endsWith('\n')) yamlStr += '\n';
+  // A frontmatter-only file ends at the closing fence, so re-saving never accumulates blank lines
+  if (!body || !/\S/.test(body)) {
+    return `---\n${yamlStr}---\n`;
+  }
+  return `---\n${yamlStr}---\n\n${body}`;
+}
+
+/**
+ * Report whitespace issues in an .md body so the UI can warn
+ * Returns: [{ line, type: 'trailing-whitespace'|'mixed-indentation', message }] (lines are 1-based)
+ */
+function lintMdBody(body) {
+  const lints = [];
+  (body || '').split(/\r?\n/).forEach((text, index) => {
+    const line = index + 1;
+    if (/[ \t]+$/.test(text)) {
+      lints.push({ line, type: 'trailing-whitespace', message: `Line ${line} has trailing whitespace` });
+    }
+    const indentation = text.match(/^[ \t]*/)[0];
+    if (indentation.includes(' ') && indentation.includes('\t')) {
+      lints.push({ line, type: 'mixed-indentation', message: `Line ${line} mixes tabs and spaces in its indentation` });
+    }
+  });
+  return lints;
+}
+
+function trimBodyWhitespace(body) {
+  const trimmed = (body || '').split(/\r?\n/).map((line) => line.replace(/[ \t]+$/, '')).join('\n');
+  return `${trimmed.replace(/\n+$/, '')}\n`;
+}
+
+/**
+ * Write an .md file with frontmatter
+ * @param {object} options - { exclusive } fails with EEXIST if the file exists;
+ *   { trimTrailingWhitespace } strips trailing whitespace from body lines and ends the body with one newline
+ */
+function writeMdFile(sourcePath, frontmatter, body, options = {}) {
+  assertMdPathUnlocked(sourcePath);
+  // Copy-on-write: an agent read from a read-only extra search dir is written to the primary agent dir instead
+  const readOnlyDir = getReadOnlyAgentDir(sourcePath);
+  const filePath = readOnlyDir ? path.join(AGENT_DIR, path.relative(readOnlyDir, path.resolve(sourcePath))) : sourcePath;
+  if (readOnlyDir) {
+    recordBatchDir(path.dirname(filePath));
+    fs.mkdirSync(path.dirname(filePath), { recursive: true });
+    console.log(`Agent ${sourcePath} is in a read-only search dir, writing ${filePath} instead`);
+  }
+  recordBatchWrite(filePath);
+  try {
+    const cleanedFrontmatter = cleanFrontmatter(frontmatter);
+    const content = renderMdFile(cleanedFrontmatter, options.trimTrailingWhitespace ? trimBodyWhitespace(body) : body);
+    assertWriteLimits(filePath, content, [
+      { name: path.basename(filePath, '.md'), fieldCount: Object.keys(cleanedFrontmatter).length }
+    ]);
+    // Exclusive mode lets the OS reject the write if another creator got there first
+    fs.writeFileSync(filePath, content, { encoding: 'utf8', flag: options.exclusive ? 'wx' : 'w' });
+    console.log(`Successfully wrote markdown file: ${filePath}`);
+  } catch (error) {
+    if (error?.code === 'EEXIST' || error?.code === CONFIG_ERROR.LIMIT_EXCEEDED) {
+      throw error;
+    }
+    console.error(`Failed to write markdown file ${filePath}:`, error);
+    const notWritable = toNotWritableError(error, filePath);
+    throw notWritable !== error ? notWritable : new Error('Failed to write agent markdown file');
+  }
+}
+
+/**
+ * Describe where an agent's fields are stored (md and/or json)
+ * @param {object} options - { includeFrontmatter } adds md.frontmatter as ordered [key, value] pairs;
+ *   { fieldsOfInterest } limits md.fields/json.fields to the given fields (frontmatter is not returned)
+ */
+function getAgentSources(agentName, workingDirectory, options = {}) {
+  // Check project level first (takes precedence)
+  const projectPath = workingDirectory ? getProjectAgentPath(workingDirectory, agentName) : null;
+  const projectExists = projectPath && fs.existsSync(projectPath);
+  
+  // Then check user level
+  const userPath = getUserAgentPath(agentName);
+  const userExists = fs.existsSync(userPath);
+  
+  // Determine which md file to use (project takes precedence)
+  const mdPath = projectExists ? projectPath : (userExists ? userPath : null);
+  const mdExists = !!mdPath;
+  const mdScope = projectExists ? AGENT_SCOPE.PROJECT : (userExists ? AGENT_SCOPE.USER : null);
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const jsonSection = jsonSource.section;
+  const jsonPath = jsonSource.path || layers.paths.customPath || layers.paths.projectPath || layers.paths.userPath;
+  const jsonScope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+
+  const sources = {
+    md: {
+      exists: mdExists,
+      path: mdPath,
+      scope: mdScope,
+      fields: []
+    },
+    json: {
+      exists: jsonSource.exists,
+      path: jsonPath,
+      scope: jsonSource.exists ? jsonScope : null,
+      fields: []
+    },
+    // Additional info about both levels
+    projectMd: {
+      exists: projectExists,
+      path: projectPath
+    },
+    userMd: {
+      exists: userExists,
+      path: userPath
+    }
+  };
+
+  const fieldsOfInterest = Array.isArray(options.fieldsOfInterest) && options.fieldsOfInterest.length > 0
+    ? options.fieldsOfInterest
+    : null;
+
+  if (fieldsOfInterest) {
+    // Projection: only report presence of the requested fields
+    if (mdExists) {
+      const { frontmatter, hasBody } = parseMdFrontmatterOnly(mdPath);
+      sources.md.fields = fieldsOfInterest.filter((field) => (
+        field === 'prompt' ? hasBody : frontmatter[field] !== undefined
+      ));
+    }
+    if (jsonSection) {
+      sources.json.fields = fieldsOfInterest.filter((field) => jsonSection[field] !== undefined);
+    }
+    return sources;
+  }
+
+  if (mdExists) {
+    const { frontmatter, hasBody } = parseMdFrontmatterOnly(mdPath);
+    sources.md.fields = Object.keys(frontmatter);
+    if (hasBody) {
+      sources.md.fields.push('prompt');
+    }
+    if (options.includeFrontmatter) {
+      sources.md.frontmatter = Object.entries(frontmatter);
+    }
+  }
+
+  if (jsonSection) {
+    sources.json.fields = Object.keys(jsonSection);
+  }
+
+  return sources;
+}
+
+function getAgentConfig(agentName, workingDirectory) {
+  // Prefer markdown agents (project > user)
+  const projectPath = workingDirectory ? getProjectAgentPath(workingDirectory, agentName) : null;
+  const projectExists = projectPath && fs.existsSync(projectPath);
+
+  const userPath = getUserAgentPath(agentName);
+  const userExists = fs.existsSync(userPath);
+
+  if (projectExists || userExists) {
+    const mdPath = projectExists ? projectPath : userPath;
+    const { frontmatter, body } = parseMdFile(mdPath);
+
+    return {
+      source: 'md',
+      scope: projectExists ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER,
+      config: {
+        ...frontmatter,
+        ...(typeof body === 'string' && body.length > 0 ? { prompt: body } : {}),
+      },
+    };
+  }
+
+  // Then fall back to opencode.json (highest-precedence entry)
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+
+  if (jsonSource.exists && jsonSource.section) {
+    const scope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+    return {
+      source: 'json',
+      scope,
+      config: { ...jsonSource.section },
+    };
+  }
+
+  return {
+    source: 'none',
+    scope: null,
+    config: {},
+  };
+}
+
+/**
+ * Get the effective agent config as OpenCode sees it: md frontmatter and body merged with json
+ * (json wins), with a {file:...} prompt dereferenced and {include:...} lines expanded.
+ * Stored files keep the raw directives.
+ */
+function getEffectiveAgent(agentName, workingDirectory) {
+  const mdInfo = getAgentScope(agentName, workingDirectory);
+  const mdData = mdInfo.path ? parseMdFile(mdInfo.path) : null;
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const jsonSection = isPlainObject(layers.mergedConfig.agent?.[agentName]) ? layers.mergedConfig.agent[agentName] : {};
+
+  const config = {
+    ...(mdData?.frontmatter || {}),
+    ...(mdData?.body ? { prompt: mdData.body } : {}),
+    ...jsonSection
+  };
+
+  if (typeof config.prompt === 'string') {
+    const stack = [];
+    let prompt = config.prompt;
+    if (isPromptFileReference(prompt)) {
+      const baseDir = jsonSection.prompt === undefined ? OPENCODE_CONFIG_DIR : getPromptFileBaseDir(jsonSource.path);
+      const promptFilePath = resolvePromptFilePath(prompt, baseDir);
+      if (!promptFilePath || !fs.existsSync(promptFilePath)) {
+        throw new Error(`Prompt file not found for agent ${agentName}: ${prompt}`);
+      }
+      prompt = stripBom(readTextFile(promptFilePath));
+      stack.push(promptFilePath);
+    }
+    config.prompt = expandPromptIncludes(prompt, stack);
+  }
+
+  let scope = mdInfo.scope;
+  if (!scope && jsonSource.exists) {
+    scope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+  }
+
+  return { name: agentName, scope, config };
+}
+
+/**
+ * Resolve the model an agent will run with: its own `model` (json wins over md),
+ * else the top-level default `model` of the merged config, else null.
+ */
+function resolveAgentModel(agentName, workingDirectory) {
+  const isSet = (value) => typeof value === 'string' && value.trim() !== '';
+  const mergedConfig = readConfig(workingDirectory);
+
+  const jsonModel = mergedConfig.agent?.[agentName]?.model;
+  if (isSet(jsonModel)) {
+    return jsonModel;
+  }
+
+  const mdInfo = getAgentScope(agentName, workingDirectory);
+  const mdModel = mdInfo.path ? parseMdFrontmatterOnly(mdInfo.path).frontmatter.model : undefined;
+  if (isSet(mdModel)) {
+    return mdModel;
+  }
+
+  return isSet(mergedConfig.model) ? mergedConfig.model : null;
+}
+
+/**
+ * List every model referenced in the config: the top-level `model`/`small_model`, the model each agent runs
+ * with (resolveAgentModel, so agents inheriting the default count too) and each command's own `model`
+ * (json wins over md). References are deduplicated by model string.
+ * Returns: [{ model, provider, providerConfigured, owners: [{ kind: 'default'|'small_model'|'agent'|'command', name }] }]
+ * `providerConfigured` reflects whether the provider has an entry in the config's `provider` section.
+ */
+function collectReferencedModels(workingDirectory) {
+  const mergedConfig = readConfig(workingDirectory);
+  const providers = isPlainObject(mergedConfig.provider) ? mergedConfig.provider : {};
+  const references = new Map();
+
+  const addReference = (model, kind, name) => {
+    if (typeof model !== 'string' || !model.trim()) return;
+    if (!references.has(model)) {
+      const slash = model.indexOf('/');
+      const provider = slash > 0 ? model.slice(0, slash) : null;
+      references.set(model, {
+        model,
+        provider,
+        providerConfigured: provider !== null && Object.prototype.hasOwnProperty.call(providers, provider),
+        owners: []
+      });
+    }
+    references.get(model).owners.push({ kind, name });
+  };
+
+  addReference(mergedConfig.model, 'default', null);
+  addReference(mergedConfig.small_model, 'small_model', null);
+
+  for (const agent of listAgents(workingDirectory)) {
+    addReference(resolveAgentModel(agent.name, workingDirectory), 'agent', agent.name);
+  }
+
+  for (const command of listCommands(workingDirectory)) {
+    const jsonModel = mergedConfig.command?.[command.name]?.model;
+    const model = jsonModel ?? (command.source === 'md' ? parseMdFrontmatterOnly(command.path).frontmatter.model : undefined);
+    addReference(model, 'command', command.name);
+  }
+
+  return Array.from(references.values()).sort((a, b) => a.model.localeCompare(b.model));
+}
+
+/**
+ * Find commands whose `agent` and agents whose `extends` name the given agent (json wins over md, like OpenCode)
+ * Returns: [{ kind: 'command'|'agent', name, field, path }] where path is the file holding the reference
+ */
+function findAgentDependents(agentName, workingDirectory) {
+  const layers = readConfigLayers(workingDirectory);
+  const dependents = [];
+  const scan = (kind, entries, field) => {
+    for (const entry of entries) {
+      const jsonValue = layers.mergedConfig[kind]?.[entry.name]?.[field];
+      let value = jsonValue;
+      let referencePath = jsonValue !== undefined ? getJsonEntrySource(layers, kind, entry.name).path : null;
+      if (jsonValue === undefined && entry.source === 'md') {
+        value = readListingFrontmatter(entry.path).frontmatter[field];
+        referencePath = entry.path;
+      }
+      if (value === agentName && !(kind === 'agent' && entry.name === agentName)) {
+        dependents.push({ kind, name: entry.name, field, path: referencePath });
+      }
+    }
+  };
+  scan('command', listCommands(workingDirectory), 'agent');
+  scan('agent', listAgents(workingDirectory), 'extends');
+  return dependents;
+}
+
+/**
+ * List all agents from .md files (across all agent dirs) and opencode.json
+ * Returns: [{ name, scope, source: 'md'|'json', path, dir, description, error? }] sorted by name;
+ * `error: { code, message }` marks an .md file that couldn't be read
+ */
+function listAgents(workingDirectory) {
+  const mdAgents = listMdEntities(getAgentDirs(workingDirectory));
+  const layers = readConfigLayers(workingDirectory);
+  const jsonAgents = isPlainObject(layers.mergedConfig.agent) ? layers.mergedConfig.agent : {};
+
+  const agents = [];
+  for (const { name, scope, path: mdPath } of mdAgents.values()) {
+    const { frontmatter, error } = readListingFrontmatter(mdPath);
+    agents.push({
+      name,
+      scope,
+      source: 'md',
+      path: mdPath,
+      dir: path.dirname(mdPath),
+      description: jsonAgents[name]?.description ?? frontmatter.description ?? null,
+      ...(error ? { error } : {})
+    });
+  }
+
+  for (const [name, section] of Object.entries(jsonAgents)) {
+    if (mdAgents.has(name)) continue;
+    const jsonSource = getJsonEntrySource(layers, 'agent', name);
+    agents.push({
+      name,
+      scope: jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER,
+      source: 'json',
+      path: jsonSource.path,
+      dir: null,
+      description: section?.description ?? null
+    });
+  }
+
+  return agents.sort((a, b) => a.name.localeCompare(b.name));
+}
+
+function createAgent(agentName, config, workingDirectory, scope) {
+  ensureDirs();
+
+  // Check if agent already exists at either level
+  const projectPath = workingDirectory ? getProjectAgentPath(workingDirectory, agentName) : null;
+  const userPath = getUserAgentPath(agentName);
+  
+  if (projectPath && fs.existsSync(projectPath)) {
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Agent ${agentName} already exists as project-level .md file`, { name: agentName });
+  }
+  
+  if (fs.existsSync(userPath)) {
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Agent ${agentName} already exists as user-level .md file`, { name: agentName });
+  }
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  if (jsonSource.exists) {
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Agent ${agentName} already exists in opencode.json`, { name: agentName });
+  }
+
+  // Determine target path based on requested scope
+  let targetPath;
+  let targetScope;
+  
+  if (scope === AGENT_SCOPE.PROJECT && workingDirectory) {
+    ensureProjectAgentDir(workingDirectory);
+    targetPath = projectPath;
+    targetScope = AGENT_SCOPE.PROJECT;
+  } else {
+    targetPath = userPath;
+    targetScope = AGENT_SCOPE.USER;
+  }
+
+  // Extract scope and prompt from config - scope is only used for path determination, not written to file
+  const { prompt, scope: _scopeFromConfig, ...frontmatter } = coerceFieldValues(ENTITY_KIND.AGENT, config);
+  assertRequiredFields(ENTITY_KIND.AGENT, { ...frontmatter, prompt });
+
+  try {
+    writeMdFile(targetPath, frontmatter, normalizePromptReference(prompt) || '', { exclusive: true });
+  } catch (error) {
+    if (error?.code === 'EEXIST') {
+      throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Agent ${agentName} already exists as ${targetScope}-level .md file`, { name: agentName });
+    }
+    throw error;
+  }
+
+  // The md now reserves the name; re-check json in case an entry was written since the first check
+  if (getJsonEntrySource(readConfigLayers(workingDirectory), 'agent', agentName).exists) {
+    fs.unlinkSync(targetPath);
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Agent ${agentName} already exists in opencode.json`, { name: agentName });
+  }
+  console.log(`Created new agent: ${agentName} (scope: ${targetScope}, path: ${targetPath})`);
+  logOperation('create', ENTITY_KIND.AGENT, agentName, Object.keys(config).filter((field) => field !== 'scope'));
+}
+
+/**
+ * Export one agent as a self-contained JSON file (merged md + json fields, prompt file inlined)
+ */
+function exportAgent(agentName, destPath, workingDirectory) {
+  const { config } = getEffectiveAgent(agentName, workingDirectory);
+  if (Object.keys(config).length === 0) {
+    throw new Error(`Agent ${agentName} has no configuration to export`);
+  }
+
+  const document = {
+    format: AGENT_EXPORT_FORMAT,
+    version: AGENT_EXPORT_VERSION,
+    name: agentName,
+    config
+  };
+  fs.mkdirSync(path.dirname(destPath), { recursive: true });
+  fs.writeFileSync(destPath, JSON.stringify(document, null, 2), 'utf8');
+  console.log(`Exported agent ${agentName} to ${destPath}`);
+}
+
+const REDACTED_VALUE = '[redacted]';
+const CREDENTIAL_KEY_PATTERN = /key|token|secret|password|auth|credential/i;
+const SUBSTITUTION_PATTERN = /^\{(?:env|file):[^}]+\}$/;
+
+/**
+ * Replace literal values in `values` (all of them, or only credential-like keys) with REDACTED_VALUE.
+ * {env:...}/{file:...} references are kept: they show where a secret comes from without containing it.
+ */
+function redactValues(values, onlyCredentialKeys) {
+  if (!isPlainObject(values)) return;
+  for (const [key, value] of Object.entries(values)) {
+    if (onlyCredentialKeys && !CREDENTIAL_KEY_PATTERN.test(key)) continue;
+    if (typeof value === 'string' && SUBSTITUTION_PATTERN.test(value.trim())) continue;
+    values[key] = REDACTED_VALUE;
+  }
+}
+
+/**
+ * Write the config as OpenCode computes it to one JSON file, for bug reports: all json layers merged,
+ * every agent and command resolved from md + json with prompt/template {file:...} and {include:...} expanded.
+ * Secrets are redacted: credential-like `provider.*.options` keys, all provider option headers, and MCP
+ * `environment`/`headers` values ({env:...}/{file:...} references are kept as written).
+ * An agent or command that fails to resolve (e.g. a missing prompt file) is exported as { error: { code, message } }.
+ */
+function exportEffectiveConfig(workingDirectory, destPath) {
+  const document = structuredClone(readConfig(workingDirectory));
+
+  const resolveEntry = (resolve) => {
+    try {
+      return resolve().config;
+    } catch (error) {
+      return { error: { code: error.code ?? null, message: error.message } };
+    }
+  };
+  const agents = {};
+  for (const name of listAgentNames(workingDirectory)) {
+    const config = resolveEntry(() => getEffectiveAgent(name, workingDirectory));
+    if (Object.keys(config).length > 0) agents[name] = config;
+  }
+  const commands = {};
+  for (const { name } of listCommandNames(workingDirectory)) {
+    commands[name] = resolveEntry(() => getEffectiveCommand(name, workingDirectory));
+  }
+
+  for (const provider of Object.values(isPlainObject(document.provider) ? document.provider : {})) {
+    if (!isPlainObject(provider?.options)) continue;
+    redactValues(provider.options, true);
+    redactValues(provider.options.headers, false);
+  }
+  for (const server of Object.values(isPlainObject(document.mcp) ? document.mcp : {})) {
+    if (!isPlainObject(server)) continue;
+    redactValues(server.environment, false);
+    redactValues(server.headers, false);
+  }
+
+  if (Object.keys(agents).length > 0) document.agent = agents;
+  else delete document.agent;
+  if (Object.keys(commands).length > 0) document.command = commands;
+  else delete document.command;
+
+  fs.mkdirSync(path.dirname(destPath), { recursive: true });
+  fs.writeFileSync(destPath, JSON.stringify(document, null, 2), 'utf8');
+  console.log(`Exported effective config to ${destPath}`);
+}
+
+/**
+ * Import an agent written by exportAgent, optionally under a new name. Fails if the agent already exists.
+ * Returns the imported agent name.
+ */
+function importAgent(srcPath, newName, workingDirectory, scope) {
+  let document;
+  try {
+    document = JSON.parse(stripBom(readTextFile(srcPath)));
+  } catch (error) {
+    console.error(`Failed to read agent export: ${srcPath}`, error);
+    throw new Error('Failed to read agent export file');
+  }
+
+  if (document?.format !== AGENT_EXPORT_FORMAT || !isPlainObject(document.config)) {
+    throw new Error(`Not an OpenChamber agent export: ${srcPath}`);
+  }
+  if (document.version > AGENT_EXPORT_VERSION) {
+    throw new Error(`Unsupported agent export version: ${document.version}`);
+  }
+
+  const agentName = newName || document.name;
+  if (typeof agentName !== 'string' || !agentName.trim()) {
+    throw new Error('Agent name is required to import');
+  }
+
+  createAgent(agentName, document.config, workingDirectory, scope);
+  return agentName;
+}
+
+/**
+ * List the starter agent templates bundled with OpenChamber
+ * Returns: [{ name, description, frontmatter, prompt }]
+ */
+function listTemplates() {
+  return getAgentTemplates();
+}
+
+/**
+ * Create a new agent from a bundled template. Fails if the agent already exists.
+ */
+function instantiateTemplate(templateName, agentName, workingDirectory, scope) {
+  const template = getAgentTemplates().find((entry) => entry.name === templateName);
+  if (!template) {
+    throw new Error(`Agent template "${templateName}" not found`);
+  }
+
+  createAgent(agentName, { ...template.frontmatter, prompt: template.prompt }, workingDirectory, scope);
+}
+
+/**
+ * Decide where an update to one field of an agent/command lands.
+ * Shared by updateAgent, updateCommand and resolveFieldTarget so they can't drift apart.
+ * - body field: md when md-backed, the referenced file for a json {file:} body, else json
+ * - agent permission: wherever it is already defined (permissionSource), then as below
+ * - other fields: json if already set there, else md when md-backed, else json
+ * Returns: 'md' | 'json' | 'promptFile'
+ */
+function getFieldPlacement(entity, field, { mdExists, creatingNewMd, jsonSection, permissionSource }) {
+  const { bodyField } = getEntityDescriptor(entity);
+  const usesMd = Boolean(mdExists || creatingNewMd);
+
+  if (field === bodyField) {
+    if (usesMd) return 'md';
+    return isPromptFileReference(jsonSection?.[bodyField]) ? 'promptFile' : 'json';
+  }
+
+  if (entity === ENTITY_KIND.AGENT && field === 'permission' && permissionSource?.source) {
+    return permissionSource.source;
+  }
+
+  if (jsonSection?.[field] !== undefined) {
+    return 'json';
+  }
+  return usesMd ? 'md' : 'json';
+}
+
+/**
+ * Update an agent's fields in place (md body/frontmatter or opencode.json, wherever they live)
+ * @param {object} options - { ifMatch } etag precondition; { force } bypasses a lock;
+ *   { promptToFile } stores an inline json prompt as a new prompt file (true for prompts/<agent>.md, or a relative path)
+ */
+function updateAgent(agentName, rawUpdates, workingDirectory, options = {}) {
+  return withLockOverride(options.force, () => {
+    ensureDirs();
+    assertIfMatch(ENTITY_KIND.AGENT, agentName, workingDirectory, options.ifMatch);
+    const updates = coerceFieldValues(ENTITY_KIND.AGENT, rawUpdates);
+    assertRequiredFieldsAfterUpdate(ENTITY_KIND.AGENT, agentName, updates, workingDirectory);
+
+    // Determine correct path: project level takes precedence
+    const { scope, path: mdPath } = getAgentWritePath(agentName, workingDirectory);
+    const mdExists = mdPath && fs.existsSync(mdPath);
+  
+    // Check if agent exists in opencode.json across all config layers
+    const layers = readConfigLayers(workingDirectory);
+    const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+    const jsonSection = jsonSource.section;
+    const hasJsonFields = jsonSource.exists && jsonSection && Object.keys(jsonSection).length > 0;
+    const jsonTarget = jsonSource.exists
+      ? { config: jsonSource.config, path: jsonSource.path }
+      : getJsonWriteTarget(layers, AGENT_SCOPE.USER);
+    let config = jsonTarget.config || {};
+  
+    // Determine if we should create a new md file:
+    // Only for built-in agents (no md file AND no json config)
+    const isBuiltinOverride = !mdExists && !hasJsonFields;
+  
+    let targetPath = mdPath;
+    let targetScope = scope;
+  
+    if (!mdExists && isBuiltinOverride) {
+      // Built-in agent override - create at user level
+      targetPath = getUserAgentPath(agentName);
+      targetScope = AGENT_SCOPE.USER;
+    }
+
+    // Only create md data for existing md files or built-in overrides
+    let mdData = mdExists ? parseMdFile(mdPath) : (isBuiltinOverride ? { frontmatter: {}, body: '' } : null);
+
+    let mdModified = false;
+    let jsonModified = false;
+    // Only create new md if it's a built-in override
+    let creatingNewMd = isBuiltinOverride;
+
+    for (const [field, value] of Object.entries(updates)) {
+      const placement = getFieldPlacement(ENTITY_KIND.AGENT, field, { mdExists, creatingNewMd, jsonSection });
+
+      if (field === 'prompt') {
+        const normalizedValue = typeof value === 'string' ? value : (value == null ? '' : String(value));
+
+        if (placement === 'md') {
+          mdData.body = normalizedValue;
+          mdModified = true;
+          continue;
+        } else if (placement === 'promptFile') {
+          const promptFilePath = resolvePromptFilePath(jsonSection.prompt, getPromptFileBaseDir(jsonSource.path));
+          if (!promptFilePath) {
+            throw new Error(`Invalid prompt file reference for agent ${agentName}`);
+          }
+          writePromptFile(promptFilePath, normalizedValue);
+          continue;
+        } else if (isPromptFileReference(normalizedValue)) {
+          if (!config.agent) config.agent = {};
+          if (!config.agent[agentName]) config.agent[agentName] = {};
+          config.agent[agentName].prompt = normalizePromptReference(normalizedValue);
+          jsonModified = true;
+          continue;
+        }
+
+        // For JSON-only agents, store prompt inline in JSON unless asked to move it to a new prompt file
+        if (!config.agent) config.agent = {};
+        if (!config.agent[agentName]) config.agent[agentName] = {};
+        if (options.promptToFile) {
+          const relativePath = typeof options.promptToFile === 'string' ? options.promptToFile : undefined;
+          const { reference, status } = getNewPromptFileTarget(agentName, relativePath, jsonTarget.path || getUserConfigFile());
+          writePromptFile(status.resolvedPath, normalizedValue);
+          config.agent[agentName].prompt = reference;
+          console.log(`Moved prompt for agent ${agentName} to ${status.resolvedPath}`);
+        } else {
+          config.agent[agentName].prompt = normalizedValue;
+        }
+        jsonModified = true;
+        continue;
+      }
+
+      // Special handling for permission field - uses location detection and preserves non-wildcards
+      if (field === 'permission') {
+        const permissionSource = getAgentPermissionSource(agentName, workingDirectory);
+        const newPermission = mergePermissionWithNonWildcards(value, permissionSource, agentName, workingDirectory);
+
+        if (permissionSource.source === 'md') {
+          // Write to existing .md file
+          const existingMdData = parseMdFile(permissionSource.path);
+          existingMdData.frontmatter.permission = newPermission;
+          writeMdFile(permissionSource.path, existingMdData.frontmatter, existingMdData.body);
+          console.log(`Updated permission in .md file: ${permissionSource.path}`);
+        } else if (permissionSource.source === 'json') {
+          // Write to existing JSON location
+          const existingConfig = readConfigFile(permissionSource.path);
+          if (!existingConfig.agent) existingConfig.agent = {};
+          if (!existingConfig.agent[agentName]) existingConfig.agent[agentName] = {};
+          existingConfig.agent[agentName].permission = newPermission;
+          writeConfig(existingConfig, permissionSource.path);
+          console.log(`Updated permission in JSON: ${permissionSource.path}`);
+        } else if (placement === 'md') {
+          // Permission not defined anywhere - use agent's source location
+          mdData.frontmatter.permission = newPermission;
+          mdModified = true;
+        } else {
+          if (!config.agent) config.agent = {};
+          if (!config.agent[agentName]) config.agent[agentName] = {};
+          config.agent[agentName].permission = newPermission;
+          jsonModified = true;
+        }
+        continue;
+      }
+
+      const inMd = mdData?.frontmatter?.[field] !== undefined;
+      const inJson = jsonSection?.[field] !== undefined;
+
+      if (value === null) {
+        // Treat null as a request to remove the field.
+        if (mdData && inMd) {
+          delete mdData.frontmatter[field];
+          mdModified = true;
+        }
+
+        if (inJson) {
+          if (config.agent?.[agentName]) {
+            delete config.agent[agentName][field];
+
+            if (Object.keys(config.agent[agentName]).length === 0) {
+              delete config.agent[agentName];
+            }
+            if (Object.keys(config.agent).length === 0) {
+              delete config.agent;
+            }
+
+            jsonModified = true;
+          }
+        }
+
+        continue;
+      }
+
+      if (placement === 'md') {
+        mdData.frontmatter[field] = value;
+        mdModified = true;
+      } else {
+        if (!config.agent) config.agent = {};
+        if (!config.agent[agentName]) config.agent[agentName] = {};
+        config.agent[agentName][field] = value;
+        jsonModified = true;
+      }
+    }
+
+    if (mdModified && mdData) {
+      if (mdExists) {
+        assertNotDeletedSinceRead(ENTITY_KIND.AGENT, agentName, targetPath);
+      }
+      writeMdFile(targetPath, mdData.frontmatter, mdData.body);
+    }
+
+    if (jsonModified) {
+      writeConfig(config, jsonTarget.path || getUserConfigFile());
+    }
+
+    console.log(`Updated agent: ${agentName} (scope: ${targetScope}, md: ${mdModified}, json: ${jsonModified})`);
+    logOperation('update', ENTITY_KIND.AGENT, agentName, Object.keys(updates));
+  });
+}
+
+/**
+ * Set a deep value inside an agent's json entry, e.g. setAgentNested('build', '/options/reasoning/effort', 'high').
+ * Intermediate objects are created as needed and siblings are preserved; `undefined` removes the value
+ * (a no-op that writes nothing when the agent or path doesn't exist).
+ * Throws INVALID_POINTER when the pointer is empty or passes through a non-object value.
+ */
+function setAgentNested(agentName, pointer, value, workingDirectory) {
+  const segments = parseJsonPointer(pointer);
+  if (segments.length === 0) {
+    throw createConfigError(CONFIG_ERROR.INVALID_POINTER, 'Pointer must address a field inside the agent', { pointer });
+  }
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const jsonTarget = jsonSource.exists
+    ? { config: jsonSource.config, path: jsonSource.path }
+    : getJsonWriteTarget(layers, workingDirectory ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER);
+  const config = jsonTarget.config || {};
+  if (value === undefined && config.agent?.[agentName] === undefined) {
+    return;
+  }
+  if (!isPlainObject(config.agent)) config.agent = {};
+  if (config.agent[agentName] === undefined) config.agent[agentName] = {};
+
+  let parent = config.agent[agentName];
+  for (let index = 0; index < segments.length; index++) {
+    if (!isPlainObject(parent)) {
+      const traversed = toJsonPointer(segments.slice(0, index));
+      throw createConfigError(
+        CONFIG_ERROR.INVALID_POINTER,
+        `Cannot set ${pointer} on agent ${agentName}: ${traversed || '/'} is not an object`,
+        { pointer }
+      );
+    }
+    const segment = segments[index];
+    if (index === segments.length - 1) {
+      if (value === undefined) {
+        if (!Object.hasOwn(parent, segment)) return;
+        delete parent[segment];
+      } else {
+        parent[segment] = value;
+      }
+      break;
+    }
+    if (parent[segment] === undefined) {
+      if (value === undefined) return;
+      parent[segment] = {};
+    }
+    parent = parent[segment];
+  }
+
+  writeConfig(config, jsonTarget.path || getUserConfigFile());
+  console.log(`Set ${pointer} on agent: ${agentName}`);
+}
+
+/**
+ * Set a numeric agent field (temperature, top_p, maxSteps) after checking it is a finite number
+ * within the range OpenCode accepts. Stringified numbers are stored as real numbers.
+ * Throws OUT_OF_RANGE with { field, min, max } otherwise.
+ */
+function setAgentNumeric(agentName, field, value, workingDirectory) {
+  const range = AGENT_NUMERIC_RANGES[field];
+  if (!range) {
+    throw new Error(`Unknown numeric agent field: ${field}`);
+  }
+
+  const number = coerceFieldValue('number', value);
+  const valid = typeof number === 'number'
+    && Number.isFinite(number)
+    && number >= range.min
+    && number <= range.max
+    && (!range.integer || Number.isInteger(number));
+  if (!valid) {
+    throw createConfigError(
+      CONFIG_ERROR.OUT_OF_RANGE,
+      `${field} must be ${range.integer ? 'an integer' : 'a number'} ${range.max === Infinity ? `of at least ${range.min}` : `between ${range.min} and ${range.max}`}, got ${JSON.stringify(value)}`,
+      { field, min: range.min, max: range.max }
+    );
+  }
+
+  updateAgent(agentName, { [field]: number }, workingDirectory);
+}
+
+/**
+ * Replace an agent's entire config.
+ * - updateAgent merges: omitted fields are kept and only fields sent as null are removed.
+ * - An upsert (createAgent when missing, else updateAgent) also keeps omitted fields.
+ * - replaceAgent keeps nothing: fields absent from `config` are removed from wherever they are stored,
+ *   and every value (including `permission`) is written exactly as given, without merging.
+ * Fields already stored in json stay in json, everything else goes to the .md file (prompt as its body).
+ * The agent must already exist - use createAgent for new agents. The .md and json writes are rolled back together.
+ * Returns: { removed: string[], updated: string[] }
+ */
+function replaceAgent(agentName, config, workingDirectory, options = {}) {
+  return withLockOverride(options.force, () => {
+    ensureDirs();
+
+    const { scope: _scopeFromConfig, ...coerced } = coerceFieldValues(ENTITY_KIND.AGENT, config);
+    const nextConfig = Object.fromEntries(Object.entries(coerced).filter(([, value]) => value != null));
+    assertFieldTypes(ENTITY_KIND.AGENT, nextConfig);
+    assertRequiredFields(ENTITY_KIND.AGENT, nextConfig);
+    const nextFields = new Set(Object.keys(nextConfig));
+
+    const mdInfo = getAgentScope(agentName, workingDirectory);
+    const layers = readConfigLayers(workingDirectory);
+    const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+    const jsonSection = isPlainObject(jsonSource.section) ? jsonSource.section : null;
+
+    if (!mdInfo.path && !jsonSource.exists) {
+      throw new Error(`Agent ${agentName} not found`);
+    }
+
+    const mdData = mdInfo.path ? parseMdFile(mdInfo.path) : null;
+    const previousFields = new Set([
+      ...Object.keys(mdData?.frontmatter || {}),
+      ...(mdData?.body ? ['prompt'] : []),
+      ...Object.keys(jsonSection || {})
+    ]);
+    const removed = Array.from(previousFields).filter((field) => !nextFields.has(field));
+
+    const nextJson = {};
+    const nextFrontmatter = {};
+    let nextBody = '';
+    for (const [field, value] of Object.entries(nextConfig)) {
+      if (!mdData || (jsonSection && field in jsonSection)) {
+        nextJson[field] = value;
+      } else if (field === 'prompt') {
+        nextBody = typeof value === 'string' ? value : String(value);
+      } else {
+        nextFrontmatter[field] = value;
+      }
+    }
+
+    const rollback = snapshotFiles([mdInfo.path, jsonSource.path].filter(Boolean));
+    try {
+      if (mdData) {
+        writeMdFile(mdInfo.path, nextFrontmatter, nextBody);
+      }
+      if (jsonSource.exists) {
+        if (Object.keys(nextJson).length > 0 || !mdData) {
+          jsonSource.config.agent[agentName] = nextJson;
+        } else {
+          delete jsonSource.config.agent[agentName];
+        }
+        writeConfig(jsonSource.config, jsonSource.path);
+      }
+    } catch (error) {
+      rollback.restore('agent replace');
+      throw error;
+    }
+
+    console.log(`Replaced agent: ${agentName} (removed: ${removed.join(', ') || 'none'})`);
+    logOperation('replace', ENTITY_KIND.AGENT, agentName, Array.from(nextFields));
+    return { removed, updated: Array.from(nextFields) };
+  });
+}
+
+/**
+ * Delete an agent's .md files and json entry, or disable it when it is a built-in with no overrides
+ * @param {object} options - { ifMatch } etag precondition; { soft } moves files to the trash; { force } bypasses a lock;
+ *   { ignoreDependents } deletes even when commands/agents still reference it (HAS_DEPENDENTS otherwise);
+ *   { clearReferences } with ignoreDependents, removes those dangling `agent`/`extends` references
+ */
+function deleteAgent(agentName, workingDirectory, options = {}) {
+  return withLockOverride(options.force, () => {
+    assertIfMatch(ENTITY_KIND.AGENT, agentName, workingDirectory, options.ifMatch);
+    const dependents = findAgentDependents(agentName, workingDirectory);
+    if (dependents.length > 0 && !options.ignoreDependents) {
+      throw createConfigError(
+        CONFIG_ERROR.HAS_DEPENDENTS,
+        `Agent ${agentName} is still referenced by ${dependents.map((dependent) => `${dependent.kind} ${dependent.name}`).join(', ')}`,
+        { name: agentName, dependents }
+      );
+    }
+    // Refused before anything is removed, so a read-only user-level file doesn't leave a half-deleted agent
+    const userPath = getUserAgentPath(agentName);
+    if (fs.existsSync(userPath)) {
+      assertNotInReadOnlyAgentDir(userPath);
+    }
+    const trash = options.soft ? createTrashEntry(ENTITY_KIND.AGENT, agentName) : null;
+    const removedPaths = [];
+    let deleted = false;
+
+    // A soft delete that fails part-way puts the trashed files back
+    try {
+      // Check project level first (takes precedence)
+      if (workingDirectory) {
+        const projectPath = getProjectAgentPath(workingDirectory, agentName);
+        if (fs.existsSync(projectPath)) {
+          removeEntityFile(projectPath, trash);
+          console.log(`Deleted project-level agent .md file: ${projectPath}`);
+          removedPaths.push(projectPath);
+          deleted = true;
+        }
+      }
+
+      // Then check user level
+      if (fs.existsSync(userPath)) {
+        removeEntityFile(userPath, trash);
+        console.log(`Deleted user-level agent .md file: ${userPath}`);
+        removedPaths.push(userPath);
+        deleted = true;
+      }
+
+      // Also check json config (highest precedence entry only)
+      const layers = readConfigLayers(workingDirectory);
+      const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+      if (jsonSource.exists && jsonSource.config && jsonSource.path) {
+        if (trash) trash.manifest.json = { path: jsonSource.path, value: jsonSource.section };
+        if (!jsonSource.config.agent) jsonSource.config.agent = {};
+        delete jsonSource.config.agent[agentName];
+        writeConfig(jsonSource.config, jsonSource.path);
+        console.log(`Removed agent from opencode.json: ${agentName}`);
+        removedPaths.push(jsonSource.path);
+        deleted = true;
+      }
+
+      // If nothing was deleted (built-in agent), or a read-only search dir still defines it,
+      // disable it in highest-precedence config
+      const readOnlyPath = findReadOnlyAgentFile(agentName);
+      if (!deleted || readOnlyPath) {
+        const jsonTarget = getJsonWriteTarget(layers, workingDirectory ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER);
+        const targetConfig = jsonTarget.config || {};
+        if (!targetConfig.agent) targetConfig.agent = {};
+        targetConfig.agent[agentName] = { disable: true };
+        writeConfig(targetConfig, jsonTarget.path || getUserConfigFile());
+        console.log(readOnlyPath ? `Disabled agent defined in read-only ${readOnlyPath}: ${agentName}` : `Disabled built-in agent: ${agentName}`);
+      }
+
+      if (trash && deleted) {
+        saveTrashEntry(trash);
+      } else if (deleted) {
+        forgetEntityLock(ENTITY_KIND.AGENT, agentName, removedPaths);
+      }
+    } catch (error) {
+      if (trash) rollBackTrashEntry(trash);
+      throw error;
+    }
+
+    if (options.clearReferences) {
+      for (const dependent of dependents) {
+        const update = dependent.kind === 'command' ? updateCommand : updateAgent;
+        update(dependent.name, { [dependent.field]: null }, workingDirectory, { force: options.force });
+        console.log(`Cleared ${dependent.field} reference to ${agentName} from ${dependent.kind} ${dependent.name}`);
+      }
+    }
+    logOperation(deleted ? 'delete' : 'disable', ENTITY_KIND.AGENT, agentName);
+  });
+}
+
+/**
+ * Dry run of deleteAgent: report what it would remove without touching the filesystem.
+ * Returns: { files: string[], jsonKeys: [{ path, pointer }], disable: { path, pointer } | null }
+ * `disable` is set when nothing can be removed and deleting would disable a built-in instead.
+ */
+function planDeleteAgent(agentName, workingDirectory) {
+  const files = [];
+  if (workingDirectory) {
+    const projectPath = getProjectAgentPath(workingDirectory, agentName);
+    if (fs.existsSync(projectPath)) files.push(projectPath);
+  }
+  const userPath = getUserAgentPath(agentName);
+  if (fs.existsSync(userPath)) files.push(userPath);
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const jsonKeys = jsonSource.exists && jsonSource.path
+    ? [{ path: jsonSource.path, pointer: toJsonPointer(['agent', agentName]) }]
+    : [];
+
+  let disable = null;
+  if (files.length === 0 && jsonKeys.length === 0) {
+    const jsonTarget = getJsonWriteTarget(layers, workingDirectory ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER);
+    disable = { path: jsonTarget.path || getUserConfigFile(), pointer: toJsonPointer(['agent', agentName, 'disable']) };
+  }
+
+  return { files, jsonKeys, disable };
+}
+
+/**
+ * Rewrite every agent .md file (including shadowed ones) in the project and user agent dirs in canonical form:
+ * top-level keys sorted, null values dropped and the standard frontmatter/body spacing.
+ * Files already in canonical form are left untouched. Extra search dirs are never rewritten.
+ * Files without frontmatter or with frontmatter that doesn't parse are skipped; a file that fails
+ * to read or write is reported in `errors` and the run continues.
+ * Returns: { rewritten: string[], skipped: [{ path, reason }], errors: [{ path, code, message }] }
+ */
+function canonicalizeAllAgents(workingDirectory) {
+  const result = { rewritten: [], skipped: [], errors: [] };
+  for (const { dir } of getOwnedAgentDirs(workingDirectory)) {
+    if (!fs.existsSync(dir)) continue;
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+      if (!entry.isFile() || !entry.name.endsWith('.md')) continue;
+
+      const mdPath = path.join(dir, entry.name);
+      try {
+        const { frontmatter, body, hasFrontmatter, frontmatterError } = parseMdFile(mdPath);
+        if (!hasFrontmatter) {
+          result.skipped.push({ path: mdPath, reason: 'no frontmatter' });
+          continue;
+        }
+        if (frontmatterError) {
+          result.skipped.push({ path: mdPath, reason: `invalid frontmatter: ${frontmatterError}` });
+          continue;
+        }
+        // Nested values keep their order: permission patterns are order-sensitive
+        const sorted = Object.fromEntries(
+          Object.keys(frontmatter).sort().map((key) => [key, frontmatter[key]])
+        );
+        if (renderMdFile(sorted, body) === readTextFile(mdPath)) continue;
+
+        writeMdFile(mdPath, sorted, body);
+        result.rewritten.push(mdPath);
+      } catch (error) {
+        result.errors.push({ path: mdPath, code: error?.code ?? null, message: error?.message || String(error) });
+      }
+    }
+  }
+  console.log(`Canonicalized ${result.rewritten.length} agent file(s)`);
+  return result;
+}
+
+/**
+ * Restore a built-in agent to OpenCode's defaults by removing every .md override and json entry for it.
+ * Unlike deleteAgent this never writes a disable flag. Throws for agents that aren't built-in.
+ * Overrides in read-only extra search dirs are never deleted; they are reported in `readOnly` and still apply.
+ * Returns: { removed: string[], readOnly: string[] } - paths of the files that held overrides
+ */
+function resetAgentToBuiltin(agentName, workingDirectory) {
+  if (!BUILTIN_AGENT_NAMES.includes(agentName)) {
+    throw new Error(`Agent ${agentName} is not a built-in agent; nothing to reset to`);
+  }
+
+  const removed = [];
+  assertNotLocked(ENTITY_KIND.AGENT, agentName, workingDirectory);
+
+  // Overrides in read-only extra search dirs are left alone and reported
+  const readOnly = getExtraAgentDirs()
+    .map((dir) => path.join(dir, `${agentName}.md`))
+    .filter((mdPath) => fs.existsSync(mdPath));
+  readOnly.forEach((mdPath) => console.warn(`Not removing ${mdPath}: it is in a read-only agent search dir`));
+
+  for (const { dir } of getOwnedAgentDirs(workingDirectory)) {
+    const mdPath = path.join(dir, `${agentName}.md`);
+    if (fs.existsSync(mdPath)) {
+      fs.unlinkSync(mdPath);
+      console.log(`Deleted agent override .md file: ${mdPath}`);
+      removed.push(mdPath);
+    }
+  }
+
+  const layers = readConfigLayers(workingDirectory);
+  for (const layer of getJsonLayerList(layers)) {
+    const section = layer.config?.agent;
+    if (!isPlainObject(section) || section[agentName] === undefined) continue;
+
+    delete section[agentName];
+    if (Object.keys(section).length === 0) {
+      delete layer.config.agent;
+    }
+    writeConfig(layer.config, layer.path);
+    console.log(`Removed agent override from ${layer.path}: ${agentName}`);
+    removed.push(layer.path);
+  }
+
+  console.log(`Reset agent to built-in defaults: ${agentName}`);
+  return { removed, readOnly };
+}
+
+/**
+ * Swap the names of two existing agents (a becomes b, b becomes a).
+ * Moves .md files via a temporary name, swaps json entries in every layer and renames
+ * {file:...} prompt files named after either agent. All-or-nothing: on failure every touched file is restored.
+ */
+function swapAgentNames(nameA, nameB, workingDirectory) {
+  if (!nameA || !nameB || nameA === nameB) {
+    throw new Error('Two different agent names are required');
+  }
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonLayers = getJsonLayerList(layers);
+  for (const name of [nameA, nameB]) {
+    const inJson = jsonLayers.some((layer) => layer.config?.agent?.[name] !== undefined);
+    if (!getAgentScope(name, workingDirectory).path && !inJson) {
+      throw new Error(`Agent ${name} not found`);
+    }
+  }
+
+  for (const name of [nameA, nameB]) {
+    const readOnlyPath = findReadOnlyAgentFile(name);
+    if (readOnlyPath) {
+      throw new Error(`Cannot swap agents: ${name} is defined in read-only ${readOnlyPath}`);
+    }
+  }
+
+  const swapName = (name) => (name === nameA ? nameB : nameA);
+  const tempSuffix = `.swap-${process.pid}-${Date.now()}`;
+
+  // .md files: swap within each agent directory
+  const mdMoves = [];
+  for (const { dir } of getAgentDirs(workingDirectory)) {
+    for (const name of [nameA, nameB]) {
+      const from = path.join(dir, `${name}.md`);
+      if (fs.existsSync(from)) {
+        mdMoves.push({ from, to: path.join(dir, `${swapName(name)}.md`) });
+      }
+    }
+  }
+
+  // Prompt files whose name matches the agent follow the rename; other references move with the entry
+  const promptMoves = [];
+  for (const layer of jsonLayers) {
+    for (const name of [nameA, nameB]) {
+      const entry = layer.config?.agent?.[name];
+      const resolved = isPlainObject(entry) ? resolvePromptFilePath(entry.prompt) : null;
+      if (!resolved || !fs.existsSync(resolved)) continue;
+      const ext = path.extname(resolved);
+      if (path.basename(resolved, ext) !== name) continue;
+      const to = path.join(path.dirname(resolved), `${swapName(name)}${ext}`);
+      promptMoves.push({ layer, name, from: resolved, to, reference: entry.prompt });
+    }
+  }
+
+  const uniqueMoves = [...mdMoves, ...promptMoves]
+    .filter((move, index, all) => all.findIndex((other) => other.from === move.from) === index);
+
+  for (const move of uniqueMoves) {
+    if (fs.existsSync(move.to) && !uniqueMoves.some((other) => other.from === move.to)) {
+      throw new Error(`Cannot swap agents: ${move.to} already exists`);
+    }
+  }
+  for (const move of mdMoves) {
+    assertMdPathUnlocked(move.from);
+  }
+
+  // Snapshot every file we may touch so a failure can be rolled back
+  const touched = new Set([...uniqueMoves.flatMap((move) => [move.from, move.to]), ...jsonLayers.map((layer) => layer.path)]);
+  const snapshot = snapshotFiles(touched);
+
+  try {
+    const staged = uniqueMoves.map((move) => {
+      const temp = `${move.from}${tempSuffix}`;
+      fs.renameSync(move.from, temp);
+      return { ...move, temp };
+    });
+    for (const move of staged) {
+      fs.renameSync(move.temp, move.to);
+    }
+
+    for (const layer of jsonLayers) {
+      const section = layer.config?.agent;
+      if (!isPlainObject(section) || (section[nameA] === undefined && section[nameB] === undefined)) continue;
+
+      const entryA = section[nameA];
+      const entryB = section[nameB];
+      delete section[nameA];
+      delete section[nameB];
+      if (entryB !== undefined) section[nameA] = entryB;
+      if (entryA !== undefined) section[nameB] = entryA;
+
+      for (const move of promptMoves.filter((candidate) => candidate.layer === layer)) {
+        const target = move.reference.trim().match(PROMPT_FILE_PATTERN)[1].trim();
+        const basename = path.basename(target);
+        const renamed = `${target.slice(0, -basename.length)}${swapName(move.name)}${path.extname(basename)}`;
+        section[swapName(move.name)].prompt = `{file:${renamed}}`;
+      }
+
+      writeConfig(layer.config, layer.path);
+    }
+  } catch (error) {
+    snapshot.restore('swap');
+    for (const move of uniqueMoves) {
+      const temp = `${move.from}${tempSuffix}`;
+      if (fs.existsSync(temp)) fs.unlinkSync(temp);
+    }
+    throw new Error(`Failed to swap agents ${nameA} and ${nameB}: ${error.message}`);
+  }
+
+  console.log(`Swapped agent names: ${nameA} <-> ${nameB}`);
+}
+
+/**
+ * Check whether an agent name resolves to a built-in, an .md agent or a json agent
+ */
+function agentExists(agentName, workingDirectory) {
+  if (BUILTIN_AGENT_NAMES.includes(agentName)) {
+    return true;
+  }
+  if (getAgentScope(agentName, workingDirectory).path) {
+    return true;
+  }
+  const mergedConfig = readConfig(workingDirectory);
+  return isPlainObject(mergedConfig.agent) && mergedConfig.agent[agentName] !== undefined;
+}
+
+/**
+ * Return the command's `agent` binding when it doesn't resolve to a known agent, otherwise null
+ */
+function findUnresolvedCommandAgent(agentName, workingDirectory) {
+  if (typeof agentName !== 'string' || !agentName.trim()) {
+    return null;
+  }
+  return agentExists(agentName, workingDirectory) ? null : agentName;
+}
+
+function getCommandAgentWarnings(commandName, agentName, workingDirectory) {
+  const unresolvedAgent = findUnresolvedCommandAgent(agentName, workingDirectory);
+  if (!unresolvedAgent) {
+    return [];
+  }
+  const warning = `Command ${commandName} references unknown agent "${unresolvedAgent}"`;
+  console.warn(warning);
+  return [warning];
+}
+
+/**
+ * List every lower-precedence definition of a command, i.e. the definitions shadowed by the winning one.
+ * Precedence: project md > user md > custom json > project json > user json
+ * Returns: [{ source: 'md'|'json', scope, path }]
+ */
+function getCommandShadowing(commandName, layers, projectMdPath, userMdPath) {
+  const definitions = [];
+  if (projectMdPath) {
+    definitions.push({ source: 'md', scope: COMMAND_SCOPE.PROJECT, path: projectMdPath });
+  }
+  if (userMdPath) {
+    definitions.push({ source: 'md', scope: COMMAND_SCOPE.USER, path: userMdPath });
+  }
+
+  const { userConfig, projectConfig, customConfig, paths } = layers;
+  const jsonLayers = [
+    { config: customConfig, scope: 'custom', path: paths.customPath },
+    { config: projectConfig, scope: COMMAND_SCOPE.PROJECT, path: paths.projectPath },
+    { config: userConfig, scope: COMMAND_SCOPE.USER, path: paths.userPath }
+  ];
+  for (const layer of jsonLayers) {
+    if (layer.path && layer.config?.command?.[commandName] !== undefined) {
+      definitions.push({ source: 'json', scope: layer.scope, path: layer.path });
+    }
+  }
+
+  return definitions.slice(1);
+}
+
+function getCommandSources(commandName, workingDirectory) {
+  // Check project level first (takes precedence)
+  const projectPath = workingDirectory ? getProjectCommandPath(workingDirectory, commandName) : null;
+  const projectExists = projectPath && fs.existsSync(projectPath);
+  
+  // Then check user level
+  const userPath = getUserCommandPath(commandName);
+  const userExists = fs.existsSync(userPath);
+  
+  // Determine which md file to use (project takes precedence)
+  const mdPath = projectExists ? projectPath : (userExists ? userPath : null);
+  const mdExists = !!mdPath;
+  const mdScope = projectExists ? COMMAND_SCOPE.PROJECT : (userExists ? COMMAND_SCOPE.USER : null);
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
+  const jsonSection = jsonSource.section;
+  const jsonPath = jsonSource.path || layers.paths.customPath || layers.paths.projectPath || layers.paths.userPath;
+  const jsonScope = jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER;
+
+  const sources = {
+    md: {
+      exists: mdExists,
+      path: mdPath,
+      scope: mdScope,
+      fields: []
+    },
+    json: {
+      exists: jsonSource.exists,
+      path: jsonPath,
+      scope: jsonSource.exists ? jsonScope : null,
+      fields: []
+    },
+    // Additional info about both levels
+    projectMd: {
+      exists: projectExists,
+      path: projectPath
+    },
+    userMd: {
+      exists: userExists,
+      path: userPath
+    }
+  };
+
+  let mdAgent;
+  if (mdExists) {
+    const { frontmatter, hasBody } = parseMdFrontmatterOnly(mdPath);
+    sources.md.fields = Object.keys(frontmatter);
+    if (hasBody) {
+      sources.md.fields.push('template');
+    }
+    mdAgent = frontmatter.agent;
+  }
+
+  if (jsonSection) {
+    sources.json.fields = Object.keys(jsonSection);
+  }
+
+  sources.shadowedBy = getCommandShadowing(commandName, layers, projectExists ? projectPath : null, userExists ? userPath : null);
+
+  // Json takes precedence over md for the effective agent binding
+  const boundAgent = jsonSection?.agent !== undefined ? jsonSection.agent : mdAgent;
+  sources.unresolvedAgent = findUnresolvedCommandAgent(boundAgent, workingDirectory);
+
+  return sources;
+}
+
+/**
+ * Get the effective command config as OpenCode sees it: md frontmatter and body merged with json
+ * (json wins), with a {file:...} template dereferenced and {include:...} lines expanded.
+ * Stored files keep the raw directives.
+ */
+function getEffectiveCommand(commandName, workingDirectory) {
+  const mdInfo = getCommandScope(commandName, workingDirectory);
+  const mdData = mdInfo.path ? parseMdFile(mdInfo.path) : null;
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
+  const jsonSection = isPlainObject(layers.mergedConfig.command?.[commandName]) ? layers.mergedConfig.command[commandName] : {};
+
+  if (!mdData && !jsonSource.exists && !BUILTIN_COMMAND_NAMES.includes(commandName)) {
+    throw new Error(`Command "${commandName}" not found`);
+  }
+
+  const config = {
+    ...(mdData?.frontmatter || {}),
+    ...(mdData?.body ? { template: mdData.body } : {}),
+    ...jsonSection
+  };
+
+  if (typeof config.template === 'string') {
+    const stack = [];
+    let template = config.template;
+    if (isPromptFileReference(template)) {
+      const templateFilePath = resolvePromptFilePath(template);
+      if (!templateFilePath || !fs.existsSync(templateFilePath)) {
+        throw new Error(`Template file not found for command ${commandName}: ${template}`);
+      }
+      template = stripBom(readTextFile(templateFilePath));
+      stack.push(templateFilePath);
+    }
+    config.template = expandPromptIncludes(template, stack);
+  }
+
+  let scope = mdInfo.scope;
+  if (!scope && jsonSource.exists) {
+    scope = jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER;
+  }
+
+  return { name: commandName, scope, config };
+}
+
+/**
+ * Declared argument names from a command's `args` field: a list of names or { name } objects, or a map keyed by name
+ */
+function getDeclaredCommandArgs(config) {
+  const args = config?.args;
+  if (Array.isArray(args)) {
+    return args
+      .map((arg) => (typeof arg === 'string' ? arg : arg?.name))
+      .filter((name) => typeof name === 'string' && name.trim())
+      .map((name) => name.trim());
+  }
+  return isPlainObject(args) ? Object.keys(args) : [];
+}
+
+function createArgsError(message, details = {}) {
+  return createConfigError(CONFIG_ERROR.INVALID_FIELD_TYPE, message, { field: 'args', ...details });
+}
+
+/**
+ * Validate structured command args and bring them into the canonical frontmatter shape:
+ * [{ name, required?, hint?, default? }] with `required` only written when true.
+ * Plain strings are accepted as shorthand for { name }. Names must be non-empty and unique.
+ */
+function normalizeCommandArgs(args) {
+  if (!Array.isArray(args)) {
+    throw createArgsError('Field "args" must be a list of arguments');
+  }
+  const seen = new Set();
+  return args.map((arg) => {
+    const spec = typeof arg === 'string' ? { name: arg } : arg;
+    if (!isPlainObject(spec)) {
+      throw createArgsError(`Invalid command argument: ${JSON.stringify(arg)}`);
+    }
+    const name = typeof spec.name === 'string' ? spec.name.trim() : '';
+    if (!name) {
+      throw createArgsError('Command argument names must be non-empty strings');
+    }
+    if (seen.has(name)) {
+      throw createArgsError(`Duplicate command argument: ${name}`, { name });
+    }
+    seen.add(name);
+    if (spec.hint !== undefined && spec.hint !== null && typeof spec.hint !== 'string') {
+      throw createArgsError(`Hint for argument ${name} must be a string`, { name });
+    }
+
+    const normalized = { name };
+    if (spec.required === true) normalized.required = true;
+    if (spec.hint) normalized.hint = spec.hint;
+    if (spec.default !== undefined && spec.default !== null) normalized.default = spec.default;
+    return normalized;
+  });
+}
+
+/**
+ * Usage hint derived from structured args, e.g. "<file> [mode]"
+ */
+function formatArgumentHint(args) {
+  return args.map((arg) => (arg.required ? `<${arg.name}>` : `[${arg.name}]`)).join(' ');
+}
+
+/**
+ * Normalize `args` in a command create/update input in place. Unless the caller sets `argument-hint`
+ * itself, it is derived from the args so the two never disagree. `args: null` removes both.
+ */
+function applyStructuredCommandArgs(values) {
+  if (values.args === undefined) {
+    return values;
+  }
+  if (values.args === null) {
+    if (values['argument-hint'] === undefined) values['argument-hint'] = null;
+    return values;
+  }
+  values.args = normalizeCommandArgs(values.args);
+  if (values['argument-hint'] === undefined) {
+    values['argument-hint'] = values.args.length > 0 ? formatArgumentHint(values.args) : null;
+  }
+  return values;
+}
+
+/**
+ * Cross-check a stored command's declared args against the {{placeholders}} its template uses.
+ * Returns: [{ kind: 'unused'|'undeclared', name }] sorted by name
+ */
+function validateCommandArgs(commandName, workingDirectory) {
+  const { config } = getEffectiveCommand(commandName, workingDirectory);
+  const declared = new Set(getDeclaredCommandArgs(config));
+  const used = new Set();
+  if (typeof config.template === 'string') {
+    for (const match of config.template.matchAll(TEMPLATE_PLACEHOLDER_PATTERN)) {
+      used.add(match[1]);
+    }
+  }
+
+  const issues = [];
+  for (const name of declared) {
+    if (!used.has(name)) issues.push({ kind: 'unused', name });
+  }
+  for (const name of used) {
+    if (!declared.has(name)) issues.push({ kind: 'undeclared', name });
+  }
+  return issues.sort((a, b) => a.name.localeCompare(b.name) || a.kind.localeCompare(b.kind));
+}
+
+/**
+ * List all commands from .md files (project > user) and opencode.json
+ * Returns: [{ name, scope, source: 'md'|'json', path, description, error? }] sorted by name;
+ * `error: { code, message }` marks an .md file that couldn't be read
+ */
+function listCommands(workingDirectory) {
+  const mdCommands = listMdEntities(getCommandDirs(workingDirectory), { recursive: true });
+  const layers = readConfigLayers(workingDirectory);
+  const jsonCommands = isPlainObject(layers.mergedConfig.command) ? layers.mergedConfig.command : {};
+
+  const commands = [];
+  for (const { name, scope, path: mdPath } of mdCommands.values()) {
+    const { frontmatter, error } = readListingFrontmatter(mdPath);
+    const jsonDescription = jsonCommands[name]?.description;
+    commands.push({
+      name,
+      scope,
+      source: 'md',
+      path: mdPath,
+      description: jsonDescription ?? frontmatter.description ?? null,
+      ...(error ? { error } : {})
+    });
+  }
+
+  for (const [name, section] of Object.entries(jsonCommands)) {
+    if (mdCommands.has(name)) continue;
+    const jsonSource = getJsonEntrySource(layers, 'command', name);
+    commands.push({
+      name,
+      scope: jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER,
+      source: 'json',
+      path: jsonSource.path,
+      description: section?.description ?? null
+    });
+  }
+
+  return commands.sort((a, b) => a.name.localeCompare(b.name));
+}
+
+/**
+ * One snapshot of everything the config UI shows, built from a single read of the config layers
+ * and one walk of the agent and command directories (instead of a get*Sources call per entity).
+ * Returns: {
+ *   agents: [{ name, scope, source: 'md'|'json'|'builtin', path, description, builtin, disabled, mdFields, jsonFields, error? }],
+ *   commands: [{ name, scope, source, path, description, shadowed, error? }],
+ *   settings, mcp: [{ name, type, enabled, scope }],
+ *   status: [{ scope, path, exists }]
+ * }
+ */
+function getConfigTree(workingDirectory) {
+  const layers = readConfigLayers(workingDirectory);
+  const { mergedConfig } = layers;
+  const jsonScopeOf = (sectionKey, name) => (
+    getJsonEntrySource(layers, sectionKey, name).path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER
+  );
+
+  const jsonAgents = isPlainObject(mergedConfig.agent) ? mergedConfig.agent : {};
+  const mdAgents = listMdEntities(getAgentDirs(workingDirectory));
+  const agentNames = new Set([...mdAgents.keys(), ...Object.keys(jsonAgents), ...BUILTIN_AGENT_NAMES]);
+  const agents = Array.from(agentNames).map((name) => {
+    const md = mdAgents.get(name);
+    const parsed = md ? readListingFrontmatter(md.path) : null;
+    const frontmatter = parsed?.frontmatter || {};
+    const section = isPlainObject(jsonAgents[name]) ? jsonAgents[name] : {};
+    const hasJson = jsonAgents[name] !== undefined;
+    let source = 'builtin';
+    if (md) source = STORAGE_TARGET.MD;
+    else if (hasJson) source = STORAGE_TARGET.JSON;
+    return {
+      name,
+      scope: md ? md.scope : (hasJson ? jsonScopeOf('agent', name) : null),
+      source,
+      path: md ? md.path : (hasJson ? getJsonEntrySource(layers, 'agent', name).path : null),
+      description: section.description ?? frontmatter.description ?? null,
+      builtin: BUILTIN_AGENT_NAMES.includes(name),
+      disabled: (section.disable ?? frontmatter.disable) === true,
+      mdFields: md ? [...Object.keys(frontmatter), ...(parsed.hasBody ? ['prompt'] : [])] : [],
+      jsonFields: Object.keys(section),
+      ...(parsed?.error ? { error: parsed.error } : {})
+    };
+  });
+
+  const jsonCommands = isPlainObject(mergedConfig.command) ? mergedConfig.command : {};
+  const commandDirs = getCommandDirs(workingDirectory);
+  const projectMdCommands = listMdEntities(commandDirs.filter((dir) => dir.scope === COMMAND_SCOPE.PROJECT), { recursive: true });
+  const userMdCommands = listMdEntities(commandDirs.filter((dir) => dir.scope === COMMAND_SCOPE.USER), { recursive: true });
+  const commandNames = new Set([...projectMdCommands.keys(), ...userMdCommands.keys(), ...Object.keys(jsonCommands)]);
+  const commands = Array.from(commandNames).map((name) => {
+    const projectMd = projectMdCommands.get(name);
+    const userMd = userMdCommands.get(name);
+    const md = projectMd || userMd;
+    const section = isPlainObject(jsonCommands[name]) ? jsonCommands[name] : {};
+    const parsed = md ? readListingFrontmatter(md.path) : null;
+    const description = section.description ?? parsed?.frontmatter.description;
+    return {
+      name,
+      scope: md ? md.scope : jsonScopeOf('command', name),
+      source: md ? STORAGE_TARGET.MD : STORAGE_TARGET.JSON,
+      path: md ? md.path : getJsonEntrySource(layers, 'command', name).path,
+      description: description ?? null,
+      shadowed: getCommandShadowing(name, layers, projectMd?.path ?? null, userMd?.path ?? null),
+      ...(parsed?.error ? { error: parsed.error } : {})
+    };
+  });
+
+  const settings = {};
+  for (const key of Object.keys(GENERAL_SETTINGS)) {
+    settings[key] = layers.userConfig[key];
+  }
+
+  const mcpServers = isPlainObject(mergedConfig.mcp) ? mergedConfig.mcp : {};
+  const mcp = Object.entries(mcpServers).map(([name, server]) => ({
+    name,
+    type: server?.type ?? null,
+    enabled: server?.enabled !== false,
+    scope: jsonScopeOf('mcp', name)
+  }));
+
+  const status = [
+    { scope: AGENT_SCOPE.USER, path: layers.paths.userPath },
+    { scope: AGENT_SCOPE.PROJECT, path: layers.paths.projectPath },
+    { scope: 'custom', path: layers.paths.customPath }
+  ]
+    .filter((layer) => layer.path)
+    .map((layer) => ({ ...layer, exists: fs.existsSync(layer.path) }));
+
+  const byName = (a, b) => a.name.localeCompare(b.name);
+  return {
+    agents: agents.sort(byName),
+    commands: commands.sort(byName),
+    settings,
+    mcp: mcp.sort(byName),
+    status
+  };
+}
+
+/**
+ * Incremental listCommands for very large command directories: each summary is passed to
+ * onCommand as soon as its frontmatter is parsed, project commands first, json-only commands last.
+ * Summaries have the listCommands shape but arrive unsorted. onCommand may return a promise to apply backpressure.
+ * Resolves to the number of commands sent.
+ */
+async function streamCommands(workingDirectory, onCommand) {
+  const layers = readConfigLayers(workingDirectory);
+  const jsonCommands = isPlainObject(layers.mergedConfig.command) ? layers.mergedConfig.command : {};
+  const seen = new Set();
+
+  const walk = async (scope, dir, prefix) => {
+    let handle;
+    try {
+      handle = await fs.promises.opendir(dir);
+    } catch (error) {
+      if (error?.code === 'ENOENT') return;
+      throw error;
+    }
+    for await (const entry of handle) {
+      if (entry.isDirectory()) {
+        await walk(scope, path.join(dir, entry.name), `${prefix}${entry.name}/`);
+        continue;
+      }
+      if (!entry.isFile() || !entry.name.endsWith('.md')) continue;
+      const name = `${prefix}${entry.name.slice(0, -'.md'.length)}`;
+      if (seen.has(name)) continue;
+      seen.add(name);
+
+      const mdPath = path.join(dir, entry.name);
+      const { frontmatter, error } = readListingFrontmatter(mdPath);
+      await onCommand({
+        name,
+        scope,
+        source: 'md',
+        path: mdPath,
+        description: jsonCommands[name]?.description ?? frontmatter.description ?? null,
+        ...(error ? { error } : {})
+      });
+    }
+  };
+
+  for (const { scope, dir } of getCommandDirs(workingDirectory)) {
+    await walk(scope, dir, '');
+  }
+
+  for (const [name, section] of Object.entries(jsonCommands)) {
+    if (seen.has(name)) continue;
+    seen.add(name);
+    const jsonSource = getJsonEntrySource(layers, 'command', name);
+    await onCommand({
+      name,
+      scope: jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER,
+      source: 'json',
+      path: jsonSource.path,
+      description: section?.description ?? null
+    });
+  }
+
+  return seen.size;
+}
+
+/**
+ * Cheap agent enumeration for a sidebar: directory entries plus json keys, no .md file is opened.
+ * Returns sorted agent names.
+ */
+function listAgentNames(workingDirectory) {
+  const names = new Set(listMdEntities(getAgentDirs(workingDirectory)).keys());
+  const jsonAgents = readConfig(workingDirectory).agent;
+  if (isPlainObject(jsonAgents)) {
+    for (const name of Object.keys(jsonAgents)) names.add(name);
+  }
+  return Array.from(names).sort((a, b) => a.localeCompare(b));
+}
+
+/**
+ * Cheap command enumeration for a sidebar: directory entries plus json keys, no .md file is opened.
+ * Returns: [{ name, scope }] sorted by name
+ */
+function listCommandNames(workingDirectory) {
+  const mdCommands = listMdEntities(getCommandDirs(workingDirectory), { recursive: true });
+  const layers = readConfigLayers(workingDirectory);
+  const commands = Array.from(mdCommands.values()).map(({ name, scope }) => ({ name, scope }));
+
+  const jsonCommands = isPlainObject(layers.mergedConfig.command) ? layers.mergedConfig.command : {};
+  for (const name of Object.keys(jsonCommands)) {
+    if (mdCommands.has(name)) continue;
+    const jsonSource = getJsonEntrySource(layers, 'command', name);
+    commands.push({
+      name,
+      scope: jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER
+    });
+  }
+
+  return commands.sort((a, b) => a.name.localeCompare(b.name));
+}
+
+/**
+ * List commands grouped by namespace (the part before the first delimiter, e.g. "git" for "git:commit").
+ * Commands without a namespace are grouped under UNGROUPED_COMMAND_KEY. Groups are sorted by key.
+ */
+function listCommandsGrouped(workingDirectory, delimiters = [':', '/']) {
+  const groups = {};
+  for (const command of listCommands(workingDirectory)) {
+    const positions = delimiters
+      .map((delimiter) => command.name.indexOf(delimiter))
+      .filter((index) => index > 0);
+    const key = positions.length > 0 ? command.name.slice(0, Math.min(...positions)) : UNGROUPED_COMMAND_KEY;
+    if (!groups[key]) groups[key] = [];
+    groups[key].push(command);
+  }
+
+  return Object.fromEntries(Object.keys(groups).sort().map((key) => [key, groups[key]]));
+}
+
+function createCommand(commandName, config, workingDirectory, scope) {
+  ensureDirs();
+
+  // Check if command already exists at either level
+  const projectPath = workingDirectory ? getProjectCommandPath(workingDirectory, commandName) : null;
+  const userPath = getUserCommandPath(commandName);
+  
+  if (projectPath && fs.existsSync(projectPath)) {
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Command ${commandName} already exists as project-level .md file`, { name: commandName });
+  }
+  
+  if (fs.existsSync(userPath)) {
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Command ${commandName} already exists as user-level .md file`, { name: commandName });
+  }
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
+  if (jsonSource.exists) {
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Command ${commandName} already exists in opencode.json`, { name: commandName });
+  }
+
+  // Determine target path based on requested scope
+  let targetPath;
+  let targetScope;
+  
+  if (scope === COMMAND_SCOPE.PROJECT && workingDirectory) {
+    ensureProjectCommandDir(workingDirectory);
+    targetPath = projectPath;
+    targetScope = COMMAND_SCOPE.PROJECT;
+  } else {
+    targetPath = userPath;
+    targetScope = COMMAND_SCOPE.USER;
+  }
+
+  // Extract scope from config - it's only used for path determination, not written to file
+  const { template, scope: _scopeFromConfig, ...frontmatter } = applyStructuredCommandArgs(coerceFieldValues(ENTITY_KIND.COMMAND, config));
+  assertFieldTypes(ENTITY_KIND.COMMAND, frontmatter);
+  assertRequiredFields(ENTITY_KIND.COMMAND, { ...frontmatter, template });
+
+  try {
+    writeMdFile(targetPath, frontmatter, normalizePromptReference(template) || '', { exclusive: true });
+  } catch (error) {
+    if (error?.code === 'EEXIST') {
+      throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Command ${commandName} already exists as ${targetScope}-level .md file`, { name: commandName });
+    }
+    throw error;
+  }
+
+  // The md now reserves the name; re-check json in case an entry was written since the first check
+  if (getJsonEntrySource(readConfigLayers(workingDirectory), 'command', commandName).exists) {
+    fs.unlinkSync(targetPath);
+    throw createConfigError(CONFIG_ERROR.ALREADY_EXISTS, `Command ${commandName} already exists in opencode.json`, { name: commandName });
+  }
+  console.log(`Created new command: ${commandName} (scope: ${targetScope}, path: ${targetPath})`);
+
+  // Project scope without a working directory silently lands in user scope; report it
+  const scopeFallback = scope === COMMAND_SCOPE.PROJECT && targetScope !== COMMAND_SCOPE.PROJECT;
+  if (scopeFallback) {
+    console.log(`Command ${commandName} saved to user scope: no project directory provided`);
+  }
+  logOperation('create', ENTITY_KIND.COMMAND, commandName, Object.keys(config).filter((field) => field !== 'scope'));
+
+  return {
+    scope: targetScope,
+    path: targetPath,
+    scopeFallback,
+    warnings: getCommandAgentWarnings(commandName, frontmatter.agent, workingDirectory),
+  };
+}
+
+function updateCommand(commandName, rawUpdates, workingDirectory, options = {}) {
+  return withLockOverride(options.force, () => {
+    ensureDirs();
+    assertIfMatch(ENTITY_KIND.COMMAND, commandName, workingDirectory, options.ifMatch);
+    const updates = applyStructuredCommandArgs(coerceFieldValues(ENTITY_KIND.COMMAND, rawUpdates));
+    assertFieldTypes(ENTITY_KIND.COMMAND, updates);
+    assertRequiredFieldsAfterUpdate(ENTITY_KIND.COMMAND, commandName, updates, workingDirectory);
+
+    // Determine correct path: project level takes precedence
+    const { scope, path: mdPath } = getCommandWritePath(commandName, workingDirectory);
+    const mdExists = mdPath && fs.existsSync(mdPath);
+
+    const layers = readConfigLayers(workingDirectory);
+    const jsonSource = getJsonEntrySource(layers, 'command', commandName);
+    const jsonSection = jsonSource.section;
+    const hasJsonFields = jsonSource.exists && jsonSection && Object.keys(jsonSection).length > 0;
+    const jsonTarget = jsonSource.exists
+      ? { config: jsonSource.config, path: jsonSource.path }
+      : getJsonWriteTarget(layers, workingDirectory ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER);
+    let config = jsonTarget.config || {};
+
+    // Only create a new md file for built-in overrides (no md + no json)
+    const isBuiltinOverride = !mdExists && !hasJsonFields;
+
+    let targetPath = mdPath;
+    let targetScope = scope;
+
+    if (!mdExists && isBuiltinOverride) {
+      // Built-in command override - create at user level
+      targetPath = getUserCommandPath(commandName);
+      targetScope = COMMAND_SCOPE.USER;
+    }
+
+    const mdData = mdExists ? parseMdFile(mdPath) : (isBuiltinOverride ? { frontmatter: {}, body: '' } : null);
+
+    let mdModified = false;
+    let jsonModified = false;
+    let creatingNewMd = isBuiltinOverride;
+
+    for (const [field, value] of Object.entries(updates)) {
+      const placement = getFieldPlacement(ENTITY_KIND.COMMAND, field, { mdExists, creatingNewMd, jsonSection });
+
+      if (field === 'template') {
+        const normalizedValue = typeof value === 'string' ? value : (value == null ? '' : String(value));
+
+        if (placement === 'md') {
+          mdData.body = normalizedValue;
+          mdModified = true;
+          continue;
+        } else if (placement === 'promptFile') {
+          const templateFilePath = resolvePromptFilePath(jsonSection.template);
+          if (!templateFilePath) {
+            throw new Error(`Invalid template file reference for command ${commandName}`);
+          }
+          writePromptFile(templateFilePath, normalizedValue);
+          continue;
+        } else if (isPromptFileReference(normalizedValue)) {
+          if (!config.command) config.command = {};
+          if (!config.command[commandName]) config.command[commandName] = {};
+          config.command[commandName].template = normalizePromptReference(normalizedValue);
+          jsonModified = true;
+          continue;
+        }
+
+        // For JSON-only commands, store template inline in JSON
+        if (!config.command) config.command = {};
+        if (!config.command[commandName]) config.command[commandName] = {};
+        config.command[commandName].template = normalizedValue;
+        jsonModified = true;
+        continue;
+      }
+
+      if (placement === 'md') {
+        mdData.frontmatter[field] = value;
+        mdModified = true;
+      } else {
+        if (!config.command) config.command = {};
+        if (!config.command[commandName]) config.command[commandName] = {};
+        config.command[commandName][field] = value;
+        jsonModified = true;
+      }
+    }
+
+    if (mdModified && mdData) {
+      if (mdExists) {
+        assertNotDeletedSinceRead(ENTITY_KIND.COMMAND, commandName, targetPath);
+      }
+      writeMdFile(targetPath, mdData.frontmatter, mdData.body);
+    }
+
+    if (jsonModified) {
+      writeConfig(config, jsonTarget.path || getUserConfigFile());
+    }
+
+    console.log(`Updated command: ${commandName} (scope: ${targetScope}, md: ${mdModified}, json: ${jsonModified})`);
+    logOperation('update', ENTITY_KIND.COMMAND, commandName, Object.keys(updates));
+
+    return { warnings: getCommandAgentWarnings(commandName, updates.agent, workingDirectory) };
+  });
+}
+
+/**
+ * Report where updateAgent/updateCommand would write a field, without writing anything.
+ * Uses the same getFieldPlacement rules as the update functions.
+ * Returns: { target: 'md'|'json'|'promptFile', path }
+ */
+function resolveFieldTarget(entity, name, field, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const { sectionKey, bodyField } = descriptor;
+
+  const { path: mdPath } = descriptor.getWritePath(name, workingDirectory);
+  const mdExists = Boolean(mdPath && fs.existsSync(mdPath));
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, sectionKey, name);
+  const jsonSection = jsonSource.section;
+  const hasJsonFields = jsonSource.exists && jsonSection && Object.keys(jsonSection).length > 0;
+  const defaultJsonScope = entity === ENTITY_KIND.AGENT || !workingDirectory ? AGENT_SCOPE.USER : COMMAND_SCOPE.PROJECT;
+  const jsonPath = (jsonSource.exists ? jsonSource.path : getJsonWriteTarget(layers, defaultJsonScope).path) || getUserConfigFile();
+
+  // Built-in overrides (no md, no json) get a new user-level md file
+  const creatingNewMd = !mdExists && !hasJsonFields;
+  const mdTargetPath = mdExists
+    ? mdPath
+    : (entity === ENTITY_KIND.AGENT ? getUserAgentPath(name) : getUserCommandPath(name));
+  const permissionSource = entity === ENTITY_KIND.AGENT && field === 'permission'
+    ? getAgentPermissionSource(name, workingDirectory)
+    : null;
+
+  const target = getFieldPlacement(entity, field, { mdExists, creatingNewMd, jsonSection, permissionSource });
+  if (permissionSource?.source) {
+    return { target, path: permissionSource.path };
+  }
+  if (target === 'promptFile') {
+    return { target, path: resolvePromptFilePath(jsonSection[bodyField]) };
+  }
+  return { target, path: target === 'md' ? mdTargetPath : jsonPath };
+}
+
+// ============== PRECONDITIONS ==============
+
+/**
+ * Opaque token for an entity's current on-disk state: a hash of its .md bytes and its winning json entry.
+ * Changes to other entities in the same opencode.json don't change the token.
+ */
+function getEntityEtag(entity, name, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const hash = crypto.createHash('sha256');
+
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  hash.update('md\0');
+  if (mdInfo.path && fs.existsSync(mdInfo.path)) {
+    hash.update(mdInfo.path);
+    hash.update('\0');
+    hash.update(fs.readFileSync(mdInfo.path));
+  }
+
+  const jsonSource = getJsonEntrySource(readConfigLayers(workingDirectory), descriptor.sectionKey, name);
+  hash.update('\0json\0');
+  if (jsonSource.exists) {
+    hash.update(jsonSource.path || '');
+    hash.update('\0');
+    hash.update(JSON.stringify(jsonSource.section));
+  }
+
+  return hash.digest('hex');
+}
+
+/**
+ * Throw PRECONDITION_FAILED when `ifMatch` is given and no longer matches the entity's etag
+ */
+function assertIfMatch(entity, name, workingDirectory, ifMatch) {
+  if (ifMatch === undefined || ifMatch === null) {
+    return;
+  }
+  const actual = getEntityEtag(entity, name, workingDirectory);
+  if (actual !== ifMatch) {
+    throw createConfigError(
+      CONFIG_ERROR.PRECONDITION_FAILED,
+      `${getEntityDescriptor(entity).label} ${name} was modified since it was read`,
+      { expected: ifMatch, actual }
+    );
+  }
+}
+
+/**
+ * Throw CONCURRENT_DELETION when an .md file read at the start of an update has since been removed
+ * (e.g. deleted in an editor), rather than recreating an agent or command the user just deleted
+ */
+function assertNotDeletedSinceRead(entity, name, filePath) {
+  if (!fs.existsSync(filePath)) {
+    throw createConfigError(
+      CONFIG_ERROR.CONCURRENT_DELETION,
+      `${getEntityDescriptor(entity).label} ${name} was deleted while it was being updated (${filePath})`,
+      { path: filePath }
+    );
+  }
+}
+
+// ============== LOCKS ==============
+
+function readLocks() {
+  let stored = {};
+  try {
+    stored = JSON.parse(fs.readFileSync(LOCK_FILE, 'utf8'));
+  } catch (error) {
+    if (error?.code !== 'ENOENT') console.warn(`Ignoring unreadable lock file ${LOCK_FILE}:`, error.message);
+  }
+  const toScopes = (value) => {
+    // Older sidecars listed bare names, which were user-level locks
+    if (Array.isArray(value)) {
+      return new Map([[USER_LOCK_SCOPE, new Set(value)]]);
+    }
+    const scopes = new Map();
+    for (const [scope, names] of Object.entries(isPlainObject(value) ? value : {})) {
+      if (Array.isArray(names)) scopes.set(scope, new Set(names));
+    }
+    return scopes;
+  };
+  return {
+    [ENTITY_KIND.AGENT]: toScopes(stored?.agent),
+    [ENTITY_KIND.COMMAND]: toScopes(stored?.command)
+  };
+}
+
+function writeLocks(locks) {
+  const toObject = (scopes) => Object.fromEntries(
+    Array.from(scopes)
+      .filter(([, names]) => names.size > 0)
+      .sort(([a], [b]) => a.localeCompare(b))
+      .map(([scope, names]) => [scope, Array.from(names).sort()])
+  );
+  recordBatchDir(path.dirname(LOCK_FILE));
+  recordBatchWrite(LOCK_FILE);
+  fs.mkdirSync(path.dirname(LOCK_FILE), { recursive: true });
+  fs.writeFileSync(LOCK_FILE, JSON.stringify({
+    agent: toObject(locks[ENTITY_KIND.AGENT]),
+    command: toObject(locks[ENTITY_KIND.COMMAND])
+  }, null, 2), 'utf8');
+}
+
+function isLockedInScope(locks, entity, scope, name) {
+  return locks[entity]?.get(scope)?.has(name) === true;
+}
+
+/**
+ * Lock scope of a config file or .md path: 'user' for the user config dir, the custom config file and
+ * extra agent dirs, otherwise the root of the project it belongs to (the parent of its .opencode dir)
+ */
+function getLockScopeForPath(filePath) {
+  const resolved = path.resolve(filePath);
+  if (
+    isWithinDirectory(resolved, OPENCODE_CONFIG_DIR)
+    || (CUSTOM_CONFIG_FILE && resolved === path.resolve(CUSTOM_CONFIG_FILE))
+    || getExtraAgentDirs().some((dir) => isWithinDirectory(resolved, dir))
+  ) {
+    return USER_LOCK_SCOPE;
+  }
+  for (let dir = path.dirname(resolved); path.dirname(dir) !== dir; dir = path.dirname(dir)) {
+    if (path.basename(dir) === '.opencode') {
+      return path.dirname(dir);
+    }
+  }
+  return path.dirname(resolved);
+}
+
+/**
+ * Lock scope of the definition an edit from workingDirectory would change (its winning .md, else its
+ * winning json entry), or null when the entity isn't defined anywhere
+ */
+function getEntityLockScope(entity, name, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  if (mdInfo.path) {
+    return getLockScopeForPath(mdInfo.path);
+  }
+  const jsonSource = getJsonEntrySource(readConfigLayers(workingDirectory), descriptor.sectionKey, name);
+  return jsonSource.exists && jsonSource.path ? getLockScopeForPath(jsonSource.path) : null;
+}
+
+/**
+ * Drop a deleted entity's locks in the scopes its files were removed from,
+ * so a new entity of the same name there doesn't start out locked
+ */
+function forgetEntityLock(entity, name, removedPaths) {
+  const locks = readLocks();
+  let changed = false;
+  for (const scope of new Set(removedPaths.map(getLockScopeForPath))) {
+    changed = locks[entity].get(scope)?.delete(name) || changed;
+  }
+  if (changed) {
+    writeLocks(locks);
+  }
+}
+
+/**
+ * An entity is locked when the sidecar (LOCK_FILE) lists its name under the lock scope of the
+ * definition seen from workingDirectory. Same-named entities in other projects or scopes are unaffected.
+ */
+function isEntityLocked(entity, name, workingDirectory) {
+  const scope = getEntityLockScope(entity, name, workingDirectory);
+  return scope !== null && isLockedInScope(readLocks(), entity, scope, name);
+}
+
+function createLockedError(entity, name, scope) {
+  return createConfigError(
+    CONFIG_ERROR.LOCKED,
+    `${getEntityDescriptor(entity).label} ${name} is locked; pass force to modify it`,
+    { name, scope }
+  );
+}
+
+/**
+ * Throw LOCKED for a locked entity unless `force` is set or a forced operation is running
+ */
+function assertNotLocked(entity, name, workingDirectory, force) {
+  if (force || lockOverrideDepth > 0 || !isEntityLocked(entity, name, workingDirectory)) {
+    return;
+  }
+  throw createLockedError(entity, name, getEntityLockScope(entity, name, workingDirectory));
+}
+
+/**
+ * Run fn with lock checks suspended when force is set (used by mutators that accept { force })
+ */
+function withLockOverride(force, fn) {
+  if (!force) {
+    return fn();
+  }
+  lockOverrideDepth += 1;
+  try {
+    return fn();
+  } finally {
+    lockOverrideDepth -= 1;
+  }
+}
+
+/**
+ * The agent/command an .md path belongs to, from the agent(s)/command(s) dir it sits in, or null
+ */
+function getEntityForMdPath(filePath) {
+  const resolved = path.resolve(filePath);
+  if (!resolved.endsWith('.md')) {
+    return null;
+  }
+  const extraAgentDirs = getExtraAgentDirs();
+  for (let dir = path.dirname(resolved); path.dirname(dir) !== dir; dir = path.dirname(dir)) {
+    const parent = path.dirname(dir);
+    const inConfigRoot = parent === OPENCODE_CONFIG_DIR || path.basename(parent) === '.opencode';
+    const base = path.basename(dir);
+    let entity = null;
+    if (extraAgentDirs.includes(dir) || (inConfigRoot && (base === 'agents' || base === 'agent'))) {
+      entity = ENTITY_KIND.AGENT;
+    } else if (inConfigRoot && (base === 'commands' || base === 'command')) {
+      entity = ENTITY_KIND.COMMAND;
+    }
+    if (entity) {
+      return { entity, name: path.relative(dir, resolved).slice(0, -'.md'.length).split(path.sep).join('/') };
+    }
+  }
+  return null;
+}
+
+/**
+ * Write-path lock check for an .md file about to be written, moved or removed
+ */
+function assertMdPathUnlocked(filePath) {
+  if (lockOverrideDepth > 0) {
+    return;
+  }
+  const owner = getEntityForMdPath(filePath);
+  const scope = owner ? getLockScopeForPath(filePath) : null;
+  if (owner && isLockedInScope(readLocks(), owner.entity, scope, owner.name)) {
+    throw createLockedError(owner.entity, owner.name, scope);
+  }
+}
+
+/**
+ * Write-path lock check for a json config: every agent/command entry that differs from the file's
+ * current content (added, changed or removed) must be unlocked
+ */
+function assertConfigWriteUnlocked(config, filePath) {
+  if (lockOverrideDepth > 0) {
+    return;
+  }
+  const scope = getLockScopeForPath(filePath);
+  const locks = readLocks();
+  const lockedNames = {
+    [ENTITY_KIND.AGENT]: locks[ENTITY_KIND.AGENT].get(scope) ?? new Set(),
+    [ENTITY_KIND.COMMAND]: locks[ENTITY_KIND.COMMAND].get(scope) ?? new Set()
+  };
+  if (lockedNames[ENTITY_KIND.AGENT].size === 0 && lockedNames[ENTITY_KIND.COMMAND].size === 0) {
+    return;
+  }
+  const current = fs.existsSync(filePath) || getActiveBatch()?.json.has(filePath) ? readConfigFile(filePath) : {};
+  for (const entity of [ENTITY_KIND.AGENT, ENTITY_KIND.COMMAND]) {
+    const sectionKey = getEntityDescriptor(entity).sectionKey;
+    const before = isPlainObject(current?.[sectionKey]) ? current[sectionKey] : {};
+    const after = isPlainObject(config?.[sectionKey]) ? config[sectionKey] : {};
+    for (const name of lockedNames[entity]) {
+      if (JSON.stringify(before[name]) !== JSON.stringify(after[name])) {
+        throw createLockedError(entity, name, scope);
+      }
+    }
+  }
+}
+
+/**
+ * Lock or unlock an agent/command in the lock scope of its definition seen from workingDirectory
+ * (the user config, or one project). The flag lives in the lock sidecar rather than as `locked: true`
+ * in the entity itself, because OpenCode forwards unknown json agent keys to the provider as model options.
+ * Unlocking also drops a legacy `locked` key.
+ */
+function setEntityLock(entity, name, locked, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  const layers = readConfigLayers(workingDirectory);
+  const scope = getEntityLockScope(entity, name, workingDirectory);
+
+  if (scope === null) {
+    throw new Error(`${descriptor.label} ${name} not found`);
+  }
+
+  const locks = readLocks();
+  if (!locks[entity].has(scope)) {
+    locks[entity].set(scope, new Set());
+  }
+  if (locked) {
+    locks[entity].get(scope).add(name);
+  } else {
+    locks[entity].get(scope).delete(name);
+  }
+  writeLocks(locks);
+
+  if (!locked) {
+    if (mdInfo.path) {
+      const mdData = parseMdFile(mdInfo.path);
+      if (mdData.frontmatter.locked !== undefined) {
+        delete mdData.frontmatter.locked;
+        writeMdFile(mdInfo.path, mdData.frontmatter, mdData.body);
+      }
+    }
+    for (const layer of getJsonLayerList(layers)) {
+      const entry = layer.config?.[descriptor.sectionKey]?.[name];
+      if (!isPlainObject(entry) || entry.locked === undefined) continue;
+      delete entry.locked;
+      writeConfig(layer.config, layer.path);
+    }
+  }
+
+  console.log(`${locked ? 'Locked' : 'Unlocked'} ${entity}: ${name} (${scope})`);
+}
+
+// ============== FIELD COERCION ==============
+
+function coerceFieldValue(type, value) {
+  if (typeof value !== 'string') {
+    return value;
+  }
+  const trimmed = value.trim();
+  if (type === 'boolean') {
+    if (trimmed === 'true') return true;
+    if (trimmed === 'false') return false;
+  } else if (type === 'number' && trimmed !== '') {
+    const parsed = Number(trimmed);
+    if (Number.isFinite(parsed)) return parsed;
+  }
+  return value;
+}
+
+/**
+ * Coerce stringified values of known fields (e.g. "true" -> true, "0.7" -> 0.7).
+ * Unknown fields and values that don't parse are left untouched.
+ */
+function coerceFieldValues(entity, values) {
+  const fieldTypes = FIELD_TYPES[entity] || {};
+  const coerced = { ...values };
+  for (const [field, type] of Object.entries(fieldTypes)) {
+    if (field in coerced) {
+      coerced[field] = coerceFieldValue(type, coerced[field]);
+    }
+  }
+  return coerced;
+}
+
+/**
+ * Reject values of known fields that still have the wrong type after coercion.
+ * null/undefined (field removal) and unknown fields pass through for forward compatibility.
+ */
+function assertFieldTypes(entity, values) {
+  const fieldTypes = FIELD_TYPES[entity] || {};
+  for (const [field, type] of Object.entries(fieldTypes)) {
+    const value = values[field];
+    if (value === null || value === undefined) continue;
+    if (typeof value !== type || (type === 'number' && !Number.isFinite(value))) {
+      throw createConfigError(
+        CONFIG_ERROR.INVALID_FIELD_TYPE,
+        `Field "${field}" must be a ${type}, got ${JSON.stringify(value)}`,
+        { field, expected: type }
+      );
+    }
+  }
+}
+
+/**
+ * Get the canonical on-disk file for an agent/command, suitable for an OS "reveal" action.
+ * Prefers the .md file (project > user), then the config file defining it in json.
+ */
+function getRevealTarget(entity, name, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  if (mdInfo.path && fs.existsSync(mdInfo.path)) {
+    return fs.realpathSync(mdInfo.path);
+  }
+
+  const jsonSource = getJsonEntrySource(readConfigLayers(workingDirectory), descriptor.sectionKey, name);
+  if (jsonSource.exists && jsonSource.path && fs.existsSync(jsonSource.path)) {
+    return fs.realpathSync(jsonSource.path);
+  }
+
+  throw new Error(`${descriptor.label} ${name} has no file on disk (built-in)`);
+}
+
+// ============== PROMPT STORAGE ==============
+
+/**
+ * Validate a new prompt file location (default prompts/<agent>.md) relative to the directory of
+ * configPath, the config file that will hold the reference.
+ * Returns: { reference: '{file:./...}', status } where status.resolvedPath is the file to create
+ */
+function getNewPromptFileTarget(agentName, relativePath, configPath) {
+  const target = (relativePath || `prompts/${agentName}.md`).replace(/^\.\//, '');
+  if (path.isAbsolute(target)) {
+    throw new Error(`Prompt file path must be relative to the config directory: ${target}`);
+  }
+  const baseDir = getPromptFileBaseDir(configPath);
+  const resolvedPath = path.resolve(baseDir, target);
+  if (resolvedPath === baseDir || !isWithinDirectory(resolvedPath, baseDir) || target.split(/[\\/]/).includes('..')) {
+    throw new Error(`Prompt file path must stay inside the config directory: ${target}`);
+  }
+  if (fs.existsSync(resolvedPath)) {
+    throw new Error(`Prompt file already exists: ${resolvedPath}`);
+  }
+  const reference = `{file:./${path.relative(baseDir, resolvedPath).split(path.sep).join('/')}}`;
+  return { reference, status: { resolvedPath } };
+}
+
+/**
+ * Move one agent's inline prompt (json string, else md body) to a file next to the config file that holds
+ * the agent's json entry, and reference it with {file:...} from that entry. An md body is cleared once moved,
+ * since {file:...} is only resolved in json. Defaults to prompts/<agent>.md. All-or-nothing.
+ * Returns the prompt file path; no-op (returning the current target) if the prompt already is a file reference.
+ */
+function externalizeAgentPrompt(agentName, relativePath, workingDirectory) {
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const jsonPrompt = isPlainObject(jsonSource.section) ? jsonSource.section.prompt : undefined;
+  if (isPromptFileReference(jsonPrompt)) {
+    return resolvePromptFilePath(jsonPrompt, getPromptFileBaseDir(jsonSource.path));
+  }
+
+  const mdInfo = getAgentScope(agentName, workingDirectory);
+  const mdData = mdInfo.path ? parseMdFile(mdInfo.path) : null;
+  const prompt = typeof jsonPrompt === 'string' ? jsonPrompt : mdData?.body;
+  if (!prompt) {
+    throw new Error(`Agent ${agentName} has no inline prompt to externalize`);
+  }
+
+  // Checked before the prompt file is written so a locked agent doesn't leave an orphaned file behind
+  assertNotLocked(ENTITY_KIND.AGENT, agentName, workingDirectory);
+  const jsonTarget = jsonSource.exists
+    ? { config: jsonSource.config, path: jsonSource.path }
+    : getJsonWriteTarget(layers, mdInfo.scope);
+  const configPath = jsonTarget.path || getUserConfigFile();
+  const { reference, status } = getNewPromptFileTarget(agentName, relativePath, configPath);
+
+  const snapshot = snapshotFiles([status.resolvedPath, configPath, mdInfo.path].filter(Boolean));
+  try {
+    writePromptFile(status.resolvedPath, prompt);
+    const config = jsonTarget.config || {};
+    if (!isPlainObject(config.agent)) config.agent = {};
+    if (!isPlainObject(config.agent[agentName])) config.agent[agentName] = {};
+    config.agent[agentName].prompt = reference;
+    writeConfig(config, configPath);
+    if (typeof jsonPrompt !== 'string') {
+      writeMdFile(mdInfo.path, mdData.frontmatter, '');
+    }
+  } catch (error) {
+    snapshot.restore('prompt externalization');
+    throw error;
+  }
+
+  console.log(`Externalized prompt for agent ${agentName}: ${status.resolvedPath}`);
+  return status.resolvedPath;
+}
+
+/**
+ * Inverse of externalizeAgentPrompt: read the agent's {file:...} prompt and store it inline again,
+ * as the md body when the agent has an .md file (dropping the json reference), else as a json string.
+ * With deleteFile, the prompt file is removed unless another agent or command still references it.
+ */
+function inlineAgentPrompt(agentName, deleteFile, workingDirectory) {
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const reference = isPlainObject(jsonSource.section) ? jsonSource.section.prompt : undefined;
+  if (!isPromptFileReference(reference)) {
+    throw new Error(`Agent ${agentName} has no {file:...} prompt reference`);
+  }
+
+  const promptFilePath = resolvePromptFilePath(reference, getPromptFileBaseDir(jsonSource.path));
+  if (!promptFilePath || !fs.existsSync(promptFilePath)) {
+    throw new Error(`Prompt file not found for agent ${agentName}: ${reference}`);
+  }
+  const prompt = stripBom(readTextFile(promptFilePath));
+
+  const mdInfo = getAgentScope(agentName, workingDirectory);
+  if (mdInfo.path) {
+    const mdData = parseMdFile(mdInfo.path);
+    writeMdFile(mdInfo.path, mdData.frontmatter, prompt);
+    delete jsonSource.section.prompt;
+    if (Object.keys(jsonSource.section).length === 0) {
+      delete jsonSource.config.agent[agentName];
+    }
+  } else {
+    jsonSource.section.prompt = prompt;
+  }
+  writeConfig(jsonSource.config, jsonSource.path);
+
+  if (deleteFile) {
+    const mergedConfig = readConfigLayers(workingDirectory).mergedConfig;
+    const stillReferenced = [['agent', 'prompt'], ['command', 'template']].some(([sectionKey, field]) => (
+      Object.values(isPlainObject(mergedConfig[sectionKey]) ? mergedConfig[sectionKey] : {})
+        .some((entry) => isPlainObject(entry) && resolvePromptFilePath(entry[field]) === promptFilePath)
+    ));
+    if (stillReferenced) {
+      console.warn(`Keeping prompt file ${promptFilePath}: still referenced elsewhere`);
+    } else {
+      fs.unlinkSync(promptFilePath);
+      console.log(`Deleted prompt file: ${promptFilePath}`);
+    }
+  }
+
+  console.log(`Inlined prompt for agent ${agentName}`);
+}
+
+/**
+ * Collect every file pulled in via {include:...} from agent/command .md bodies (shadowed ones included),
+ * inline json prompts/templates, and the given prompt files - following includes transitively.
+ * Unreadable files are skipped; an include that cannot be read still counts as included.
+ */
+function collectIncludedPromptFiles(workingDirectory, layers, promptFiles) {
+  const sources = [];
+  const walkMd = (dir) => {
+    if (!fs.existsSync(dir)) return;
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+      const entryPath = path.join(dir, entry.name);
+      if (entry.isDirectory()) walkMd(entryPath);
+      else if (entry.isFile() && entry.name.endsWith('.md')) sources.push({ filePath: entryPath });
+    }
+  };
+  for (const { dir } of [...getAgentDirs(workingDirectory), ...getCommandDirs(workingDirectory)]) {
+    walkMd(dir);
+  }
+  for (const layer of layers) {
+    for (const [sectionKey, field] of [['agent', 'prompt'], ['command', 'template']]) {
+      const section = isPlainObject(layer.config?.[sectionKey]) ? layer.config[sectionKey] : {};
+      for (const entry of Object.values(section)) {
+        if (isPlainObject(entry) && typeof entry[field] === 'string' && !isPromptFileReference(entry[field])) {
+          sources.push({ content: entry[field] });
+        }
+      }
+    }
+  }
+  for (const filePath of promptFiles) {
+    sources.push({ filePath });
+  }
+
+  const included = new Set();
+  const scanned = new Set();
+  while (sources.length > 0) {
+    const source = sources.pop();
+    let content = source.content;
+    if (source.filePath) {
+      if (scanned.has(source.filePath)) continue;
+      scanned.add(source.filePath);
+      try {
+        content = readTextFileLossy(source.filePath).content;
+      } catch {
+        continue;
+      }
+    }
+    for (const line of content.split(/\r?\n/)) {
+      const match = line.match(PROMPT_INCLUDE_PATTERN);
+      const includePath = match ? resolveConfigRelativePath(match[1].trim()) : null;
+      if (!includePath) continue;
+      const resolved = path.resolve(includePath);
+      included.add(resolved);
+      sources.push({ filePath: resolved });
+    }
+  }
+  return included;
+}
+
+/**
+ * Consolidate byte-identical prompt files referenced via {file:...} from agent prompts and command templates.
+ * For each set of identical files the most-referenced one (then the shortest path) is kept, every reference
+ * to the others is rewritten to it, and the others are deleted. Files outside the config directory are never touched,
+ * and a duplicate that any prompt or .md body pulls in with {include:...} is kept (reported under keptIncluded).
+ * Returns: { groups: [{ canonical, duplicates }], rewrittenReferences, removedFiles, keptIncluded, bytesSaved }
+ */
+function dedupPromptFiles(workingDirectory) {
+  const layers = getJsonLayerList(readConfigLayers(workingDirectory));
+  const references = [];
+  for (const layer of layers) {
+    for (const [sectionKey, field] of [['agent', 'prompt'], ['command', 'template']]) {
+      const section = isPlainObject(layer.config?.[sectionKey]) ? layer.config[sectionKey] : {};
+      for (const [name, entry] of Object.entries(section)) {
+        if (!isPlainObject(entry) || !isPromptFileReference(entry[field])) continue;
+        const status = getPromptReferenceStatus(entry[field]);
+        if (!status.safe || !status.exists || !fs.statSync(status.resolvedPath).isFile()) continue;
+        references.push({ layer, entry, field, name, filePath: status.resolvedPath });
+      }
+    }
+  }
+
+  const filesByHash = new Map();
+  const hashByFile = new Map();
+  for (const { filePath } of references) {
+    if (hashByFile.has(filePath)) continue;
+    const hash = crypto.createHash('sha256').update(fs.readFileSync(filePath)).digest('hex');
+    hashByFile.set(filePath, hash);
+    if (!filesByHash.has(hash)) filesByHash.set(hash, []);
+    filesByHash.get(hash).push(filePath);
+  }
+
+  const includedFiles = collectIncludedPromptFiles(workingDirectory, layers, hashByFile.keys());
+  const report = { groups: [], rewrittenReferences: 0, removedFiles: [], keptIncluded: [], bytesSaved: 0 };
+  const touchedLayers = new Set();
+  for (const files of filesByHash.values()) {
+    if (files.length < 2) continue;
+    const referenceCount = (filePath) => references.filter((reference) => reference.filePath === filePath).length;
+    const [canonical, ...duplicates] = [...files].sort((a, b) => (
+      referenceCount(b) - referenceCount(a) || a.length - b.length || a.localeCompare(b)
+    ));
+    const canonicalReference = normalizePromptReference(`{file:${canonical}}`);
+
+    for (const reference of references) {
+      if (!duplicates.includes(reference.filePath)) continue;
+      reference.entry[reference.field] = canonicalReference;
+      touchedLayers.add(reference.layer);
+      report.rewrittenReferences += 1;
+    }
+    report.groups.push({ canonical, duplicates });
+  }
+
+  // References are rewritten before any duplicate is deleted, so a failed write leaves every file in place
+  for (const layer of touchedLayers) {
+    writeConfig(layer.config, layer.path);
+  }
+  for (const { duplicates } of report.groups) {
+    for (const filePath of duplicates) {
+      if (includedFiles.has(path.resolve(filePath))) {
+        report.keptIncluded.push(filePath);
+        continue;
+      }
+      report.bytesSaved += fs.statSync(filePath).size;
+      fs.unlinkSync(filePath);
+      report.removedFiles.push(filePath);
+    }
+  }
+
+  if (report.removedFiles.length > 0) {
+    console.log(`Deduplicated ${report.removedFiles.length} prompt file(s), saved ${report.bytesSaved} bytes`);
+  }
+  return report;
+}
+
+// ============== ENTITY WATCH ==============
+
+/**
+ * Watch a single agent/command: its winning .md file (or the config file defining it in json)
+ * plus the {file:...} prompt file it references.
+ * Each file's parent directory is watched and events are filtered by name, so editors that save atomically
+ * (write a temp file, rename it over the original) keep being seen. For a json entity onChange only fires when
+ * that entity's own section changes, not for edits elsewhere in the config file.
+ * onChange receives { type: 'modified'|'deleted'|'prompt-file-changed', path }, or
+ * { type: 'error', path, error } when a watcher fails (that file is no longer watched).
+ * Returns a handle; call handle.close() to stop watching.
+ */
+function watchEntity(entity, name, workingDirectory, onChange) {
+  const descriptor = getEntityDescriptor(entity);
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  const jsonSource = getJsonEntrySource(readConfigLayers(workingDirectory), descriptor.sectionKey, name);
+
+  const entityPath = mdInfo.path || (jsonSource.exists ? jsonSource.path : null);
+  if (!entityPath || !fs.existsSync(entityPath)) {
+    throw new Error(`${descriptor.label} ${name} has no file on disk to watch`);
+  }
+
+  // State snapshots: null means gone; equal snapshots mean nothing relevant changed
+  const readFileState = (filePath) => () => (
+    fs.existsSync(filePath) ? crypto.createHash('sha256').update(fs.readFileSync(filePath)).digest('hex') : null
+  );
+  const readSectionState = (filePath) => () => {
+    const section = readConfigFile(filePath)?.[descriptor.sectionKey]?.[name];
+    return section === undefined ? null : JSON.stringify(section);
+  };
+
+  const watchers = [];
+  const watchFile = (filePath, changeType, readState) => {
+    const baseName = path.basename(filePath);
+    let lastState = readState();
+    const watcher = fs.watch(path.dirname(filePath), (_eventType, changedName) => {
+      if (changedName && changedName.toString() !== baseName) return;
+      let state;
+      try {
+        state = readState();
+      } catch {
+        // Caught mid-write (e.g. truncated json); the write's next event reads it again
+        return;
+      }
+      if (state === lastState) return;
+      lastState = state;
+      const deleted = state === null && changeType === 'modified';
+      onChange({ type: deleted ? 'deleted' : changeType, path: filePath });
+    });
+    watcher.on('error', (error) => {
+      console.warn(`Stopped watching ${filePath}:`, error);
+      watcher.close();
+      watchers.splice(watchers.indexOf(watcher), 1);
+      onChange({ type: 'error', path: filePath, error });
+    });
+    watchers.push(watcher);
+  };
+
+  if (mdInfo.path) {
+    watchFile(mdInfo.path, 'modified', readFileState(mdInfo.path));
+  } else {
+    watchFile(entityPath, 'modified', readSectionState(entityPath));
+  }
+
+  const promptReference = isPlainObject(jsonSource.section) ? jsonSource.section[descriptor.bodyField] : undefined;
+  const promptFilePath = resolvePromptFilePath(promptReference);
+  if (promptFilePath && fs.existsSync(promptFilePath)) {
+    watchFile(promptFilePath, 'prompt-file-changed', readFileState(promptFilePath));
+  }
+
+  return {
+    close() {
+      for (const watcher of watchers.splice(0)) {
+        watcher.close();
+      }
+    }
+  };
+}
+
+// ============== REQUIRED FIELDS ==============
+
+/**
+ * Set the fields every agent/command must define, e.g. { agent: ['description', 'model'] }.
+ * The default policy is empty and requires nothing.
+ */
+function setRequiredFieldsPolicy(policy = {}) {
+  requiredFieldsPolicy = {
+    agent: Array.isArray(policy.agent) ? [...policy.agent] : [],
+    command: Array.isArray(policy.command) ? [...policy.command] : []
+  };
+}
+
+function getRequiredFieldsPolicy() {
+  return { agent: [...requiredFieldsPolicy.agent], command: [...requiredFieldsPolicy.command] };
+}
+
+/**
+ * Merge an entity's md frontmatter/body with its merged json section (json wins), without dereferencing files
+ */
+function getMergedEntityConfig(entity, name, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  const mdData = mdInfo.path ? parseMdFile(mdInfo.path) : null;
+  const mergedConfig = readConfig(workingDirectory);
+  const jsonSection = mergedConfig?.[descriptor.sectionKey]?.[name];
+
+  return {
+    ...(mdData?.frontmatter || {}),
+    ...(mdData?.body ? { [descriptor.bodyField]: mdData.body } : {}),
+    ...(isPlainObject(jsonSection) ? jsonSection : {})
+  };
+}
+
+/**
+ * Throw MISSING_REQUIRED_FIELD if the config lacks a field required by the active policy
+ */
+function assertRequiredFields(entity, config) {
+  for (const field of requiredFieldsPolicy[entity] || []) {
+    const value = config[field];
+    if (value == null || (typeof value === 'string' && !value.trim())) {
+      throw createConfigError(
+        CONFIG_ERROR.MISSING_REQUIRED_FIELD,
+        `Missing required ${entity} field: ${field}`,
+        { field }
+      );
+    }
+  }
+}
+
+/**
+ * Check the config an entity would have after applying updates (null removes a field)
+ */
+function assertRequiredFieldsAfterUpdate(entity, name, updates, workingDirectory) {
+  if ((requiredFieldsPolicy[entity] || []).length === 0) {
+    return;
+  }
+  const prospective = { ...getMergedEntityConfig(entity, name, workingDirectory), ...updates };
+  for (const [field, value] of Object.entries(updates)) {
+    if (value === null) delete prospective[field];
+  }
+  assertRequiredFields(entity, prospective);
+}
+
+// ============== FIELD PLACEMENT ==============
+
+/**
+ * Lookup helpers shared by agents and commands
+ */
+function getEntityDescriptor(entity) {
+  if (entity === ENTITY_KIND.AGENT) {
+    return {
+      label: 'Agent',
+      sectionKey: 'agent',
+      bodyField: 'prompt',
+      getScope: getAgentScope,
+      getWritePath: getAgentWritePath
+    };
+  }
+  if (entity === ENTITY_KIND.COMMAND) {
+    return {
+      label: 'Command',
+      sectionKey: 'command',
+      bodyField: 'template',
+      getScope: getCommandScope,
+      getWritePath: getCommandWritePath
+    };
+  }
+  throw new Error(`Unknown entity kind: ${entity}`);
+}
+
+/**
+ * Which file OpenCode loads an agent or command from, using the same precedence as writes:
+ * project .md > user .md > the highest-precedence opencode.json entry > built-in.
+ * When an .md file wins, `jsonOverridePath` names the json file whose entry still overrides its fields.
+ * Returns: { source: 'md'|'json'|'builtin', scope, path, jsonOverridePath }
+ */
+function resolveLoadSource(entity, name, workingDirectory) {
+  const descriptor = getEntityDescriptor(entity);
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, descriptor.sectionKey, name);
+  const jsonScope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+
+  const mdInfo = descriptor.getScope(name, workingDirectory);
+  if (mdInfo.path) {
+    return {
+      source: STORAGE_TARGET.MD,
+      scope: mdInfo.scope,
+      path: mdInfo.path,
+      jsonOverridePath: jsonSource.exists ? jsonSource.path : null
+    };
+  }
+  if (jsonSource.exists) {
+    return { source: STORAGE_TARGET.JSON, scope: jsonScope, path: jsonSource.path, jsonOverridePath: null };
+  }
+
+  const builtins = entity === ENTITY_KIND.AGENT ? BUILTIN_AGENT_NAMES : BUILTIN_COMMAND_NAMES;
+  if (builtins.includes(name)) {
+    return { source: 'builtin', scope: null, path: null, jsonOverridePath: null };
+  }
+  throw createConfigError(CONFIG_ERROR.NOT_FOUND, `${descriptor.label} "${name}" not found`, { entity, name });
+}
+
+/**
+ * Explicitly move a field between the entity's .md file and opencode.json.
+ * The effective value (json takes precedence over md) is written to the target and removed from the other source.
+ * Moving a {file:...} body to md inlines the referenced file (which is kept). All-or-nothing.
+ * @param {object} options - { ifMatch } etag precondition
+ */
+function relocateField(entity, name, field, target, workingDirectory, options = {}) {
+  if (target !== STORAGE_TARGET.MD && target !== STORAGE_TARGET.JSON) {
+    throw new Error(`Unknown storage target: ${target}`);
+  }
+
+  assertIfMatch(entity, name, workingDirectory, options.ifMatch);
+  const descriptor = getEntityDescriptor(entity);
+  const { sectionKey, bodyField } = descriptor;
+  const isBodyField = field === bodyField;
+
+  const existingMd = descriptor.getScope(name, workingDirectory);
+  const mdData = existingMd.path ? parseMdFile(existingMd.path) : null;
+  const inMd = isBodyField ? Boolean(mdData?.body) : mdData?.frontmatter?.[field] !== undefined;
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, sectionKey, name);
+  const inJson = jsonSource.section?.[field] !== undefined;
+
+  if (!inMd && !inJson) {
+    throw new Error(`Field "${field}" not found for ${entity} ${name}`);
+  }
+
+  let value;
+  if (inJson) {
+    value = jsonSource.section[field];
+  } else {
+    value = isBodyField ? mdData.body : mdData.frontmatter[field];
+  }
+
+  const jsonTarget = jsonSource.exists
+    ? { config: jsonSource.config, path: jsonSource.path }
+    : getJsonWriteTarget(layers, existingMd.scope || AGENT_SCOPE.USER);
+  const config = jsonTarget.config || {};
+  const configPath = jsonTarget.path || getUserConfigFile();
+
+  if (target === STORAGE_TARGET.MD) {
+    const mdScope = jsonSource.path && jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+    const mdTarget = descriptor.getWritePath(name, workingDirectory, mdScope);
+    const targetData = mdData || { frontmatter: {}, body: '' };
+    if (isBodyField) {
+      // {file:...} is only resolved in json, so the md body gets the file's content
+      if (inJson && isPromptFileReference(value)) {
+        const promptFilePath = resolvePromptFilePath(value, getPromptFileBaseDir(jsonSource.path));
+        if (!promptFilePath || !fs.existsSync(promptFilePath)) {
+          throw new Error(`Prompt file not found for ${entity} ${name}: ${value}`);
+        }
+        value = stripBom(readTextFile(promptFilePath));
+      }
+      targetData.body = typeof value === 'string' ? value : String(value ?? '');
+    } else {
+      targetData.frontmatter[field] = value;
+    }
+
+    const snapshot = snapshotFiles([mdTarget.path, ...(inJson ? [configPath] : [])]);
+    try {
+      fs.mkdirSync(path.dirname(mdTarget.path), { recursive: true });
+      writeMdFile(mdTarget.path, targetData.frontmatter, targetData.body);
+
+      if (inJson) {
+        delete config[sectionKey][name][field];
+        if (Object.keys(config[sectionKey][name]).length === 0) {
+          delete config[sectionKey][name];
+        }
+        if (Object.keys(config[sectionKey]).length === 0) {
+          delete config[sectionKey];
+        }
+        writeConfig(config, configPath);
+      }
+    } catch (error) {
+      snapshot.restore('field relocate');
+      throw error;
+    }
+  } else {
+    if (!config[sectionKey]) config[sectionKey] = {};
+    if (!config[sectionKey][name]) config[sectionKey][name] = {};
+    config[sectionKey][name][field] = value;
+
+    const snapshot = snapshotFiles([configPath, ...(inMd ? [existingMd.path] : [])]);
+    try {
+      writeConfig(config, configPath);
+
+      if (inMd) {
+        if (isBodyField) {
+          mdData.body = '';
+        } else {
+          delete mdData.frontmatter[field];
+        }
+        writeMdFile(existingMd.path, mdData.frontmatter, mdData.body);
+      }
+    } catch (error) {
+      snapshot.restore('field relocate');
+      throw error;
+    }
+  }
+
+  console.log(`Relocated ${entity} field: ${name}.${field} -> ${target}`);
+}
+
+/**
+ * Store an agent entirely in its .md file or entirely in opencode.json.
+ * The effective fields (json over md) are written to the target and the other source is removed.
+ * Moving to md inlines a {file:...} prompt as the body (the prompt file itself is kept);
+ * moving to json keeps the md body as an inline prompt string.
+ */
+function setAgentStorage(agentName, target, workingDirectory) {
+  if (target !== STORAGE_TARGET.MD && target !== STORAGE_TARGET.JSON) {
+    throw new Error(`Unknown storage target: ${target}`);
+  }
+
+  const existingMd = getAgentScope(agentName, workingDirectory);
+  const mdData = existingMd.path ? parseMdFile(existingMd.path) : null;
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+  const jsonSection = isPlainObject(jsonSource.section) ? jsonSource.section : null;
+
+  if (!mdData && !jsonSection) {
+    throw new Error(`Agent "${agentName}" has no .md file or opencode.json entry`);
+  }
+
+  const merged = {
+    ...(mdData?.frontmatter || {}),
+    ...(mdData?.body ? { prompt: mdData.body } : {}),
+    ...(jsonSection || {})
+  };
+
+  if (target === STORAGE_TARGET.MD) {
+    const { prompt, ...frontmatter } = merged;
+    let body = typeof prompt === 'string' ? prompt : '';
+    if (isPromptFileReference(prompt)) {
+      const promptFilePath = resolvePromptFilePath(prompt, getPromptFileBaseDir(jsonSource.path));
+      if (!promptFilePath || !fs.existsSync(promptFilePath)) {
+        throw new Error(`Prompt file not found for agent ${agentName}: ${prompt}`);
+      }
+      body = stripBom(readTextFile(promptFilePath));
+    }
+
+    let mdPath = existingMd.path;
+    if (!mdPath) {
+      const mdScope = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+      mdPath = getAgentWritePath(agentName, workingDirectory, mdScope).path;
+      fs.mkdirSync(path.dirname(mdPath), { recursive: true });
+    }
+    writeMdFile(mdPath, frontmatter, body);
+
+    if (jsonSection) {
+      delete jsonSource.config.agent[agentName];
+      if (Object.keys(jsonSource.config.agent).length === 0) {
+        delete jsonSource.config.agent;
+      }
+      writeConfig(jsonSource.config, jsonSource.path);
+    }
+  } else {
+    const jsonTarget = jsonSource.exists
+      ? { config: jsonSource.config, path: jsonSource.path }
+      : getJsonWriteTarget(layers, existingMd.scope || AGENT_SCOPE.USER);
+    const config = jsonTarget.config || {};
+    if (!isPlainObject(config.agent)) config.agent = {};
+    config.agent[agentName] = merged;
+    writeConfig(config, jsonTarget.path || getUserConfigFile());
+
+    if (existingMd.path) {
+      removeEntityFile(existingMd.path, null);
+      console.log(`Deleted agent .md file: ${existingMd.path}`);
+    }
+  }
+
+  console.log(`Stored agent ${agentName} entirely in ${target}`);
+}
+
+// ============== DISABLE FLAGS ==============
+
+function isDisableOnlyEntry(entry) {
+  return isPlainObject(entry) && Object.keys(entry).length === 1 && entry.disable !== undefined;
+}
+
+/**
+ * Find json agent/command entries that only hold a `disable` flag for a name that is neither a known built-in
+ * nor backed by an .md file.
+ * These are typically left behind when OpenCode renames or removes a built-in.
+ * Returns: [{ entity, name, path }]
+ */
+function findOrphanDisables(workingDirectory, builtins = {}) {
+  const builtinNames = {
+    agent: new Set(builtins.agents || BUILTIN_AGENT_NAMES),
+    command: new Set(builtins.commands || BUILTIN_COMMAND_NAMES)
+  };
+  const layers = readConfigLayers(workingDirectory);
+  const { userPath, projectPath, customPath } = layers.paths;
+  const layerEntries = [
+    { config: layers.userConfig, path: userPath },
+    { config: layers.projectConfig, path: projectPath },
+    { config: layers.customConfig, path: customPath }
+  ];
+
+  const orphans = [];
+  const seen = new Set();
+  for (const { config, path: configPath } of layerEntries) {
+    if (!configPath || seen.has(configPath)) continue;
+    seen.add(configPath);
+
+    for (const sectionKey of [ENTITY_KIND.AGENT, ENTITY_KIND.COMMAND]) {
+      const section = isPlainObject(config?.[sectionKey]) ? config[sectionKey] : {};
+      for (const [name, entry] of Object.entries(section)) {
+        if (!isDisableOnlyEntry(entry) || builtinNames[sectionKey].has(name)) continue;
+        // A disable flag for an agent/command that still has an .md file is intentional, not orphaned
+        if (getEntityDescriptor(sectionKey).getScope(name, workingDirectory).path) continue;
+        orphans.push({ entity: sectionKey, name, path: configPath });
+      }
+    }
+  }
+
+  return orphans;
+}
+
+/**
+ * Remove orphaned disable-only entries reported by findOrphanDisables
+ * Returns the removed entries.
+ */
+function removeOrphanDisables(workingDirectory, builtins = {}) {
+  const orphans = findOrphanDisables(workingDirectory, builtins);
+  const byPath = new Map();
+  for (const orphan of orphans) {
+    if (!byPath.has(orphan.path)) byPath.set(orphan.path, []);
+    byPath.get(orphan.path).push(orphan);
+  }
+
+  for (const [configPath, entries] of byPath) {
+    const config = readConfigFile(configPath);
+    for (const { entity, name } of entries) {
+      delete config[entity][name];
+      if (Object.keys(config[entity]).length === 0) {
+        delete config[entity];
+      }
+    }
+    writeConfig(config, configPath);
+    console.log(`Removed ${entries.length} orphaned disable entries from ${configPath}`);
+  }
+
+  return orphans;
+}
+
+/**
+ * Enable or disable an agent without deleting it.
+ * Disabling merges `disable: true` into the agent's json entry (preserving other fields).
+ * Enabling removes `disable` from every json layer and the md frontmatter, dropping json entries left empty.
+ */
+function setAgentEnabled(agentName, enabled, workingDirectory) {
+  const layers = readConfigLayers(workingDirectory);
+
+  if (!enabled) {
+    const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
+    const jsonTarget = jsonSource.exists
+      ? { config: jsonSource.config, path: jsonSource.path }
+      : getJsonWriteTarget(layers, workingDirectory ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER);
+    const config = jsonTarget.config || {};
+    if (!config.agent) config.agent = {};
+    config.agent[agentName] = { ...(isPlainObject(config.agent[agentName]) ? config.agent[agentName] : {}), disable: true };
+    writeConfig(config, jsonTarget.path || getUserConfigFile());
+    console.log(`Disabled agent: ${agentName}`);
+    return;
+  }
+
+  const jsonLayers = getJsonLayerList(layers);
+
+  for (const layer of jsonLayers) {
+    const entry = layer.config?.agent?.[agentName];
+    if (!isPlainObject(entry) || entry.disable === undefined) continue;
+
+    delete entry.disable;
+    if (Object.keys(entry).length === 0) {
+      delete layer.config.agent[agentName];
+    }
+    if (Object.keys(layer.config.agent).length === 0) {
+      delete layer.config.agent;
+    }
+    writeConfig(layer.config, layer.path);
+  }
+
+  const mdInfo = getAgentScope(agentName, workingDirectory);
+  if (mdInfo.path) {
+    const mdData = parseMdFile(mdInfo.path);
+    if (mdData.frontmatter.disable !== undefined) {
+      delete mdData.frontmatter.disable;
+      writeMdFile(mdInfo.path, mdData.frontmatter, mdData.body);
+    }
+  }
+
+  console.log(`Enabled agent: ${agentName}`);
+}
+
+/**
+ * Enable or disable a list of built-in agents with one write per config file.
+ * Disabling adds `disable: true` in the user opencode.json. Enabling removes `disable` from every json layer
+ * (user, project, custom), as setAgentEnabled does, so a disable elsewhere can't keep the agent off.
+ * Defaults to the user-facing built-ins; any names may be passed (e.g. built-ins added by a newer OpenCode).
+ * Existing override fields on those entries are kept; only `disable` is added or removed.
+ * Returns the names that actually changed.
+ */
+function setAllBuiltinsEnabled(enabled, builtinNames = USER_FACING_BUILTIN_AGENT_NAMES, workingDirectory) {
+  const layers = enabled
+    ? getJsonLayerList(readConfigLayers(workingDirectory))
+    : [{ path: getUserConfigFile(), config: readConfigFile(getUserConfigFile()) }];
+  const changed = new Set();
+
+  for (const layer of layers) {
+    const config = layer.config || {};
+    const agents = isPlainObject(config.agent) ? config.agent : {};
+    let layerChanged = false;
+
+    for (const name of builtinNames) {
+      const entry = isPlainObject(agents[name]) ? agents[name] : {};
+      if (enabled) {
+        if (entry.disable === undefined) continue;
+        delete entry.disable;
+        if (Object.keys(entry).length === 0) {
+          delete agents[name];
+        }
+      } else {
+        if (entry.disable === true) continue;
+        agents[name] = { ...entry, disable: true };
+      }
+      changed.add(name);
+      layerChanged = true;
+    }
+
+    if (layerChanged) {
+      if (Object.keys(agents).length > 0) {
+        config.agent = agents;
+      } else {
+        delete config.agent;
+      }
+      writeConfig(config, layer.path);
+    }
+  }
+
+  const changedNames = builtinNames.filter((name) => changed.has(name));
+  if (changedNames.length > 0) {
+    console.log(`${enabled ? 'Enabled' : 'Disabled'} built-in agents: ${changedNames.join(', ')}`);
+  }
+  return changedNames;
+}
+
+/**
+ * Canonicalize agent disable flags (idempotent):
+ * - `disable: false` is removed from md, and from json unless it overrides a lower-precedence disable
+ * - an md `disable: true` is removed when json of the same or broader scope already disables the agent
+ *   (user json covers every project; project json only covers that project's md)
+ * Returns: { changes: [{ name, path, action }] }
+ */
+function normalizeDisables(workingDirectory) {
+  const layers = readConfigLayers(workingDirectory);
+  const jsonLayers = getJsonLayerList(layers);
+
+  const changes = [];
+  const mdAgents = Array.from(listMdEntities(getAgentDirs(workingDirectory)).values())
+    .map((agent) => ({ ...agent, frontmatter: readListingFrontmatter(agent.path).frontmatter }));
+  const mdDisabled = new Set(mdAgents.filter((agent) => agent.frontmatter.disable === true).map((agent) => agent.name));
+  // name -> scopes whose json disables the agent (layers are visited lowest precedence first)
+  const jsonDisables = new Map();
+
+  for (const layer of jsonLayers) {
+    const section = isPlainObject(layer.config?.agent) ? layer.config.agent : null;
+    if (!section) continue;
+
+    let modified = false;
+    for (const [name, entry] of Object.entries(section)) {
+      if (!isPlainObject(entry)) continue;
+
+      if (entry.disable === true) {
+        if (!jsonDisables.has(name)) jsonDisables.set(name, new Set());
+        jsonDisables.get(name).add(layer.scope);
+      } else if (entry.disable === false && !jsonDisables.has(name) && !mdDisabled.has(name)) {
+        // Only redundant when nothing below this layer disables the agent
+        delete entry.disable;
+        if (Object.keys(entry).length === 0) {
+          delete section[name];
+        }
+        changes.push({ name, path: layer.path, action: 'removed redundant disable: false' });
+        modified = true;
+      }
+    }
+
+    if (modified) {
+      if (Object.keys(section).length === 0) {
+        delete layer.config.agent;
+      }
+      writeConfig(layer.config, layer.path);
+    }
+  }
+
+  for (const { name, scope, path: mdPath, frontmatter } of mdAgents) {
+    const disable = frontmatter.disable;
+    const disabledBy = jsonDisables.get(name) || new Set();
+    const coveredByJson = disabledBy.has(AGENT_SCOPE.USER) || (scope === AGENT_SCOPE.PROJECT && disabledBy.has(AGENT_SCOPE.PROJECT));
+
+    if (disable === false) {
+      changes.push({ name, path: mdPath, action: 'removed redundant disable: false' });
+    } else if (disable === true && coveredByJson) {
+      changes.push({ name, path: mdPath, action: 'removed duplicate disable: true (already disabled in json)' });
+    } else {
+      continue;
+    }
+    // The body is only needed once we actually rewrite the file
+    const mdData = parseMdFile(mdPath);
+    delete mdData.frontmatter.disable;
+    writeMdFile(mdPath, mdData.frontmatter, mdData.body);
+  }
+
+  return { changes };
+}
+
+// ============== SCHEMAS ==============
+
+// `x-storage` lists where a field may live; `x-md-body` marks the field stored as the .md body
+const AGENT_SCHEMA = {
+  $schema: 'http://json-schema.org/draft-07/schema#',
+  title: 'OpenCode agent',
+  type: 'object',
+  properties: {
+    description: { type: 'string', 'x-storage': ['md', 'json'] },
+    mode: { type: 'string', enum: ['primary', 'subagent', 'all'], 'x-storage': ['md', 'json'] },
+    model: { type: 'string', description: 'provider/model', 'x-storage': ['md', 'json'] },
+    temperature: { type: 'number', minimum: 0, maximum: 2, 'x-storage': ['md', 'json'] },
+    top_p: { type: 'number', minimum: 0, maximum: 1, 'x-storage': ['md', 'json'] },
+    maxSteps: { type: 'integer', minimum: 1, 'x-storage': ['md', 'json'] },
+    color: { type: 'string', 'x-storage': ['md', 'json'] },
+    disable: { type: 'boolean', 'x-storage': ['md', 'json'] },
+    prompt: {
+      type: 'string',
+      description: 'Prompt text, or a {file:path} reference in json',
+      'x-storage': ['md', 'json'],
+      'x-md-body': true
+    },
+    tools: {
+      type: 'object',
+      additionalProperties: { type: 'boolean' },
+      'x-storage': ['md', 'json']
+    },
+    permission: {
+      type: 'object',
+      additionalProperties: {
+        oneOf: [
+          { type: 'string', enum: ['allow', 'ask', 'deny'] },
+          { type: 'object', additionalProperties: { type: 'string', enum: ['allow', 'ask', 'deny'] } }
+        ]
+      },
+      'x-storage': ['md', 'json']
+    },
+    options: { type: 'object', 'x-storage': ['json'] }
+  },
+  additionalProperties: true
+};
+
+const COMMAND_SCHEMA = {
+  $schema: 'http://json-schema.org/draft-07/schema#',
+  title: 'OpenCode command',
+  type: 'object',
+  properties: {
+    template: {
+      type: 'string',
+      description: 'Command template; $ARGUMENTS and $1..$n are substituted',
+      'x-storage': ['md', 'json'],
+      'x-md-body': true
+    },
+    description: { type: 'string', 'x-storage': ['md', 'json'] },
+    'argument-hint': { type: 'string', 'x-storage': ['md', 'json'] },
+    agent: { type: 'string', 'x-storage': ['md', 'json'] },
+    model: { type: 'string', description: 'provider/model', 'x-storage': ['md', 'json'] },
+    subtask: { type: 'boolean', 'x-storage': ['md', 'json'] },
+    args: {
+      type: 'array',
+      description: 'Declared arguments, referenced as {{name}} in the template',
+      items: {
+        type: 'object',
+        properties: {
+          name: { type: 'string', minLength: 1 },
+          required: { type: 'boolean' },
+          hint: { type: 'string' },
+          default: {}
+        },
+        required: ['name']
+      },
+      'x-storage': ['md', 'json']
+    }
+  },
+  additionalProperties: true
+};
+
+/**
+ * JSON Schema for agent configs, for form generation and client-side validation
+ */
+function agentSchema() {
+  return structuredClone(AGENT_SCHEMA);
+}
+
+/**
+ * JSON Schema for command configs, for form generation and client-side validation
+ */
+function commandSchema() {
+  return structuredClone(COMMAND_SCHEMA);
+}
+
+// ============== AGENT ROSTER ==============
+
+/**
+ * Resolve the agents OpenCode would load: built-ins, user and project .md agents and json agents,
+ * with disables applied.
+ * Returns: [{ name, origin: 'builtin'|'user'|'project', builtIn, enabled, path }] sorted by name
+ */
+function resolveAgentRoster(workingDirectory, builtins = BUILTIN_AGENT_NAMES) {
+  const builtinNames = new Set(builtins);
+  const mdAgents = listMdEntities(getAgentDirs(workingDirectory));
+  const layers = readConfigLayers(workingDirectory);
+  const jsonAgents = isPlainObject(layers.mergedConfig.agent) ? layers.mergedConfig.agent : {};
+
+  const names = new Set([...builtinNames, ...mdAgents.keys(), ...Object.keys(jsonAgents)]);
+  const roster = [];
+
+  for (const name of names) {
+    const mdAgent = mdAgents.get(name);
+    const jsonEntry = isPlainObject(jsonAgents[name]) ? jsonAgents[name] : null;
+    const frontmatter = mdAgent ? readListingFrontmatter(mdAgent.path).frontmatter : {};
+
+    let origin = 'builtin';
+    let entryPath = null;
+    if (mdAgent) {
+      origin = mdAgent.scope;
+      entryPath = mdAgent.path;
+    } else if (jsonEntry && !builtinNames.has(name)) {
+      const jsonSource = getJsonEntrySource(layers, 'agent', name);
+      origin = jsonSource.path === layers.paths.projectPath ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER;
+      entryPath = jsonSource.path;
+    }
+
+    // Json is merged over md frontmatter, so a json disable flag wins
+    const disable = jsonEntry?.disable !== undefined ? jsonEntry.disable : frontmatter.disable;
+
+    roster.push({
+      name,
+      origin,
+      builtIn: builtinNames.has(name),
+      enabled: disable !== true,
+      path: entryPath
+    });
   }
 
-  // Then check user level
-  const userPath = getUserAgentPath(agentName);
-  if (fs.existsSync(userPath)) {
-    fs.unlinkSync(userPath);
-    console.log(`Deleted user-level agent .md file: ${userPath}`);
-    deleted = true;
+  return roster.sort((a, b) => a.name.localeCompare(b.name));
+}
+
+/**
+ * Compare two rosters from resolveAgentRoster and report how OpenCode's visible agents changed.
+ * Disabling a built-in removes it from view (reported as removed, re-enabling as added);
+ * for other agents enable/disable transitions are reported separately.
+ * Returns: { added, removed, enabled, disabled }
+ */
+function diffAgentRosters(before, after) {
+  const beforeByName = new Map(before.map((agent) => [agent.name, agent]));
+  const afterByName = new Map(after.map((agent) => [agent.name, agent]));
+  const delta = { added: [], removed: [], enabled: [], disabled: [] };
+
+  for (const [name, next] of afterByName) {
+    const previous = beforeByName.get(name);
+    if (!previous) {
+      if (next.enabled) delta.added.push(name);
+      continue;
+    }
+    if (previous.enabled === next.enabled) continue;
+
+    if (next.builtIn) {
+      delta[next.enabled ? 'added' : 'removed'].push(name);
+    } else {
+      delta[next.enabled ? 'enabled' : 'disabled'].push(name);
+    }
+  }
+
+  for (const [name, previous] of beforeByName) {
+    if (!afterByName.has(name) && previous.enabled) {
+      delta.removed.push(name);
+    }
   }
 
-  // Also check json config (highest precedence entry only)
+  return delta;
+}
+
+function getProviderSources(providerId, workingDirectory) {
   const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'agent', agentName);
-  if (jsonSource.exists && jsonSource.config && jsonSource.path) {
-    if (!jsonSource.config.agent) jsonSource.config.agent = {};
-    delete jsonSource.config.agent[agentName];
-    writeConfig(jsonSource.config, jsonSource.path);
-    console.log(`Removed agent from opencode.json: ${agentName}`);
-    deleted = true;
+  const { userConfig, projectConfig, customConfig, paths } = layers;
+
+  const customProviders = isPlainObject(customConfig?.provider) ? customConfig.provider : {};
+  const customProvidersAlias = isPlainObject(customConfig?.providers) ? customConfig.providers : {};
+  const projectProviders = isPlainObject(projectConfig?.provider) ? projectConfig.provider : {};
+  const projectProvidersAlias = isPlainObject(projectConfig?.providers) ? projectConfig.providers : {};
+  const userProviders = isPlainObject(userConfig?.provider) ? userConfig.provider : {};
+  const userProvidersAlias = isPlainObject(userConfig?.providers) ? userConfig.providers : {};
+
+  const customExists =
+    (customProviders && Object.prototype.hasOwnProperty.call(customProviders, providerId)) ||
+    (customProvidersAlias && Object.prototype.hasOwnProperty.call(customProvidersAlias, providerId));
+  const projectExists =
+    (projectProviders && Object.prototype.hasOwnProperty.call(projectProviders, providerId)) ||
+    (projectProvidersAlias && Object.prototype.hasOwnProperty.call(projectProvidersAlias, providerId));
+  const userExists =
+    (userProviders && Object.prototype.hasOwnProperty.call(userProviders, providerId)) ||
+    (userProvidersAlias && Object.prototype.hasOwnProperty.call(userProvidersAlias, providerId));
+
+  return {
+    sources: {
+      auth: { exists: false },
+      user: { exists: userExists, path: paths.userPath },
+      project: { exists: projectExists, path: paths.projectPath || null },
+      custom: { exists: customExists, path: paths.customPath }
+    }
+  };
+}
+
+function removeProviderConfig(providerId, workingDirectory, scope = 'user') {
+  if (!providerId || typeof providerId !== 'string') {
+    throw new Error('Provider ID is required');
   }
 
-  // If nothing was deleted (built-in agent), disable it in highest-precedence config
-  if (!deleted) {
-    const jsonTarget = getJsonWriteTarget(layers, workingDirectory ? AGENT_SCOPE.PROJECT : AGENT_SCOPE.USER);
-    const targetConfig = jsonTarget.config || {};
-    if (!targetConfig.agent) targetConfig.agent = {};
-    targetConfig.agent[agentName] = { disable: true };
-    writeConfig(targetConfig, jsonTarget.path || CONFIG_FILE);
-    console.log(`Disabled built-in agent: ${agentName}`);
+  const layers = readConfigLayers(workingDirectory);
+  let targetPath = layers.paths.userPath;
+
+  if (scope === 'project') {
+    if (!workingDirectory) {
+      throw new Error('Working directory is required for project scope');
+    }
+    targetPath = layers.paths.projectPath || targetPath;
+  } else if (scope === 'custom') {
+    if (!layers.paths.customPath) {
+      return false;
+    }
+    targetPath = layers.paths.customPath;
+  }
+
+  const targetConfig = getConfigForPath(layers, targetPath);
+  const providerConfig = isPlainObject(targetConfig.provider) ? targetConfig.provider : {};
+  const providersConfig = isPlainObject(targetConfig.providers) ? targetConfig.providers : {};
+  const removedProvider = providerConfig && Object.prototype.hasOwnProperty.call(providerConfig, providerId);
+  const removedProviders = providersConfig && Object.prototype.hasOwnProperty.call(providersConfig, providerId);
+
+  if (!removedProvider && !removedProviders) {
+    return false;
+  }
+
+  if (removedProvider) {
+    delete providerConfig[providerId];
+    if (Object.keys(providerConfig).length === 0) {
+      delete targetConfig.provider;
+    } else {
+      targetConfig.provider = providerConfig;
+    }
+  }
+
+  if (removedProviders) {
+    delete providersConfig[providerId];
+    if (Object.keys(providersConfig).length === 0) {
+      delete targetConfig.providers;
+    } else {
+      targetConfig.providers = providersConfig;
+    }
   }
+
+  writeConfig(targetConfig, targetPath || getUserConfigFile());
+  console.log(`Removed provider ${providerId} from config: ${targetPath}`);
+  return true;
 }
 
-function getCommandSources(commandName, workingDirectory) {
-  // Check project level first (takes precedence)
-  const projectPath = workingDirectory ? getProjectCommandPath(workingDirectory, commandName) : null;
-  const projectExists = projectPath && fs.existsSync(projectPath);
-  
-  // Then check user level
-  const userPath = getUserCommandPath(commandName);
-  const userExists = fs.existsSync(userPath);
-  
-  // Determine which md file to use (project takes precedence)
-  const mdPath = projectExists ? projectPath : (userExists ? userPath : null);
-  const mdExists = !!mdPath;
-  const mdScope = projectExists ? COMMAND_SCOPE.PROJECT : (userExists ? COMMAND_SCOPE.USER : null);
+function deleteCommand(commandName, workingDirectory, options = {}) {
+  return withLockOverride(options.force, () => {
+    assertIfMatch(ENTITY_KIND.COMMAND, commandName, workingDirectory, options.ifMatch);
+    const trash = options.soft ? createTrashEntry(ENTITY_KIND.COMMAND, commandName) : null;
+    const removedPaths = [];
+    let deleted = false;
+
+    // A soft delete that fails part-way puts the trashed files back
+    try {
+      // Check project level first (takes precedence)
+      if (workingDirectory) {
+        const projectPath = getProjectCommandPath(workingDirectory, commandName);
+        if (fs.existsSync(projectPath)) {
+          removeEntityFile(projectPath, trash);
+          console.log(`Deleted project-level command .md file: ${projectPath}`);
+          removedPaths.push(projectPath);
+          deleted = true;
+        }
+      }
+
+      // Then check user level
+      const userPath = getUserCommandPath(commandName);
+      if (fs.existsSync(userPath)) {
+        removeEntityFile(userPath, trash);
+        console.log(`Deleted user-level command .md file: ${userPath}`);
+        removedPaths.push(userPath);
+        deleted = true;
+      }
+
+      // Also check json config (highest precedence entry only)
+      const layers = readConfigLayers(workingDirectory);
+      const jsonSource = getJsonEntrySource(layers, 'command', commandName);
+      if (jsonSource.exists && jsonSource.config && jsonSource.path) {
+        if (trash) trash.manifest.json = { path: jsonSource.path, value: jsonSource.section };
+        if (!jsonSource.config.command) jsonSource.config.command = {};
+        delete jsonSource.config.command[commandName];
+        writeConfig(jsonSource.config, jsonSource.path);
+        console.log(`Removed command from opencode.json: ${commandName}`);
+        removedPaths.push(jsonSource.path);
+        deleted = true;
+      }
+
+      if (!deleted) {
+        throw new Error(`Command "${commandName}" not found`);
+      }
+
+      if (trash) {
+        saveTrashEntry(trash);
+      } else {
+        forgetEntityLock(ENTITY_KIND.COMMAND, commandName, removedPaths);
+      }
+    } catch (error) {
+      if (trash) rollBackTrashEntry(trash);
+      throw error;
+    }
+    logOperation('delete', ENTITY_KIND.COMMAND, commandName);
+  });
+}
 
+/**
+ * Rename a command and/or move it to another scope in one step, for .md and json-only commands alike.
+ * The .md file is copied byte-for-byte to the destination and the source removed. The `command.<name>` json entry
+ * moves with it: renamed in place in every layer, and moved from the source scope's layer to the target scope's
+ * layer on a scope change. Refuses when the destination file or json entry already exists.
+ * All-or-nothing: on failure every touched file is restored. Returns the destination path (.md, else config file).
+ */
+function relocateCommand(commandName, newName, newScope, workingDirectory) {
+  const source = getCommandScope(commandName, workingDirectory);
   const layers = readConfigLayers(workingDirectory);
+  const jsonLayers = getJsonLayerList(layers);
   const jsonSource = getJsonEntrySource(layers, 'command', commandName);
-  const jsonSection = jsonSource.section;
-  const jsonPath = jsonSource.path || layers.paths.customPath || layers.paths.projectPath || layers.paths.userPath;
-  const jsonScope = jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER;
+  if (!source.path && !jsonSource.exists) {
+    throw new Error(`Command "${commandName}" not found`);
+  }
 
-  const sources = {
-    md: {
-      exists: mdExists,
-      path: mdPath,
-      scope: mdScope,
-      fields: []
-    },
-    json: {
-      exists: jsonSource.exists,
-      path: jsonPath,
-      scope: jsonSource.exists ? jsonScope : null,
-      fields: []
-    },
-    // Additional info about both levels
-    projectMd: {
-      exists: projectExists,
-      path: projectPath
-    },
-    userMd: {
-      exists: userExists,
-      path: userPath
+  const sourceScope = source.scope
+    || (jsonSource.path === layers.paths.projectPath ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER);
+  const targetName = newName || commandName;
+  const targetScope = newScope || sourceScope;
+  if (targetScope === COMMAND_SCOPE.PROJECT && !workingDirectory) {
+    throw new Error('A project directory is required to move a command to project scope');
+  }
+  if (targetName === commandName && targetScope === sourceScope) {
+    return source.path || jsonSource.path;
+  }
+
+  const targetPath = !source.path ? null : targetScope === COMMAND_SCOPE.PROJECT
+    ? getProjectCommandPath(workingDirectory, targetName)
+    : getUserCommandPath(targetName);
+  if (targetPath && fs.existsSync(targetPath)) {
+    throw new Error(`Cannot relocate command ${commandName}: ${targetPath} already exists`);
+  }
+
+  // Each layer holding the entry either renames it in place or, on a scope change, hands it to the target layer
+  const targetLayer = jsonLayers.find((layer) => layer.scope === targetScope);
+  const jsonMoves = jsonLayers
+    .filter((layer) => isPlainObject(layer.config?.command) && layer.config.command[commandName] !== undefined)
+    .map((layer) => ({
+      from: layer,
+      to: targetScope !== sourceScope && layer.scope === sourceScope && targetLayer ? targetLayer : layer
+    }))
+    .filter(({ from, to }) => from !== to || targetName !== commandName);
+  for (const { to } of jsonMoves) {
+    if (to.config?.command?.[targetName] !== undefined) {
+      throw new Error(`Cannot relocate command ${commandName}: ${targetName} already exists in ${to.path}`);
     }
-  };
+  }
 
-  if (mdExists) {
-    const { frontmatter, body } = parseMdFile(mdPath);
-    sources.md.fields = Object.keys(frontmatter);
-    if (body) {
-      sources.md.fields.push('template');
+  if (source.path) {
+    assertMdPathUnlocked(source.path);
+  }
+  const touched = [source.path, targetPath, ...jsonMoves.flatMap(({ from, to }) => [from.path, to.path])].filter(Boolean);
+  const snapshot = snapshotFiles(new Set(touched));
+  try {
+    if (source.path) {
+      const content = fs.readFileSync(source.path);
+      fs.mkdirSync(path.dirname(targetPath), { recursive: true });
+      fs.writeFileSync(targetPath, content, { flag: 'wx' });
+      fs.unlinkSync(source.path);
+    }
+
+    const changedLayers = new Set();
+    for (const { from, to } of jsonMoves) {
+      const entry = from.config.command[commandName];
+      delete from.config.command[commandName];
+      if (Object.keys(from.config.command).length === 0) delete from.config.command;
+      if (!to.config) to.config = {};
+      if (!isPlainObject(to.config.command)) to.config.command = {};
+      to.config.command[targetName] = entry;
+      changedLayers.add(from).add(to);
+    }
+    for (const layer of changedLayers) {
+      writeConfig(layer.config, layer.path);
     }
+  } catch (error) {
+    snapshot.restore('command relocate');
+    throw new Error(`Failed to relocate command ${commandName}: ${error.message}`);
   }
 
-  if (jsonSection) {
-    sources.json.fields = Object.keys(jsonSection);
+  const destination = targetPath || jsonMoves.find(({ from }) => from.path === jsonSource.path)?.to.path || jsonSource.path;
+  console.log(`Relocated command: ${commandName} (${source.path || jsonSource.path}) -> ${targetName} (${destination})`);
+  return destination;
+}
+
+/**
+ * Dry run of deleteCommand: report what it would remove without touching the filesystem.
+ * Returns: { files: string[], jsonKeys: [{ path, pointer }], disable: null }
+ */
+function planDeleteCommand(commandName, workingDirectory) {
+  const files = [];
+  if (workingDirectory) {
+    const projectPath = getProjectCommandPath(workingDirectory, commandName);
+    if (fs.existsSync(projectPath)) files.push(projectPath);
+  }
+  const userPath = getUserCommandPath(commandName);
+  if (fs.existsSync(userPath)) files.push(userPath);
+
+  const layers = readConfigLayers(workingDirectory);
+  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
+  const jsonKeys = jsonSource.exists && jsonSource.path
+    ? [{ path: jsonSource.path, pointer: toJsonPointer(['command', commandName]) }]
+    : [];
+
+  if (files.length === 0 && jsonKeys.length === 0) {
+    throw new Error(`Command "${commandName}" not found`);
+  }
+
+  // Commands have no built-in disable fallback
+  return { files, jsonKeys, disable: null };
+}
+
+// ============== PROFILES ==============
+
+/**
+ * Active profile name from OPENCODE_PROFILE, or null for the default config dir
+ */
+function getActiveProfile() {
+  return ACTIVE_PROFILE;
+}
+
+/**
+ * Where this module reads and writes user config, after OPENCODE_PROFILE and OPENCODE_CONFIG are applied
+ * Returns: { profile, configDir, agentDir, commandDir, skillDir, configFile, customConfigFile }
+ */
+function configPaths() {
+  return {
+    profile: ACTIVE_PROFILE,
+    configDir: OPENCODE_CONFIG_DIR,
+    agentDir: AGENT_DIR,
+    commandDir: COMMAND_DIR,
+    skillDir: SKILL_DIR,
+    configFile: getUserConfigFile(),
+    customConfigFile: CUSTOM_CONFIG_FILE
+  };
+}
+
+/**
+ * List the default config dir and every ~/.config/opencode-<profile> directory
+ * Returns: [{ name, path, active }] with name null for the default
+ */
+function listProfiles() {
+  const profiles = [];
+  const defaultDir = getProfileConfigDir(null);
+  if (fs.existsSync(defaultDir) || ACTIVE_PROFILE === null) {
+    profiles.push({ name: null, path: defaultDir, active: ACTIVE_PROFILE === null });
+  }
+  if (!fs.existsSync(CONFIG_HOME_DIR)) {
+    return profiles;
+  }
+
+  const names = fs.readdirSync(CONFIG_HOME_DIR, { withFileTypes: true })
+    .filter((entry) => entry.isDirectory() && entry.name.startsWith(PROFILE_DIR_PREFIX))
+    .map((entry) => entry.name.slice(PROFILE_DIR_PREFIX.length))
+    .filter((name) => PROFILE_NAME_PATTERN.test(name))
+    .sort();
+  for (const name of names) {
+    profiles.push({ name, path: getProfileConfigDir(name), active: name === ACTIVE_PROFILE });
+  }
+  if (ACTIVE_PROFILE !== null && !names.includes(ACTIVE_PROFILE)) {
+    profiles.push({ name: ACTIVE_PROFILE, path: OPENCODE_CONFIG_DIR, active: true });
+  }
+  return profiles;
+}
+
+// ============== TRASH ==============
+
+function createTrashEntry(entity, name) {
+  const deletedAt = new Date();
+  const id = `${deletedAt.getTime()}-${entity}-${encodeURIComponent(name).replace(/%/g, '_')}`;
+  return {
+    dir: path.join(TRASH_DIR, id),
+    manifest: { id, entity, name, deletedAt: deletedAt.toISOString(), files: [], json: null }
+  };
+}
+
+function moveFile(from, to) {
+  recordBatchDir(path.dirname(to));
+  recordBatchWrite(from);
+  recordBatchWrite(to);
+  fs.mkdirSync(path.dirname(to), { recursive: true });
+  try {
+    fs.renameSync(from, to);
+  } catch (error) {
+    // Project and config dirs may live on different devices
+    if (error?.code !== 'EXDEV') throw error;
+    fs.copyFileSync(from, to);
+    fs.unlinkSync(from);
+  }
+}
+
+/**
+ * Throw NOT_WRITABLE for a file in a read-only extra agent search dir
+ */
+function assertNotInReadOnlyAgentDir(filePath) {
+  if (getReadOnlyAgentDir(filePath)) {
+    throw createConfigError(
+      CONFIG_ERROR.NOT_WRITABLE,
+      `Cannot remove ${filePath}: it is in a read-only agent search dir`,
+      { path: filePath }
+    );
+  }
+}
+
+/**
+ * Delete an entity file, or move it into the trash entry when soft-deleting.
+ * Throws NOT_WRITABLE for files in read-only extra search dirs.
+ */
+function removeEntityFile(filePath, trash) {
+  assertMdPathUnlocked(filePath);
+  assertNotInReadOnlyAgentDir(filePath);
+  if (!trash) {
+    recordBatchWrite(filePath);
+    fs.unlinkSync(filePath);
+    return;
+  }
+  const trashPath = path.join(trash.dir, `${trash.manifest.files.length}-${path.basename(filePath)}`);
+  moveFile(filePath, trashPath);
+  trash.manifest.files.push({ originalPath: filePath, trashPath });
+}
+
+/**
+ * Undo a soft delete that failed before its manifest was saved: move the trashed files back, drop the entry
+ */
+function rollBackTrashEntry(trash) {
+  for (const file of [...trash.manifest.files].reverse()) {
+    try {
+      moveFile(file.trashPath, file.originalPath);
+    } catch (restoreError) {
+      console.error(`Failed to move ${file.trashPath} back to ${file.originalPath}:`, restoreError);
+    }
   }
-
-  return sources;
+  fs.rmSync(trash.dir, { recursive: true, force: true });
 }
 
-function createCommand(commandName, config, workingDirectory, scope) {
-  ensureDirs();
+function saveTrashEntry(trash) {
+  recordBatchDir(trash.dir);
+  recordBatchWrite(path.join(trash.dir, TRASH_MANIFEST));
+  fs.mkdirSync(trash.dir, { recursive: true });
+  fs.writeFileSync(path.join(trash.dir, TRASH_MANIFEST), JSON.stringify(trash.manifest, null, 2), 'utf8');
+  console.log(`Moved ${trash.manifest.entity} ${trash.manifest.name} to trash: ${trash.manifest.id}`);
+}
 
-  // Check if command already exists at either level
-  const projectPath = workingDirectory ? getProjectCommandPath(workingDirectory, commandName) : null;
-  const userPath = getUserCommandPath(commandName);
-  
-  if (projectPath && fs.existsSync(projectPath)) {
-    throw new Error(`Command ${commandName} already exists as project-level .md file`);
+function readTrashManifest(id) {
+  if (typeof id !== 'string' || !/^[\w.-]+$/.test(id)) {
+    throw new Error(`Invalid trash id: ${id}`);
   }
-  
-  if (fs.existsSync(userPath)) {
-    throw new Error(`Command ${commandName} already exists as user-level .md file`);
+  const manifestPath = path.join(TRASH_DIR, id, TRASH_MANIFEST);
+  if (!fs.existsSync(manifestPath)) {
+    throw new Error(`Trash entry not found: ${id}`);
   }
+  return JSON.parse(readTextFile(manifestPath));
+}
 
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
-  if (jsonSource.exists) {
-    throw new Error(`Command ${commandName} already exists in opencode.json`);
+/**
+ * List soft-deleted agents and commands, newest first
+ * Returns: [{ id, entity, name, deletedAt, files: string[], jsonPath }]
+ */
+function listTrash() {
+  if (!fs.existsSync(TRASH_DIR)) {
+    return [];
   }
+  const entries = [];
+  for (const entry of fs.readdirSync(TRASH_DIR, { withFileTypes: true })) {
+    if (!entry.isDirectory()) continue;
+    try {
+      const manifest = readTrashManifest(entry.name);
+      entries.push({
+        id: manifest.id,
+        entity: manifest.entity,
+        name: manifest.name,
+        deletedAt: manifest.deletedAt,
+        files: manifest.files.map((file) => file.originalPath),
+        jsonPath: manifest.json?.path ?? null
+      });
+    } catch (error) {
+      console.warn(`Skipping unreadable trash entry ${entry.name}:`, error);
+    }
+  }
+  return entries.sort((a, b) => b.deletedAt.localeCompare(a.deletedAt));
+}
 
-  // Determine target path based on requested scope
-  let targetPath;
-  let targetScope;
-  
-  if (scope === COMMAND_SCOPE.PROJECT && workingDirectory) {
-    ensureProjectCommandDir(workingDirectory);
-    targetPath = projectPath;
-    targetScope = COMMAND_SCOPE.PROJECT;
-  } else {
-    targetPath = userPath;
-    targetScope = COMMAND_SCOPE.USER;
+/**
+ * Restore a soft-deleted entity: move its files back and reinstate the json entry captured at delete time.
+ * Refuses when any original file or json entry has been recreated since.
+ */
+function restoreFromTrash(id) {
+  const manifest = readTrashManifest(id);
+  const sectionKey = getEntityDescriptor(manifest.entity).sectionKey;
+
+  for (const file of manifest.files) {
+    if (fs.existsSync(file.originalPath)) {
+      throw new Error(`Cannot restore ${manifest.name}: ${file.originalPath} already exists`);
+    }
+  }
+  let config = null;
+  if (manifest.json) {
+    config = readConfigFile(manifest.json.path);
+    if (config[sectionKey]?.[manifest.name] !== undefined) {
+      throw new Error(`Cannot restore ${manifest.name}: it already exists in ${manifest.json.path}`);
+    }
   }
 
-  // Extract scope from config - it's only used for path determination, not written to file
-  const { template, scope: _scopeFromConfig, ...frontmatter } = config;
+  // A failed json write moves the files back into the trash, so the entry stays restorable
+  const restored = [];
+  try {
+    for (const file of manifest.files) {
+      moveFile(file.trashPath, file.originalPath);
+      restored.push(file);
+    }
+    if (config) {
+      if (!isPlainObject(config[sectionKey])) config[sectionKey] = {};
+      config[sectionKey][manifest.name] = manifest.json.value;
+      writeConfig(config, manifest.json.path);
+    }
+  } catch (error) {
+    for (const file of restored.reverse()) {
+      try {
+        moveFile(file.originalPath, file.trashPath);
+      } catch (moveError) {
+        console.error(`Failed to move ${file.originalPath} back to the trash:`, moveError);
+      }
+    }
+    throw error;
+  }
 
-  writeMdFile(targetPath, frontmatter, template || '');
-  console.log(`Created new command: ${commandName} (scope: ${targetScope}, path: ${targetPath})`);
+  fs.rmSync(path.join(TRASH_DIR, manifest.id), { recursive: true, force: true });
+  console.log(`Restored ${manifest.entity} ${manifest.name} from trash`);
+  return { entity: manifest.entity, name: manifest.name };
 }
 
-function updateCommand(commandName, updates, workingDirectory) {
-  ensureDirs();
-
-  // Determine correct path: project level takes precedence
-  const { scope, path: mdPath } = getCommandWritePath(commandName, workingDirectory);
-  const mdExists = mdPath && fs.existsSync(mdPath);
+// ============== AGENT MCP ACCESS ==============
 
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
-  const jsonSection = jsonSource.section;
-  const hasJsonFields = jsonSource.exists && jsonSection && Object.keys(jsonSection).length > 0;
-  const jsonTarget = jsonSource.exists
-    ? { config: jsonSource.config, path: jsonSource.path }
-    : getJsonWriteTarget(layers, workingDirectory ? COMMAND_SCOPE.PROJECT : COMMAND_SCOPE.USER);
-  let config = jsonTarget.config || {};
+// OpenCode names MCP tools `<server>_<tool>`; an agent's `tools` map toggles them, `<server>_*` covers the whole server
 
-  // Only create a new md file for built-in overrides (no md + no json)
-  const isBuiltinOverride = !mdExists && !hasJsonFields;
+function getEffectiveAgentTools(agentName, workingDirectory) {
+  const mdInfo = getAgentScope(agentName, workingDirectory);
+  const mdTools = mdInfo.path ? parseMdFrontmatterOnly(mdInfo.path).frontmatter.tools : undefined;
+  const jsonTools = readConfig(workingDirectory).agent?.[agentName]?.tools;
+  return {
+    ...(isPlainObject(mdTools) ? mdTools : {}),
+    ...(isPlainObject(jsonTools) ? jsonTools : {})
+  };
+}
 
-  let targetPath = mdPath;
-  let targetScope = scope;
+/**
+ * The server a tools key belongs to; the longest matching name wins so `git_hub_*` is not read as `git`
+ */
+function getMcpServerForToolKey(key, serverNames) {
+  return serverNames
+    .filter((server) => key.startsWith(`${server}_`))
+    .sort((a, b) => b.length - a.length)[0] ?? null;
+}
 
-  if (!mdExists && isBuiltinOverride) {
-    // Built-in command override - create at user level
-    targetPath = getUserCommandPath(commandName);
-    targetScope = COMMAND_SCOPE.USER;
+/**
+ * Per-server MCP access for an agent, read from its effective `tools` map (json over md).
+ * Every configured MCP server is listed; `enabled` is the `<server>_*` toggle or null when unset.
+ * Returns: { [server]: { enabled: boolean|null, tools: { [tool]: boolean } } }
+ */
+function getAgentMcpAccess(agentName, workingDirectory) {
+  const tools = getEffectiveAgentTools(agentName, workingDirectory);
+  const mcp = readConfig(workingDirectory).mcp;
+  const serverNames = new Set(isPlainObject(mcp) ? Object.keys(mcp) : []);
+  // Keep access to servers that are no longer configured visible
+  for (const key of Object.keys(tools)) {
+    if (key.endsWith('_*')) serverNames.add(key.slice(0, -2));
   }
 
-  const mdData = mdExists ? parseMdFile(mdPath) : (isBuiltinOverride ? { frontmatter: {}, body: '' } : null);
-
-  let mdModified = false;
-  let jsonModified = false;
-  let creatingNewMd = isBuiltinOverride;
-
-  for (const [field, value] of Object.entries(updates)) {
+  const access = {};
+  for (const server of serverNames) {
+    access[server] = { enabled: null, tools: {} };
+  }
+  for (const [key, value] of Object.entries(tools)) {
+    const server = getMcpServerForToolKey(key, Array.from(serverNames));
+    if (!server) continue;
+    const tool = key.slice(server.length + 1);
+    if (tool === '*') {
+      access[server].enabled = value;
+    } else {
+      access[server].tools[tool] = value;
+    }
+  }
+  return access;
+}
 
-    if (field === 'template') {
-      const normalizedValue = typeof value === 'string' ? value : (value == null ? '' : String(value));
+/**
+ * Replace the MCP access of the given servers in an agent's `tools` map; servers not in `access`
+ * and non-MCP tool toggles are kept. `enabled: null` drops the server-wide toggle, a null server clears it entirely.
+ * Old keys are removed from the .md frontmatter and the json entry separately, wherever each one lives;
+ * new keys go to json when it already has a `tools` map (it wins over md), else to the .md file.
+ * Returns: { warnings } naming servers that are not configured in the `mcp` section.
+ */
+function setAgentMcpAccess(agentName, access, workingDirectory, options = {}) {
+  return withLockOverride(options.force, () => {
+    if (!isPlainObject(access)) {
+      throw new Error('MCP access must be an object keyed by server name');
+    }
 
-      if (mdExists || creatingNewMd) {
-        if (mdData) {
-          mdData.body = normalizedValue;
-          mdModified = true;
-        }
-        continue;
-      } else if (isPromptFileReference(jsonSection?.template)) {
-        const templateFilePath = resolvePromptFilePath(jsonSection.template);
-        if (!templateFilePath) {
-          throw new Error(`Invalid template file reference for command ${commandName}`);
+    const mcp = readConfig(workingDirectory).mcp;
+    const configuredServers = isPlainObject(mcp) ? Object.keys(mcp) : [];
+    const warnings = Object.keys(access)
+      .filter((server) => access[server] !== null && !configuredServers.includes(server))
+      .map((server) => `Agent ${agentName} references unknown MCP server "${server}"`);
+    warnings.forEach((warning) => console.warn(warning));
+
+    const serverNames = Array.from(new Set([...configuredServers, ...Object.keys(access)]));
+    const withoutAccessKeys = (tools) => Object.fromEntries(Object.entries(isPlainObject(tools) ? tools : {}).filter(([key]) => {
+      const server = getMcpServerForToolKey(key, serverNames);
+      return !(server && access[server] !== undefined);
+    }));
+
+    const added = {};
+    for (const [server, serverAccess] of Object.entries(access)) {
+      if (serverAccess === null) continue;
+      const { enabled = null, tools: serverTools = {} } = isPlainObject(serverAccess) ? serverAccess : {};
+      if (enabled !== null && typeof enabled !== 'boolean') {
+        throw createConfigError(CONFIG_ERROR.INVALID_FIELD_TYPE, `MCP access for ${server} must have a boolean "enabled"`, { field: 'tools' });
+      }
+      if (enabled !== null) {
+        added[`${server}_*`] = enabled;
+      }
+      for (const [tool, allowed] of Object.entries(serverTools)) {
+        if (typeof allowed !== 'boolean') {
+          throw createConfigError(CONFIG_ERROR.INVALID_FIELD_TYPE, `MCP tool ${server}_${tool} must be true or false`, { field: 'tools' });
         }
-        writePromptFile(templateFilePath, normalizedValue);
-        continue;
-      } else if (isPromptFileReference(normalizedValue)) {
-        if (!config.command) config.command = {};
-        if (!config.command[commandName]) config.command[commandName] = {};
-        config.command[commandName].template = normalizedValue;
-        jsonModified = true;
-        continue;
+        added[`${server}_${tool}`] = allowed;
       }
-
-      // For JSON-only commands, store template inline in JSON
-      if (!config.command) config.command = {};
-      if (!config.command[commandName]) config.command[commandName] = {};
-      config.command[commandName].template = normalizedValue;
-      jsonModified = true;
-      continue;
     }
 
-    const inMd = mdData?.frontmatter?.[field] !== undefined;
-    const inJson = jsonSection?.[field] !== undefined;
+    const mdInfo = getAgentScope(agentName, workingDirectory);
+    if (!mdInfo.path) {
+      const tools = { ...withoutAccessKeys(getEffectiveAgentTools(agentName, workingDirectory)), ...added };
+      updateAgent(agentName, { tools: Object.keys(tools).length > 0 ? tools : null }, workingDirectory, options);
+      return { warnings };
+    }
 
-    // JSON takes precedence over md, so update JSON first if field exists there
-    if (inJson) {
-      if (!config.command) config.command = {};
-      if (!config.command[commandName]) config.command[commandName] = {};
-      config.command[commandName][field] = value;
-      jsonModified = true;
-    } else if (inMd || creatingNewMd) {
-      if (mdData) {
-        mdData.frontmatter[field] = value;
-        mdModified = true;
+    const mdData = parseMdFile(mdInfo.path);
+    const jsonSource = getJsonEntrySource(readConfigLayers(workingDirectory), 'agent', agentName);
+    const jsonSection = isPlainObject(jsonSource.section) ? jsonSource.section : null;
+    const addToJson = isPlainObject(jsonSection?.tools);
+
+    const mdTools = { ...withoutAccessKeys(mdData.frontmatter.tools), ...(addToJson ? {} : added) };
+    const mdChanged = JSON.stringify(mdTools) !== JSON.stringify(isPlainObject(mdData.frontmatter.tools) ? mdData.frontmatter.tools : {});
+    const jsonTools = addToJson ? { ...withoutAccessKeys(jsonSection.tools), ...added } : null;
+    const jsonChanged = addToJson && JSON.stringify(jsonTools) !== JSON.stringify(jsonSection.tools);
+
+    const rollback = snapshotFiles([mdInfo.path, ...(jsonChanged ? [jsonSource.path] : [])]);
+    try {
+      if (mdChanged) {
+        if (Object.keys(mdTools).length > 0) mdData.frontmatter.tools = mdTools;
+        else delete mdData.frontmatter.tools;
+        writeMdFile(mdInfo.path, mdData.frontmatter, mdData.body);
       }
-    } else {
-      // New field - add to appropriate location based on command source
-      if ((mdExists || creatingNewMd) && mdData) {
-        mdData.frontmatter[field] = value;
-        mdModified = true;
-      } else {
-        if (!config.command) config.command = {};
-        if (!config.command[commandName]) config.command[commandName] = {};
-        config.command[commandName][field] = value;
-        jsonModified = true;
+      if (jsonChanged) {
+        if (Object.keys(jsonTools).length > 0) jsonSection.tools = jsonTools;
+        else delete jsonSection.tools;
+        if (Object.keys(jsonSection).length === 0) delete jsonSource.config.agent[agentName];
+        writeConfig(jsonSource.config, jsonSource.path);
       }
+    } catch (error) {
+      rollback.restore('MCP access update');
+      throw error;
     }
-  }
 
-  if (mdModified && mdData) {
-    writeMdFile(targetPath, mdData.frontmatter, mdData.body);
-  }
+    if (mdChanged || jsonChanged) {
+      logOperation('update', ENTITY_KIND.AGENT, agentName, ['tools']);
+    }
+    return { warnings };
+  });
+}
 
-  if (jsonModified) {
-    writeConfig(config, jsonTarget.path || CONFIG_FILE);
-  }
+// ============== GENERAL SETTINGS ==============
+
+// Scalar top-level keys editable through the settings panel, with a validator per key
+const GENERAL_SETTINGS = {
+  theme: { type: 'string' },
+  model: { type: 'string' },
+  small_model: { type: 'string' },
+  username: { type: 'string' },
+  share: { type: 'string', values: ['manual', 'auto', 'disabled'] },
+  autoshare: { type: 'boolean' },
+  autoupdate: { type: 'boolean' }
+};
 
-  console.log(`Updated command: ${commandName} (scope: ${targetScope}, md: ${mdModified}, json: ${jsonModified})`);
+/**
+ * Read the allowlisted top-level settings from the user config.
+ * Unset keys are returned as undefined.
+ */
+function getGeneralSettings() {
+  const config = readConfigFile(getUserConfigFile());
+  const settings = {};
+  for (const key of Object.keys(GENERAL_SETTINGS)) {
+    settings[key] = config[key];
+  }
+  return settings;
 }
 
-function getProviderSources(providerId, workingDirectory) {
-  const layers = readConfigLayers(workingDirectory);
-  const { userConfig, projectConfig, customConfig, paths } = layers;
+/**
+ * Set a single allowlisted top-level setting in the user config. `null`/`undefined` removes it.
+ * Throws INVALID_SETTING for unknown keys or values of the wrong type.
+ */
+function setGeneralSetting(key, value) {
+  const spec = GENERAL_SETTINGS[key];
+  if (!spec) {
+    throw createConfigError(CONFIG_ERROR.INVALID_SETTING, `Unknown setting: ${key}`, { key });
+  }
 
-  const customProviders = isPlainObject(customConfig?.provider) ? customConfig.provider : {};
-  const customProvidersAlias = isPlainObject(customConfig?.providers) ? customConfig.providers : {};
-  const projectProviders = isPlainObject(projectConfig?.provider) ? projectConfig.provider : {};
-  const projectProvidersAlias = isPlainObject(projectConfig?.providers) ? projectConfig.providers : {};
-  const userProviders = isPlainObject(userConfig?.provider) ? userConfig.provider : {};
-  const userProvidersAlias = isPlainObject(userConfig?.providers) ? userConfig.providers : {};
+  const config = readConfigFile(getUserConfigFile());
+  if (value === null || value === undefined) {
+    if (!(key in config)) {
+      return;
+    }
+    delete config[key];
+  } else {
+    if (typeof value !== spec.type) {
+      throw createConfigError(
+        CONFIG_ERROR.INVALID_SETTING,
+        `Setting ${key} must be a ${spec.type}`,
+        { key, value }
+      );
+    }
+    if (spec.values && !spec.values.includes(value)) {
+      throw createConfigError(
+        CONFIG_ERROR.INVALID_SETTING,
+        `Setting ${key} must be one of: ${spec.values.join(', ')}`,
+        { key, value }
+      );
+    }
+    config[key] = value;
+  }
 
-  const customExists =
-    (customProviders && Object.prototype.hasOwnProperty.call(customProviders, providerId)) ||
-    (customProvidersAlias && Object.prototype.hasOwnProperty.call(customProvidersAlias, providerId));
-  const projectExists =
-    (projectProviders && Object.prototype.hasOwnProperty.call(projectProviders, providerId)) ||
-    (projectProvidersAlias && Object.prototype.hasOwnProperty.call(projectProvidersAlias, providerId));
-  const userExists =
-    (userProviders && Object.prototype.hasOwnProperty.call(userProviders, providerId)) ||
-    (userProvidersAlias && Object.prototype.hasOwnProperty.call(userProvidersAlias, providerId));
+  writeConfig(config, getUserConfigFile());
+  console.log(`Updated setting: ${key}`);
+}
 
-  return {
-    sources: {
-      auth: { exists: false },
-      user: { exists: userExists, path: paths.userPath },
-      project: { exists: projectExists, path: paths.projectPath || null },
-      custom: { exists: customExists, path: paths.customPath }
-    }
-  };
+/**
+ * Get the configured theme name, or null when unset
+ */
+function getTheme() {
+  return getGeneralSettings().theme ?? null;
 }
 
-function removeProviderConfig(providerId, workingDirectory, scope = 'user') {
-  if (!providerId || typeof providerId !== 'string') {
-    throw new Error('Provider ID is required');
+/**
+ * Set the top-level theme. Unknown names are written anyway (with a warning) since themes
+ * may come from places this module can't see.
+ */
+function setTheme(name, workingDirectory) {
+  if (typeof name !== 'string' || !name.trim()) {
+    throw createConfigError(CONFIG_ERROR.INVALID_SETTING, 'Theme name must be a non-empty string', { key: 'theme' });
   }
+  if (!listAvailableThemes(workingDirectory).includes(name)) {
+    console.warn(`Theme "${name}" is not a known theme`);
+  }
+  setGeneralSetting('theme', name);
+}
 
-  const layers = readConfigLayers(workingDirectory);
-  let targetPath = layers.paths.userPath;
-
-  if (scope === 'project') {
-    if (!workingDirectory) {
-      throw new Error('Working directory is required for project scope');
-    }
-    targetPath = layers.paths.projectPath || targetPath;
-  } else if (scope === 'custom') {
-    if (!layers.paths.customPath) {
-      return false;
+/**
+ * List theme names: OpenCode's bundled themes plus *.json files in the user and project themes dirs
+ */
+function listAvailableThemes(workingDirectory) {
+  const themes = new Set(BUILTIN_THEME_NAMES);
+  const dirs = [path.join(OPENCODE_CONFIG_DIR, 'themes')];
+  if (workingDirectory) {
+    dirs.push(path.join(workingDirectory, '.opencode', 'themes'));
+  }
+  for (const dir of dirs) {
+    if (!fs.existsSync(dir)) continue;
+    for (const entry of fs.readdirSync(dir, { withFileTypes: true })) {
+      if (entry.isFile() && entry.name.endsWith('.json')) {
+        themes.add(entry.name.slice(0, -'.json'.length));
+      }
     }
-    targetPath = layers.paths.customPath;
   }
+  return Array.from(themes).sort((a, b) => a.localeCompare(b));
+}
 
-  const targetConfig = getConfigForPath(layers, targetPath);
-  const providerConfig = isPlainObject(targetConfig.provider) ? targetConfig.provider : {};
-  const providersConfig = isPlainObject(targetConfig.providers) ? targetConfig.providers : {};
-  const removedProvider = providerConfig && Object.prototype.hasOwnProperty.call(providerConfig, providerId);
-  const removedProviders = providersConfig && Object.prototype.hasOwnProperty.call(providersConfig, providerId);
+// ============== INSTRUCTIONS ==============
 
-  if (!removedProvider && !removedProviders) {
-    return false;
+function readInstructions(config) {
+  return Array.isArray(config.instructions)
+    ? config.instructions.filter((entry) => typeof entry === 'string')
+    : [];
+}
+
+/**
+ * List the top-level `instructions` globs from the user opencode.json, in order
+ */
+function listInstructions() {
+  return readInstructions(readConfigFile(getUserConfigFile()));
+}
+
+/**
+ * Append an instruction glob (e.g. "AGENTS.md" or "docs/*.md"). Already-present entries are left where they are.
+ * Returns the updated list.
+ */
+function addInstruction(glob) {
+  if (typeof glob !== 'string' || !glob.trim()) {
+    throw createConfigError(CONFIG_ERROR.INVALID_SETTING, 'Instruction must be a non-empty string', { key: 'instructions', value: glob });
   }
+  const entry = glob.trim();
+  const config = readConfigFile(getUserConfigFile());
+  const instructions = Array.from(new Set(readInstructions(config)));
+  if (!instructions.includes(entry)) {
+    instructions.push(entry);
+  }
+  if (!Array.isArray(config.instructions) || instructions.length !== config.instructions.length
+    || instructions.some((value, index) => value !== config.instructions[index])) {
+    config.instructions = instructions;
+    writeConfig(config, getUserConfigFile());
+    console.log(`Added instruction: ${entry}`);
+  }
+  return instructions;
+}
 
-  if (removedProvider) {
-    delete providerConfig[providerId];
-    if (Object.keys(providerConfig).length === 0) {
-      delete targetConfig.provider;
-    } else {
-      targetConfig.provider = providerConfig;
-    }
+/**
+ * Remove an instruction glob; the key is dropped once the list is empty. Returns the updated list.
+ */
+function removeInstruction(glob) {
+  const config = readConfigFile(getUserConfigFile());
+  if (!Array.isArray(config.instructions)) {
+    return [];
+  }
+  const entry = typeof glob === 'string' ? glob.trim() : glob;
+  const instructions = config.instructions.filter((value) => value !== entry);
+  if (instructions.length === config.instructions.length) {
+    return readInstructions(config);
+  }
+  if (instructions.length > 0) {
+    config.instructions = instructions;
+  } else {
+    delete config.instructions;
   }
+  writeConfig(config, getUserConfigFile());
+  console.log(`Removed instruction: ${entry}`);
+  return readInstructions(config);
+}
 
-  if (removedProviders) {
-    delete providersConfig[providerId];
-    if (Object.keys(providersConfig).length === 0) {
-      delete targetConfig.providers;
-    } else {
-      targetConfig.providers = providersConfig;
+// ============== DEPRECATED KEYS ==============
+
+/**
+ * Apply the renames for one section to an object in place. A rename whose new key is already set
+ * is reported as a conflict and left alone.
+ */
+function applyKeyRenames(target, section, location, migrations, dryRun) {
+  for (const rename of DEPRECATED_KEY_RENAMES) {
+    if (rename.section !== section || !isPlainObject(target) || target[rename.from] === undefined) continue;
+    const migration = { ...location, from: rename.from, to: rename.to };
+    if (target[rename.to] !== undefined) {
+      migrations.push({ ...migration, action: 'conflict' });
+      continue;
+    }
+    migrations.push({ ...migration, action: 'renamed' });
+    if (!dryRun) {
+      const value = target[rename.from];
+      delete target[rename.from];
+      target[rename.to] = rename.mapValue ? rename.mapValue(value) : value;
     }
   }
-
-  writeConfig(targetConfig, targetPath || CONFIG_FILE);
-  console.log(`Removed provider ${providerId} from config: ${targetPath}`);
-  return true;
 }
 
-function deleteCommand(commandName, workingDirectory) {
-  let deleted = false;
-
-  // Check project level first (takes precedence)
-  if (workingDirectory) {
-    const projectPath = getProjectCommandPath(workingDirectory, commandName);
-    if (fs.existsSync(projectPath)) {
-      fs.unlinkSync(projectPath);
-      console.log(`Deleted project-level command .md file: ${projectPath}`);
-      deleted = true;
+/**
+ * Rewrite deprecated keys (DEPRECATED_KEY_RENAMES) in every opencode.json layer and in agent/command frontmatter.
+ * With dryRun nothing is written.
+ * Returns: [{ path, entity, name, from, to, action: 'renamed'|'conflict'|'error' }] (entity/name are null for top-level keys);
+ * an .md file that can't be read is reported as action 'error' with { code, message } and the rest still migrate
+ */
+function migrateDeprecatedKeys(dryRun, workingDirectory) {
+  const migrations = [];
+
+  for (const layer of getJsonLayerList(readConfigLayers(workingDirectory))) {
+    if (!isPlainObject(layer.config) || !fs.existsSync(layer.path)) continue;
+    const before = migrations.length;
+    applyKeyRenames(layer.config, null, { path: layer.path, entity: null, name: null }, migrations, dryRun);
+    for (const entity of [ENTITY_KIND.AGENT, ENTITY_KIND.COMMAND]) {
+      const entries = isPlainObject(layer.config[entity]) ? layer.config[entity] : {};
+      for (const [name, entry] of Object.entries(entries)) {
+        applyKeyRenames(entry, entity, { path: layer.path, entity, name }, migrations, dryRun);
+      }
+    }
+    if (!dryRun && migrations.slice(before).some((migration) => migration.action === 'renamed')) {
+      writeConfig(layer.config, layer.path);
     }
   }
 
-  // Then check user level
-  const userPath = getUserCommandPath(commandName);
-  if (fs.existsSync(userPath)) {
-    fs.unlinkSync(userPath);
-    console.log(`Deleted user-level command .md file: ${userPath}`);
-    deleted = true;
+  const mdDirs = [
+    ...getOwnedAgentDirs(workingDirectory).map((dir) => ({ ...dir, entity: ENTITY_KIND.AGENT, recursive: false })),
+    ...getCommandDirs(workingDirectory).map((dir) => ({ ...dir, entity: ENTITY_KIND.COMMAND, recursive: true }))
+  ];
+  for (const { dir, scope, entity, recursive } of mdDirs) {
+    // One dir at a time so shadowed files are migrated too
+    for (const { name, path: mdPath } of listMdEntities([{ scope, dir }], { recursive }).values()) {
+      const { frontmatter, error } = readListingFrontmatter(mdPath);
+      if (error) {
+        migrations.push({ path: mdPath, entity, name, from: null, to: null, action: 'error', ...error });
+        continue;
+      }
+      if (!DEPRECATED_KEY_RENAMES.some((rename) => rename.section === entity && frontmatter[rename.from] !== undefined)) continue;
+      const mdData = parseMdFile(mdPath);
+      const before = migrations.length;
+      applyKeyRenames(mdData.frontmatter, entity, { path: mdPath, entity, name }, migrations, dryRun);
+      if (!dryRun && migrations.slice(before).some((migration) => migration.action === 'renamed')) {
+        writeMdFile(mdPath, mdData.frontmatter, mdData.body);
+      }
+    }
   }
 
-  // Also check json config (highest precedence entry only)
-  const layers = readConfigLayers(workingDirectory);
-  const jsonSource = getJsonEntrySource(layers, 'command', commandName);
-  if (jsonSource.exists && jsonSource.config && jsonSource.path) {
-    if (!jsonSource.config.command) jsonSource.config.command = {};
-    delete jsonSource.config.command[commandName];
-    writeConfig(jsonSource.config, jsonSource.path);
-    console.log(`Removed command from opencode.json: ${commandName}`);
-    deleted = true;
+  if (!dryRun && migrations.length > 0) {
+    console.log(`Migrated ${migrations.filter((migration) => migration.action === 'renamed').length} deprecated config key(s)`);
   }
+  return migrations;
+}
 
-  if (!deleted) {
-    throw new Error(`Command "${commandName}" not found`);
+// ============== MANAGED KEYS ==============
+
+// Top-level keys this module edits; every other key is passed through untouched on read-modify-write
+const MANAGED_KEYS = Object.freeze(['agent', 'command', 'provider', 'providers', 'instructions', ...Object.keys(GENERAL_SETTINGS)]);
+
+function managedKeys() {
+  return MANAGED_KEYS;
+}
+
+/**
+ * List top-level keys present in a config that this module doesn't manage
+ */
+function findUnmanagedKeys(config) {
+  if (!isPlainObject(config)) {
+    return [];
   }
+  return Object.keys(config).filter((key) => !MANAGED_KEYS.includes(key));
 }
 
 // ============== SKILL CRUD ==============
@@ -1809,11 +6993,22 @@ export {
   getAgentScope,
   getAgentPermissionSource,
   getAgentConfig,
+  getEffectiveAgent,
+  listAgents,
+  setAgentSearchPath,
   createAgent,
   updateAgent,
+  replaceAgent,
+  exportAgent,
+  exportEffectiveConfig,
+  importAgent,
+  listTemplates,
+  instantiateTemplate,
   deleteAgent,
   getCommandSources,
   getCommandScope,
+  listCommands,
+  listCommandsGrouped,
   createCommand,
   updateCommand,
   deleteCommand,
@@ -1827,14 +7022,110 @@ export {
   writeSkillSupportingFile,
   deleteSkillSupportingFile,
   readConfig,
+  readConfigAnnotated,
+  readConfigWithDiagnostics,
+  readConfigStrict,
+  parseMdFrontmatterOnly,
+  setAgentNested,
+  planDeleteAgent,
+  findAgentDependents,
+  planDeleteCommand,
+  canonicalizeAllAgents,
+  getEntityEtag,
+  resolveLoadSource,
+  relocateCommand,
+  agentSchema,
+  commandSchema,
+  resolveAgentModel,
+  lintMdBody,
+  migrateDeprecatedKeys,
+  managedKeys,
+  findUnmanagedKeys,
+  isEntityLocked,
+  setEntityLock,
+  setOperationLog,
+  getOperationLogPath,
+  getTheme,
+  setTheme,
+  listAvailableThemes,
+  readTextFileLossy,
+  findBackupAtOrBefore,
+  setAgentNumeric,
+  getAgentMcpAccess,
+  setAgentMcpAccess,
+  applyBatch,
+  applyJsonPatch,
+  getActiveProfile,
+  configPaths,
+  listProfiles,
+  listTrash,
+  restoreFromTrash,
+  watchEntity,
+  externalizeAgentPrompt,
+  inlineAgentPrompt,
+  dedupPromptFiles,
+  getEffectiveCommand,
+  validateCommandArgs,
+  collectReferencedModels,
+  listAgentNames,
+  listCommandNames,
+  streamCommands,
+  getConfigTree,
+  refreshAndDiff,
+  diffConfigs,
   writeConfig,
+  serializeConfig,
+  scheduleConfigWrite,
+  flushPendingWrites,
+  setConfigWriteDelay,
+  listBackups,
+  pruneBackups,
+  restoreConfigBackup,
+  registerPreWriteHook,
+  registerPostWriteHook,
+  setConfigValidator,
+  setBackupDirectory,
+  getBackupDirectory,
   getProviderSources,
   removeProviderConfig,
   AGENT_DIR,
   COMMAND_DIR,
   SKILL_DIR,
   CONFIG_FILE,
+  configFileVariant,
+  relocateField,
+  setAgentStorage,
+  resolveFieldTarget,
+  getRevealTarget,
+  getPromptReferenceStatus,
+  normalizePromptReference,
+  setRequiredFieldsPolicy,
+  getRequiredFieldsPolicy,
+  findOrphanDisables,
+  removeOrphanDisables,
+  normalizeDisables,
+  setAgentEnabled,
+  setAllBuiltinsEnabled,
+  swapAgentNames,
+  resetAgentToBuiltin,
+  setWriteLimits,
+  getWriteLimits,
+  getGeneralSettings,
+  setGeneralSetting,
+  listInstructions,
+  addInstruction,
+  removeInstruction,
+  resolveAgentRoster,
+  diffAgentRosters,
   AGENT_SCOPE,
   COMMAND_SCOPE,
-  SKILL_SCOPE
+  SKILL_SCOPE,
+  ENTITY_KIND,
+  STORAGE_TARGET,
+  SERIALIZE_STYLE,
+  CONFIG_ERROR,
+  BUILTIN_AGENT_NAMES,
+  USER_FACING_BUILTIN_AGENT_NAMES,
+  BUILTIN_COMMAND_NAMES,
+  UNGROUPED_COMMAND_KEY
 };
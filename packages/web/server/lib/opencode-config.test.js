@@ -0,0 +1,1098 @@
+import { afterAll, afterEach, beforeEach, describe, expect, it } from 'bun:test';
+import fs from 'fs';
+import os from 'os';
+import path from 'path';
+
+// Config paths are resolved when the module loads, so HOME must point at a sandbox before importing it
+const HOME = fs.mkdtempSync(path.join(os.tmpdir(), 'openchamber-config-test-'));
+process.env.HOME = HOME;
+for (const key of ['OPENCODE_PROFILE', 'OPENCODE_CONFIG', 'OPENCHAMBER_AGENT_DIRS', 'OPENCHAMBER_BACKUP_DIR']) {
+  delete process.env[key];
+}
+const config = await import('./opencode-config.js');
+
+const CONFIG_DIR = path.join(HOME, '.config', 'opencode');
+const AGENT_DIR = path.join(CONFIG_DIR, 'agents');
+const CONFIG_FILE = path.join(CONFIG_DIR, 'opencode.json');
+const LATIN1_BYTES = Buffer.from([0x63, 0x61, 0x66, 0xe9, 0x0a]); // "café\n" in latin-1
+
+const writeFile = (filePath, content) => {
+  fs.mkdirSync(path.dirname(filePath), { recursive: true });
+  fs.writeFileSync(filePath, content);
+};
+const readJson = (filePath = CONFIG_FILE) => JSON.parse(fs.readFileSync(filePath, 'utf8'));
+
+const isRoot = typeof process.getuid === 'function' && process.getuid() === 0;
+
+const makeProject = () => fs.mkdtempSync(path.join(HOME, 'project-'));
+
+beforeEach(() => {
+  fs.rmSync(CONFIG_DIR, { recursive: true, force: true });
+});
+
+afterAll(() => {
+  fs.rmSync(HOME, { recursive: true, force: true });
+});
+
+describe('invalid encoding', () => {
+  it('names the offending file when an agent is read directly', () => {
+    writeFile(path.join(AGENT_DIR, 'legacy.md'), Buffer.concat([Buffer.from('---\ndescription: x\n---\n'), LATIN1_BYTES]));
+
+    expect(() => config.getAgentConfig('legacy')).toThrow({ code: config.CONFIG_ERROR.INVALID_ENCODING });
+    expect(() => config.getAgentConfig('legacy')).toThrow('legacy.md');
+  });
+
+  it('reports the bad file per entry in listings and keeps going', () => {
+    writeFile(path.join(AGENT_DIR, 'good.md'), '---\ndescription: fine\n---\nprompt\n');
+    writeFile(path.join(AGENT_DIR, 'legacy.md'), Buffer.concat([Buffer.from('---\ndescription: '), LATIN1_BYTES, Buffer.from('---\n')]));
+
+    const agents = config.listAgents();
+    expect(agents.find((agent) => agent.name === 'good')).toMatchObject({ description: 'fine' });
+    expect(agents.find((agent) => agent.name === 'good').error).toBeUndefined();
+    expect(agents.find((agent) => agent.name === 'legacy').error).toMatchObject({ code: config.CONFIG_ERROR.INVALID_ENCODING });
+
+    expect(config.getConfigTree().agents.find((agent) => agent.name === 'legacy').error).toBeDefined();
+    expect(config.migrateDeprecatedKeys(true).find((entry) => entry.name === 'legacy')).toMatchObject({ action: 'error' });
+    expect(config.findAgentDependents('good')).toEqual([]);
+  });
+
+  it('offers a lossy read that reports the replaced offsets', () => {
+    const filePath = path.join(CONFIG_DIR, 'legacy.txt');
+    writeFile(filePath, LATIN1_BYTES);
+
+    expect(config.readTextFileLossy(filePath)).toEqual({ content: 'caf�\n', replacedOffsets: [3] });
+  });
+
+  it('reports overlong encodings and surrogates the way the decoder replaces them', () => {
+    const filePath = path.join(CONFIG_DIR, 'overlong.txt');
+    // "a", overlong "/", "b", UTF-16 surrogate half, "c", truncated 3-byte sequence at the end
+    writeFile(filePath, Buffer.from([0x61, 0xc0, 0xaf, 0x62, 0xed, 0xa0, 0x80, 0x63, 0xe2, 0x82]));
+
+    expect(config.readTextFileLossy(filePath)).toEqual({
+      content: 'a\ufffd\ufffdb\ufffd\ufffd\ufffdc\ufffd',
+      replacedOffsets: [1, 2, 4, 5, 6, 8]
+    });
+  });
+});
+
+describe('read-only user config dir', () => {
+  const makeReadOnlyConfig = () => {
+    writeFile(path.join(AGENT_DIR, 'existing.md'), '---\ndescription: baked in\n---\nprompt\n');
+    for (const dir of [AGENT_DIR, CONFIG_DIR]) fs.chmodSync(dir, 0o555);
+  };
+
+  afterEach(() => {
+    for (const dir of [CONFIG_DIR, AGENT_DIR]) fs.chmodSync(dir, 0o755);
+  });
+
+  it('still reads existing agents', () => {
+    makeReadOnlyConfig();
+
+    expect(config.getAgentSources('existing').md.exists).toBe(true);
+    expect(fs.readdirSync(CONFIG_DIR)).toEqual(['agents']);
+  });
+
+  it('still writes project-scope agents', () => {
+    makeReadOnlyConfig();
+    const project = makeProject();
+
+    config.createAgent('local', { description: 'here', prompt: 'p' }, project, config.AGENT_SCOPE.PROJECT);
+    expect(fs.existsSync(path.join(project, '.opencode', 'agents', 'local.md'))).toBe(true);
+  });
+
+  it.skipIf(isRoot)('rejects user-scope writes with NOT_WRITABLE naming the dir', () => {
+    makeReadOnlyConfig();
+
+    expect(() => config.createAgent('fresh', { description: 'x', prompt: 'p' })).toThrow({
+      code: config.CONFIG_ERROR.NOT_WRITABLE,
+      path: AGENT_DIR
+    });
+  });
+});
+
+describe('BOM-prefixed files', () => {
+  it('parses a BOM-prefixed opencode.json', () => {
+    writeFile(CONFIG_FILE, `﻿${JSON.stringify({ agent: { bom: { model: 'a/b' } } })}`);
+
+    expect(config.readConfig().agent.bom).toEqual({ model: 'a/b' });
+  });
+
+  it('keeps a BOM-free opencode.json BOM-free when writing it back', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { plain: { model: 'a/b' } } }));
+
+    config.updateAgent('plain', { model: 'c/d' });
+
+    const written = fs.readFileSync(CONFIG_FILE);
+    expect(written[0]).toBe(0x7b); // "{"
+    expect(readJson().agent.plain).toEqual({ model: 'c/d' });
+  });
+
+  it('parses BOM-prefixed markdown frontmatter and writes it back without a BOM', () => {
+    writeFile(path.join(AGENT_DIR, 'bom.md'), '﻿---\ndescription: from windows\n---\nprompt\n');
+
+    expect(config.getAgentConfig('bom').config).toMatchObject({ description: 'from windows', prompt: 'prompt' });
+
+    config.updateAgent('bom', { description: 'edited' });
+    const written = fs.readFileSync(path.join(AGENT_DIR, 'bom.md'), 'utf8');
+    expect(written.charCodeAt(0)).not.toBe(0xfeff);
+    expect(written.startsWith('---\n')).toBe(true);
+  });
+
+  it('keeps a BOM-free markdown file BOM-free when writing it back', () => {
+    writeFile(path.join(AGENT_DIR, 'plain.md'), '---\ndescription: plain\n---\nprompt\n');
+
+    config.updateAgent('plain', { description: 'edited' });
+
+    expect(fs.readFileSync(path.join(AGENT_DIR, 'plain.md'))[0]).toBe(0x2d); // "-"
+  });
+});
+
+describe('backup directory', () => {
+  afterEach(() => {
+    config.setBackupDirectory(null);
+  });
+
+  it('writes, lists and restores backups in a separate backup directory', () => {
+    const backupDir = fs.mkdtempSync(path.join(HOME, 'backups-'));
+    config.setBackupDirectory(backupDir);
+    writeFile(CONFIG_FILE, JSON.stringify({ model: 'first/model' }));
+
+    config.writeConfig({ model: 'second/model' }, CONFIG_FILE);
+
+    expect(fs.readdirSync(CONFIG_DIR)).toEqual(['opencode.json']);
+    const backups = config.listBackups();
+    expect(backups).toHaveLength(1);
+    expect(path.dirname(backups[0].path)).toBe(backupDir);
+    expect(backups[0].sourcePath).toBe(CONFIG_FILE);
+
+    config.restoreConfigBackup(CONFIG_FILE);
+    expect(readJson()).toEqual({ model: 'first/model' });
+  });
+});
+
+describe('write hooks', () => {
+  const unregister = [];
+
+  afterEach(() => {
+    while (unregister.length > 0) unregister.pop()();
+  });
+
+  it('lets a pre-write hook veto configs missing $schema', () => {
+    unregister.push(config.registerPreWriteHook((value) => {
+      if (!value.$schema) throw new Error('$schema is required');
+    }));
+
+    expect(() => config.writeConfig({ model: 'a/b' }, CONFIG_FILE)).toThrow('$schema is required');
+    expect(fs.existsSync(CONFIG_FILE)).toBe(false);
+
+    config.writeConfig({ $schema: 'https://opencode.ai/config.json' }, CONFIG_FILE);
+    expect(readJson().$schema).toBe('https://opencode.ai/config.json');
+  });
+
+  it('passes the config and path to post-write hooks', () => {
+    const calls = [];
+    unregister.push(config.registerPostWriteHook((value, filePath) => calls.push({ value, filePath })));
+
+    config.writeConfig({ model: 'a/b' }, CONFIG_FILE);
+    expect(calls).toEqual([{ value: { model: 'a/b' }, filePath: CONFIG_FILE }]);
+  });
+});
+
+describe('serializeConfig', () => {
+  const value = { model: 'a/b', agent: { z: { model: 'c/d' }, a: { disable: true } } };
+
+  it('emits compact output on a single line', () => {
+    expect(config.serializeConfig(value, config.SERIALIZE_STYLE.COMPACT)).not.toContain('\n');
+  });
+
+  it('sorts keys recursively in sorted mode', () => {
+    const sorted = config.serializeConfig(value, config.SERIALIZE_STYLE.SORTED);
+
+    expect(sorted.indexOf('"a"')).toBeLessThan(sorted.indexOf('"z"'));
+    expect(sorted.indexOf('"agent"')).toBeLessThan(sorted.indexOf('"model"'));
+  });
+
+  it('round-trips compact output through readConfig', () => {
+    config.writeConfig(value, CONFIG_FILE, { style: config.SERIALIZE_STYLE.COMPACT });
+
+    expect(config.readConfig()).toEqual(value);
+  });
+});
+
+describe('required fields policy', () => {
+  afterEach(() => {
+    config.setRequiredFieldsPolicy({});
+  });
+
+  it('rejects an agent missing a required model', () => {
+    config.setRequiredFieldsPolicy({ agent: ['model'] });
+
+    expect(() => config.createAgent('modelless', { description: 'd', prompt: 'p' })).toThrow({
+      code: config.CONFIG_ERROR.MISSING_REQUIRED_FIELD,
+      field: 'model'
+    });
+    expect(fs.existsSync(path.join(AGENT_DIR, 'modelless.md'))).toBe(false);
+  });
+
+  it('rejects an update that removes a required field', () => {
+    config.createAgent('modelled', { description: 'd', model: 'a/b', prompt: 'p' });
+    config.setRequiredFieldsPolicy({ agent: ['model'] });
+
+    expect(() => config.updateAgent('modelled', { model: null })).toThrow({
+      code: config.CONFIG_ERROR.MISSING_REQUIRED_FIELD,
+      field: 'model'
+    });
+  });
+
+  it('requires nothing by default', () => {
+    config.createAgent('anything', { prompt: 'p' });
+
+    expect(fs.existsSync(path.join(AGENT_DIR, 'anything.md'))).toBe(true);
+  });
+});
+
+describe('block-scalar frontmatter', () => {
+  it('keeps multi-line frontmatter values as literal block scalars', () => {
+    const notes = 'line one\nline two\n';
+    config.createAgent('multi', { description: 'd', notes, prompt: 'p' });
+
+    expect(fs.readFileSync(path.join(AGENT_DIR, 'multi.md'), 'utf8')).toContain('notes: |');
+    expect(config.getAgentConfig('multi').config.notes).toBe(notes);
+  });
+});
+
+describe('frontmatter detection', () => {
+  it('tolerates blank lines before the opening ---', () => {
+    writeFile(path.join(AGENT_DIR, 'spaced.md'), '\n\n---\ndescription: spaced\n---\nthe body\n');
+
+    expect(config.getAgentConfig('spaced').config).toMatchObject({ description: 'spaced', prompt: 'the body' });
+  });
+});
+
+describe('diffAgentRosters', () => {
+  it('reports a disabled built-in as removed from view', () => {
+    const before = config.resolveAgentRoster();
+    config.setAgentEnabled('plan', false);
+    config.createAgent('added', { description: 'd', prompt: 'p' });
+
+    expect(config.diffAgentRosters(before, config.resolveAgentRoster())).toEqual({
+      added: ['added'],
+      removed: ['plan'],
+      enabled: [],
+      disabled: []
+    });
+  });
+});
+
+describe('field coercion', () => {
+  it('stores stringified booleans and numbers from the UI as real values', () => {
+    config.createAgent('typed', { description: 'd', prompt: 'p' });
+    config.updateAgent('typed', { disable: 'true', temperature: '0.4' });
+    config.updateCommand('typed-cmd', { template: 't', subtask: 'false' });
+
+    expect(config.getAgentConfig('typed').config).toMatchObject({ disable: true, temperature: 0.4 });
+    expect(fs.readFileSync(path.join(AGENT_DIR, 'typed.md'), 'utf8')).toContain('disable: true');
+    expect(config.getEffectiveCommand('typed-cmd').config.subtask).toBe(false);
+  });
+
+  it('leaves unknown fields untouched', () => {
+    config.createAgent('untyped', { description: 'd', prompt: 'p' });
+    config.updateAgent('untyped', { custom: 'true' });
+
+    expect(config.getAgentConfig('untyped').config.custom).toBe('true');
+  });
+});
+
+describe('write limits', () => {
+  const defaultLimits = config.getWriteLimits();
+
+  afterEach(() => {
+    config.setWriteLimits(defaultLimits);
+  });
+
+  it('refuses oversized configs before touching disk', () => {
+    config.setWriteLimits({ maxBytes: 64 });
+
+    expect(() => config.writeConfig({ instructions: ['x'.repeat(128)] }, CONFIG_FILE)).toThrow({
+      code: config.CONFIG_ERROR.LIMIT_EXCEEDED,
+      limit: 64
+    });
+    expect(fs.existsSync(CONFIG_FILE)).toBe(false);
+  });
+
+  it('refuses entities with too many fields', () => {
+    config.setWriteLimits({ maxFieldsPerEntity: 3 });
+    const agent = Object.fromEntries(['a', 'b', 'c', 'd'].map((key) => [key, 1]));
+
+    expect(() => config.writeConfig({ agent: { wide: agent } }, CONFIG_FILE)).toThrow({
+      code: config.CONFIG_ERROR.LIMIT_EXCEEDED,
+      limit: 3,
+      actual: 4
+    });
+  });
+});
+
+describe('resetAgentToBuiltin', () => {
+  it('resets a built-in override without leaving a disable flag', () => {
+    writeFile(path.join(AGENT_DIR, 'build.md'), '---\nmodel: a/b\n---\ncustom prompt\n');
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { build: { temperature: 0.1 } } }));
+
+    config.resetAgentToBuiltin('build');
+
+    expect(fs.existsSync(path.join(AGENT_DIR, 'build.md'))).toBe(false);
+    expect(readJson().agent?.build).toBeUndefined();
+    expect(config.resolveAgentRoster().find((agent) => agent.name === 'build')).toMatchObject({ enabled: true });
+  });
+
+  it('refuses to reset a user-defined agent', () => {
+    config.createAgent('mine', { description: 'd', prompt: 'p' });
+
+    expect(() => config.resetAgentToBuiltin('mine')).toThrow();
+    expect(fs.existsSync(path.join(AGENT_DIR, 'mine.md'))).toBe(true);
+  });
+});
+
+describe('parseMdFrontmatterOnly', () => {
+  const LARGE_BODY = `${'x'.repeat(256 * 1024)}\n`;
+
+  it('yields the same frontmatter as the full parse', () => {
+    const filePath = path.join(AGENT_DIR, 'big.md');
+    writeFile(filePath, `---\ndescription: big\nmodel: a/b\ntemperature: 0.3\n---\n${LARGE_BODY}`);
+
+    const { prompt, ...fullFrontmatter } = config.getAgentConfig('big').config;
+    expect(prompt.trim()).toBe(LARGE_BODY.trim());
+    expect(config.parseMdFrontmatterOnly(filePath)).toEqual({ frontmatter: fullFrontmatter, hasBody: true });
+  });
+
+  it('stops reading at the closing fence', () => {
+    const filePath = path.join(AGENT_DIR, 'big.md');
+    writeFile(filePath, `---\ndescription: big\n---\n${LARGE_BODY}`);
+    const readSync = fs.readSync;
+    let bytesRead = 0;
+    fs.readSync = (...args) => {
+      const count = readSync(...args);
+      bytesRead += count;
+      return count;
+    };
+
+    try {
+      config.parseMdFrontmatterOnly(filePath);
+    } finally {
+      fs.readSync = readSync;
+    }
+    expect(bytesRead).toBeGreaterThan(0);
+    expect(bytesRead).toBeLessThan(LARGE_BODY.length / 10);
+  });
+
+  it('reports a frontmatter-only file as having no body', () => {
+    const filePath = path.join(AGENT_DIR, 'bare.md');
+    writeFile(filePath, '---\ndescription: bare\n---\n\n');
+
+    expect(config.parseMdFrontmatterOnly(filePath)).toEqual({ frontmatter: { description: 'bare' }, hasBody: false });
+  });
+});
+
+describe('setAgentNested', () => {
+  it('creates and updates a nested pointer, keeping siblings', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { build: { options: { keep: 1 } } } }));
+
+    config.setAgentNested('build', '/options/reasoning/effort', 'low');
+    config.setAgentNested('build', '/options/reasoning/effort', 'high');
+
+    expect(readJson().agent.build).toEqual({ options: { keep: 1, reasoning: { effort: 'high' } } });
+  });
+
+  it('rejects pointers through non-object values', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { build: { model: 'a/b' } } }));
+
+    expect(() => config.setAgentNested('build', '/model/provider', 'x')).toThrow({
+      code: config.CONFIG_ERROR.INVALID_POINTER
+    });
+  });
+
+  it('treats removing a missing agent or path as a no-op', () => {
+    const original = JSON.stringify({ agent: { build: { options: {} } } });
+    writeFile(CONFIG_FILE, original);
+
+    config.setAgentNested('ghost', '/options/effort', undefined);
+    config.setAgentNested('build', '/options/reasoning/effort', undefined);
+
+    expect(fs.readFileSync(CONFIG_FILE, 'utf8')).toBe(original);
+  });
+});
+
+describe('opencode.jsonc', () => {
+  const JSONC_FILE = path.join(CONFIG_DIR, 'opencode.jsonc');
+
+  it('reads and writes back a .jsonc-only setup, keeping comments', () => {
+    writeFile(JSONC_FILE, '{\n  // pinned by the team\n  "model": "a/b",\n  "agent": {\n    "j": { "model": "c/d" }\n  }\n}\n');
+
+    expect(config.configFileVariant()).toBe('jsonc');
+    expect(config.readConfig().agent.j).toEqual({ model: 'c/d' });
+
+    config.updateAgent('j', { temperature: 0.2 });
+
+    expect(fs.existsSync(CONFIG_FILE)).toBe(false);
+    expect(fs.readFileSync(JSONC_FILE, 'utf8')).toContain('// pinned by the team');
+    expect(config.readConfig().agent.j).toEqual({ model: 'c/d', temperature: 0.2 });
+  });
+
+  it('prefers opencode.json when both exist', () => {
+    writeFile(JSONC_FILE, '{ "model": "from/jsonc" }');
+    writeFile(CONFIG_FILE, '{ "model": "from/json" }');
+
+    expect(config.configFileVariant()).toBe('json');
+    expect(config.readConfig().model).toBe('from/json');
+  });
+});
+
+describe('entity etags', () => {
+  it('rejects an update with a stale etag', () => {
+    config.createAgent('etagged', { description: 'd', prompt: 'p' });
+    const etag = config.getEntityEtag(config.ENTITY_KIND.AGENT, 'etagged');
+
+    config.updateAgent('etagged', { description: 'changed elsewhere' });
+
+    expect(() => config.updateAgent('etagged', { description: 'mine' }, undefined, { ifMatch: etag })).toThrow({
+      code: config.CONFIG_ERROR.PRECONDITION_FAILED
+    });
+    expect(config.getAgentConfig('etagged').config.description).toBe('changed elsewhere');
+  });
+
+  it('accepts an update with the current etag', () => {
+    config.createAgent('etagged', { description: 'd', prompt: 'p' });
+    const etag = config.getEntityEtag(config.ENTITY_KIND.AGENT, 'etagged');
+
+    config.updateAgent('etagged', { description: 'mine' }, undefined, { ifMatch: etag });
+
+    expect(config.getAgentConfig('etagged').config.description).toBe('mine');
+  });
+});
+
+describe('prompt path expansion', () => {
+  afterEach(() => {
+    delete process.env.OPENCHAMBER_TEST_PROMPTS;
+  });
+
+  it('expands ~ to the home directory', () => {
+    expect(config.getPromptReferenceStatus('{file:~/prompts/x.txt}').resolvedPath).toBe(path.join(HOME, 'prompts', 'x.txt'));
+  });
+
+  it('expands $VAR and ${VAR}', () => {
+    process.env.OPENCHAMBER_TEST_PROMPTS = path.join(HOME, 'shared');
+
+    expect(config.getPromptReferenceStatus('{file:$OPENCHAMBER_TEST_PROMPTS/x.txt}').resolvedPath).toBe(path.join(HOME, 'shared', 'x.txt'));
+    expect(config.getPromptReferenceStatus('{file:${OPENCHAMBER_TEST_PROMPTS}/x.txt}').resolvedPath).toBe(path.join(HOME, 'shared', 'x.txt'));
+  });
+
+  it('leaves undefined variables as written', () => {
+    expect(config.getPromptReferenceStatus('{file:$OPENCHAMBER_UNSET/x.txt}').resolvedPath).toBe(path.join(CONFIG_DIR, '$OPENCHAMBER_UNSET', 'x.txt'));
+  });
+});
+
+describe('operation log', () => {
+  const logPath = path.join(HOME, 'operations.jsonl');
+
+  beforeEach(() => {
+    fs.rmSync(logPath, { force: true });
+  });
+
+  afterEach(() => {
+    config.setOperationLog(false);
+  });
+
+  it('appends a well-formed line per update', () => {
+    config.createAgent('logged', { description: 'd', prompt: 'p' });
+    config.setOperationLog(true, logPath);
+
+    config.updateAgent('logged', { description: 'new', model: 'a/b' });
+
+    const lines = fs.readFileSync(logPath, 'utf8').trim().split('\n');
+    expect(lines).toHaveLength(1);
+    const entry = JSON.parse(lines[0]);
+    expect(entry).toMatchObject({ operation: 'update', entity: 'agent', name: 'logged', fields: ['description', 'model'] });
+    expect(Number.isNaN(Date.parse(entry.timestamp))).toBe(false);
+  });
+
+  it('is off by default', () => {
+    config.createAgent('unlogged', { description: 'd', prompt: 'p' });
+
+    expect(fs.existsSync(logPath)).toBe(false);
+  });
+});
+
+describe('frontmatter-only md files', () => {
+  it('writes an empty body without dangling blank lines and round-trips it', () => {
+    const filePath = path.join(AGENT_DIR, 'bare.md');
+    writeFile(filePath, '---\ndescription: bare\n---\nold prompt\n');
+
+    config.updateAgent('bare', { prompt: '' });
+    const first = fs.readFileSync(filePath, 'utf8');
+    expect(first.endsWith('\n---\n')).toBe(true);
+    expect(first).not.toContain('\n\n');
+
+    config.updateAgent('bare', { description: 'bare' });
+    expect(fs.readFileSync(filePath, 'utf8')).toBe(first);
+    expect(config.getAgentConfig('bare').config.prompt).toBeUndefined();
+  });
+});
+
+describe('configPaths', () => {
+  it('reports where the module reads and writes', () => {
+    expect(config.configPaths()).toMatchObject({
+      profile: null,
+      configDir: CONFIG_DIR,
+      agentDir: AGENT_DIR,
+      commandDir: path.join(CONFIG_DIR, 'commands'),
+      configFile: CONFIG_FILE
+    });
+  });
+
+  it('follows the config file variant in use', () => {
+    writeFile(path.join(CONFIG_DIR, 'opencode.jsonc'), '{}');
+
+    expect(config.configPaths().configFile).toBe(path.join(CONFIG_DIR, 'opencode.jsonc'));
+  });
+});
+
+describe('verifyWithOpencode', () => {
+  afterEach(() => {
+    config.setConfigValidator(null);
+  });
+
+  it('restores the previous version when the validator rejects the result', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ model: 'good/model' }));
+    config.setConfigValidator(() => ({ ok: false, message: 'unknown provider' }));
+
+    expect(() => config.writeConfig({ model: 'bad/model' }, CONFIG_FILE, { verifyWithOpencode: true })).toThrow({
+      code: config.CONFIG_ERROR.VERIFICATION_FAILED
+    });
+    expect(readJson()).toEqual({ model: 'good/model' });
+  });
+
+  it('skips the validator unless asked', () => {
+    config.setConfigValidator(() => {
+      throw new Error('validator should not run');
+    });
+
+    config.writeConfig({ model: 'a/b' }, CONFIG_FILE);
+    expect(readJson()).toEqual({ model: 'a/b' });
+  });
+});
+
+describe('concurrent deletion during update', () => {
+  it('reports the deletion instead of resurrecting the agent', () => {
+    const filePath = path.join(AGENT_DIR, 'doomed.md');
+    writeFile(filePath, '---\ndescription: d\n---\np\n');
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { doomed: { permission: { edit: 'ask' } } } }));
+    // The permission lands in opencode.json before the md is written; delete the md in between
+    const unregister = config.registerPreWriteHook(() => fs.rmSync(filePath, { force: true }));
+
+    try {
+      expect(() => config.updateAgent('doomed', { permission: { edit: 'deny' }, description: 'edited' })).toThrow({
+        code: config.CONFIG_ERROR.CONCURRENT_DELETION
+      });
+    } finally {
+      unregister();
+    }
+    expect(fs.existsSync(filePath)).toBe(false);
+  });
+});
+
+describe('normalizePromptReference', () => {
+  it('stores x, ./x and config-dir paths in one form', () => {
+    const forms = ['{file:prompts/x.md}', '{file:./prompts/x.md}', `{file:${path.join(CONFIG_DIR, 'prompts', 'x.md')}}`];
+
+    for (const form of forms) {
+      expect(config.normalizePromptReference(form)).toBe('{file:./prompts/x.md}');
+    }
+  });
+
+  it('is stable when applied twice', () => {
+    const once = config.normalizePromptReference('{file:prompts/x.md}');
+
+    expect(config.normalizePromptReference(once)).toBe(once);
+  });
+});
+
+describe('entity locks', () => {
+  it('blocks edits and deletes of a locked agent unless forced', () => {
+    config.createAgent('guarded', { description: 'd', prompt: 'p' });
+    config.setEntityLock(config.ENTITY_KIND.AGENT, 'guarded', true);
+
+    expect(() => config.updateAgent('guarded', { description: 'x' })).toThrow({ code: config.CONFIG_ERROR.LOCKED });
+    expect(() => config.deleteAgent('guarded')).toThrow({ code: config.CONFIG_ERROR.LOCKED });
+
+    config.updateAgent('guarded', { description: 'forced' }, undefined, { force: true });
+    expect(config.getAgentConfig('guarded').config.description).toBe('forced');
+  });
+
+  it('keeps the agent and its lock out of OpenCode-visible files', () => {
+    config.createAgent('guarded', { description: 'd', prompt: 'p' });
+    config.setEntityLock(config.ENTITY_KIND.AGENT, 'guarded', true);
+
+    expect(fs.readFileSync(path.join(AGENT_DIR, 'guarded.md'), 'utf8')).not.toContain('locked');
+    expect(config.isEntityLocked(config.ENTITY_KIND.AGENT, 'guarded')).toBe(true);
+  });
+
+  it('scopes a lock to the user config or one project', () => {
+    const project = makeProject();
+    const otherProject = makeProject();
+    config.createAgent('shared', { description: 'user', prompt: 'p' });
+    for (const dir of [project, otherProject]) {
+      writeFile(path.join(dir, '.opencode', 'agents', 'shared.md'), '---\ndescription: project\n---\np\n');
+    }
+
+    config.setEntityLock(config.ENTITY_KIND.AGENT, 'shared', true, project);
+
+    expect(config.isEntityLocked(config.ENTITY_KIND.AGENT, 'shared', project)).toBe(true);
+    expect(config.isEntityLocked(config.ENTITY_KIND.AGENT, 'shared')).toBe(false);
+    expect(config.isEntityLocked(config.ENTITY_KIND.AGENT, 'shared', otherProject)).toBe(false);
+    expect(() => config.updateAgent('shared', { description: 'x' }, project)).toThrow({ code: config.CONFIG_ERROR.LOCKED });
+    config.updateAgent('shared', { description: 'edited' }, otherProject);
+    config.updateAgent('shared', { description: 'edited' });
+  });
+
+  it('does not let ignoreDependents bypass a lock, nor force bypass dependents', () => {
+    config.createAgent('helper', { description: 'd', prompt: 'p' });
+    config.createCommand('review', { description: 'd', agent: 'helper', template: 't' });
+    config.setEntityLock(config.ENTITY_KIND.AGENT, 'helper', true);
+
+    expect(() => config.deleteAgent('helper', undefined, { ignoreDependents: true })).toThrow({ code: config.CONFIG_ERROR.LOCKED });
+    expect(() => config.deleteAgent('helper', undefined, { force: true })).toThrow({ code: config.CONFIG_ERROR.HAS_DEPENDENTS });
+
+    config.deleteAgent('helper', undefined, { force: true, ignoreDependents: true });
+    expect(fs.existsSync(path.join(AGENT_DIR, 'helper.md'))).toBe(false);
+    expect(config.isEntityLocked(config.ENTITY_KIND.AGENT, 'helper')).toBe(false);
+  });
+});
+
+describe('deleting a referenced agent', () => {
+  beforeEach(() => {
+    config.createAgent('helper', { description: 'd', prompt: 'p' });
+    config.createCommand('review', { description: 'd', agent: 'helper', template: 't' });
+  });
+
+  it('reports commands that still reference it', () => {
+    expect(() => config.deleteAgent('helper')).toThrow({
+      code: config.CONFIG_ERROR.HAS_DEPENDENTS,
+      name: 'helper',
+      dependents: [expect.objectContaining({ kind: 'command', name: 'review', field: 'agent' })]
+    });
+    expect(fs.existsSync(path.join(AGENT_DIR, 'helper.md'))).toBe(true);
+  });
+
+  it('leaves the references in place with ignoreDependents alone', () => {
+    config.deleteAgent('helper', undefined, { ignoreDependents: true });
+
+    expect(fs.existsSync(path.join(AGENT_DIR, 'helper.md'))).toBe(false);
+    expect(config.getEffectiveCommand('review').config.agent).toBe('helper');
+  });
+
+  it('nulls out the dangling references with clearReferences', () => {
+    config.deleteAgent('helper', undefined, { ignoreDependents: true, clearReferences: true });
+
+    expect(fs.existsSync(path.join(AGENT_DIR, 'helper.md'))).toBe(false);
+    expect(config.getEffectiveCommand('review').config.agent).toBeUndefined();
+  });
+});
+
+describe('concurrent creates', () => {
+  // Runs `interloper` just before the exclusive md write, after createAgent's existence checks have passed
+  const createWithInterloper = (name, interloper) => {
+    const writeFileSync = fs.writeFileSync;
+    fs.writeFileSync = (filePath, ...rest) => {
+      if (filePath === path.join(AGENT_DIR, `${name}.md`)) {
+        fs.writeFileSync = writeFileSync;
+        interloper();
+      }
+      return writeFileSync(filePath, ...rest);
+    };
+    try {
+      config.createAgent(name, { description: 'loser', prompt: 'p' });
+    } finally {
+      fs.writeFileSync = writeFileSync;
+    }
+  };
+
+  it('rejects a create whose md file appears after the existence check', () => {
+    const filePath = path.join(AGENT_DIR, 'racer.md');
+
+    expect(() => createWithInterloper('racer', () => writeFile(filePath, '---\ndescription: winner\n---\n'))).toThrow({
+      code: config.CONFIG_ERROR.ALREADY_EXISTS,
+      name: 'racer'
+    });
+    expect(fs.readFileSync(filePath, 'utf8')).toBe('---\ndescription: winner\n---\n');
+  });
+
+  it('rejects a create whose json entry appears after the existence check', () => {
+    expect(() => createWithInterloper('racer', () => writeFile(CONFIG_FILE, JSON.stringify({ agent: { racer: { description: 'winner' } } })))).toThrow({
+      code: config.CONFIG_ERROR.ALREADY_EXISTS,
+      name: 'racer'
+    });
+    expect(fs.existsSync(path.join(AGENT_DIR, 'racer.md'))).toBe(false);
+    expect(readJson().agent.racer).toEqual({ description: 'winner' });
+  });
+});
+
+describe('applyBatch rollback', () => {
+  const logPath = path.join(HOME, 'batch-operations.jsonl');
+  const failingOp = { op: 'rename', entity: 'agent', name: 'nobody' };
+
+  beforeEach(() => {
+    fs.rmSync(logPath, { force: true });
+  });
+
+  afterEach(() => {
+    config.setOperationLog(false);
+  });
+
+  it('removes files and project dirs created by ops before the failure', () => {
+    const project = makeProject();
+
+    expect(() => config.applyBatch([
+      { op: 'create', entity: 'agent', name: 'fresh', config: { description: 'd', prompt: 'p' }, scope: config.AGENT_SCOPE.PROJECT },
+      failingOp
+    ], project)).toThrow({ batchIndex: 1 });
+
+    expect(fs.existsSync(path.join(project, '.opencode'))).toBe(false);
+  });
+
+  it('restores soft-deleted files and leaves no trash entry', () => {
+    config.createAgent('kept', { description: 'd', prompt: 'p' });
+    const mdPath = path.join(AGENT_DIR, 'kept.md');
+    const mdBefore = fs.readFileSync(mdPath, 'utf8');
+
+    expect(() => config.applyBatch([
+      { op: 'delete', entity: 'agent', name: 'kept', options: { soft: true } },
+      failingOp
+    ])).toThrow({ batchIndex: 1 });
+
+    expect(fs.readFileSync(mdPath, 'utf8')).toBe(mdBefore);
+    expect(config.listTrash()).toEqual([]);
+  });
+
+  it('restores the lock of a force-deleted agent', () => {
+    config.createAgent('pinned', { description: 'd', prompt: 'p' });
+    config.setEntityLock('agent', 'pinned', true);
+
+    expect(() => config.applyBatch([
+      { op: 'delete', entity: 'agent', name: 'pinned', options: { force: true } },
+      failingOp
+    ])).toThrow({ batchIndex: 1 });
+
+    expect(fs.existsSync(path.join(AGENT_DIR, 'pinned.md'))).toBe(true);
+    expect(config.isEntityLocked('agent', 'pinned')).toBe(true);
+  });
+
+  it('logs operations only once the batch commits', () => {
+    config.setOperationLog(true, logPath);
+
+    expect(() => config.applyBatch([
+      { op: 'create', entity: 'agent', name: 'unlogged', config: { description: 'd', prompt: 'p' } },
+      failingOp
+    ])).toThrow({ batchIndex: 1 });
+    expect(fs.existsSync(logPath)).toBe(false);
+
+    config.applyBatch([{ op: 'create', entity: 'agent', name: 'logged', config: { description: 'd', prompt: 'p' } }]);
+    const entries = fs.readFileSync(logPath, 'utf8').trim().split('\n').map((line) => JSON.parse(line));
+    expect(entries).toEqual([expect.objectContaining({ operation: 'create', name: 'logged' })]);
+  });
+
+  it('writes config directly once the batch has returned', () => {
+    config.applyBatch([{ op: 'setting', key: 'theme', value: 'dark' }]);
+    config.writeConfig({ theme: 'light' });
+
+    expect(readJson()).toEqual({ theme: 'light' });
+  });
+});
+
+describe('scheduled config writes', () => {
+  beforeEach(() => {
+    config.setConfigWriteDelay(60_000);
+  });
+
+  afterEach(() => {
+    config.flushPendingWrites();
+    config.setConfigWriteDelay(500);
+  });
+
+  it('writes the config as it was when scheduled', () => {
+    const scheduled = { theme: 'dark' };
+    config.scheduleConfigWrite(scheduled);
+    scheduled.theme = 'light';
+
+    config.flushPendingWrites();
+    expect(readJson()).toEqual({ theme: 'dark' });
+  });
+
+  it('serves reads from the pending config', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ theme: 'light' }));
+    config.scheduleConfigWrite({ theme: 'dark' });
+
+    expect(config.readConfig().theme).toBe('dark');
+  });
+
+  it('keeps a pending change through a synchronous read-modify-write of the same file', () => {
+    config.scheduleConfigWrite({ theme: 'dark' });
+    config.createAgent('json-only', { description: 'd', prompt: 'p' });
+    config.setAgentStorage('json-only', 'json');
+
+    expect(readJson()).toMatchObject({ theme: 'dark', agent: { 'json-only': { description: 'd' } } });
+    expect(config.flushPendingWrites().written).toEqual([]);
+    expect(readJson().theme).toBe('dark');
+  });
+});
+
+describe('readConfigWithDiagnostics', () => {
+  it('reports duplicate keys with their line', () => {
+    writeFile(CONFIG_FILE, '{\n  "theme": "light",\n  "agent": {\n    "a": {},\n    "a": { "model": "x/y" }\n  },\n  "theme": "dark"\n}\n');
+
+    const { config: result, diagnostics } = config.readConfigWithDiagnostics(CONFIG_FILE);
+    expect(result.theme).toBe('dark');
+    expect(diagnostics).toEqual([
+      expect.objectContaining({ type: 'duplicate-key', pointer: '/agent/a', line: 5 }),
+      expect.objectContaining({ type: 'duplicate-key', pointer: '/theme', line: 7 })
+    ]);
+  });
+
+  it('counts comments that a rewrite would drop', () => {
+    writeFile(CONFIG_FILE, '{\n  // first\n  "theme": "dark" /* second */\n}\n');
+
+    expect(config.readConfigWithDiagnostics(CONFIG_FILE).diagnostics).toEqual([
+      expect.objectContaining({ type: 'comments', count: 2 })
+    ]);
+  });
+
+  it('does not warn about comments in .jsonc files, which keep them', () => {
+    const jsoncFile = path.join(CONFIG_DIR, 'opencode.jsonc');
+    writeFile(jsoncFile, '{\n  // kept\n  "theme": "dark"\n}\n');
+
+    expect(config.readConfigWithDiagnostics(jsoncFile).diagnostics).toEqual([]);
+  });
+});
+
+describe('soft delete', () => {
+  const unregister = [];
+  const failJsonWrites = () => unregister.push(config.registerPreWriteHook(() => {
+    throw new Error('disk full');
+  }));
+
+  afterEach(() => {
+    while (unregister.length > 0) unregister.pop()();
+  });
+
+  it('round-trips an agent defined in md and json through the trash', () => {
+    config.createAgent('split', { description: 'd', prompt: 'p' });
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { split: { model: 'a/b' } } }));
+
+    config.deleteAgent('split', undefined, { soft: true });
+    expect(fs.existsSync(path.join(AGENT_DIR, 'split.md'))).toBe(false);
+    expect(readJson().agent.split).toBeUndefined();
+
+    const [entry] = config.listTrash();
+    config.restoreFromTrash(entry.id);
+    expect(fs.existsSync(path.join(AGENT_DIR, 'split.md'))).toBe(true);
+    expect(readJson().agent.split).toEqual({ model: 'a/b' });
+    expect(config.listTrash()).toEqual([]);
+  });
+
+  it('puts the files back when the json write fails', () => {
+    config.createAgent('split', { description: 'd', prompt: 'p' });
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { split: { model: 'a/b' } } }));
+    failJsonWrites();
+
+    expect(() => config.deleteAgent('split', undefined, { soft: true })).toThrow('disk full');
+    expect(fs.existsSync(path.join(AGENT_DIR, 'split.md'))).toBe(true);
+    expect(readJson().agent.split).toEqual({ model: 'a/b' });
+    expect(config.listTrash()).toEqual([]);
+  });
+
+  it('keeps the trash entry restorable when restoring fails', () => {
+    config.createAgent('split', { description: 'd', prompt: 'p' });
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { split: { model: 'a/b' } } }));
+    config.deleteAgent('split', undefined, { soft: true });
+    const [entry] = config.listTrash();
+    failJsonWrites();
+
+    expect(() => config.restoreFromTrash(entry.id)).toThrow('disk full');
+    expect(fs.existsSync(path.join(AGENT_DIR, 'split.md'))).toBe(false);
+
+    unregister.pop()();
+    config.restoreFromTrash(entry.id);
+    expect(fs.existsSync(path.join(AGENT_DIR, 'split.md'))).toBe(true);
+  });
+});
+
+describe('externalizeAgentPrompt', () => {
+  const unregister = [];
+
+  afterEach(() => {
+    while (unregister.length > 0) unregister.pop()();
+  });
+
+  it('writes the prompt file next to the project config that references it', () => {
+    const project = makeProject();
+    const projectConfig = path.join(project, 'opencode.json');
+    writeFile(projectConfig, JSON.stringify({ agent: { local: { description: 'd', prompt: 'Be local.' } } }));
+
+    const promptFile = config.externalizeAgentPrompt('local', undefined, project);
+
+    expect(promptFile).toBe(path.join(project, 'prompts', 'local.md'));
+    expect(fs.readFileSync(promptFile, 'utf8')).toBe('Be local.');
+    expect(readJson(projectConfig).agent.local.prompt).toBe('{file:./prompts/local.md}');
+    expect(config.getEffectiveAgent('local', project).config.prompt).toBe('Be local.');
+
+    config.inlineAgentPrompt('local', true, project);
+    expect(readJson(projectConfig).agent.local.prompt).toBe('Be local.');
+    expect(fs.existsSync(promptFile)).toBe(false);
+  });
+
+  it('removes the new prompt file when the config write fails', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { inline: { description: 'd', prompt: 'Be inline.' } } }));
+    const before = fs.readFileSync(CONFIG_FILE, 'utf8');
+    unregister.push(config.registerPreWriteHook(() => {
+      throw new Error('disk full');
+    }));
+
+    expect(() => config.externalizeAgentPrompt('inline')).toThrow('disk full');
+    expect(fs.existsSync(path.join(CONFIG_DIR, 'prompts', 'inline.md'))).toBe(false);
+    expect(fs.readFileSync(CONFIG_FILE, 'utf8')).toBe(before);
+  });
+});
+
+describe('relocateField', () => {
+  const unregister = [];
+
+  afterEach(() => {
+    while (unregister.length > 0) unregister.pop()();
+  });
+
+  it('inlines a {file:...} prompt when moving it to md', () => {
+    writeFile(path.join(CONFIG_DIR, 'prompts', 'filed.md'), 'From the file.');
+    writeFile(path.join(AGENT_DIR, 'filed.md'), '---\ndescription: d\n---\n');
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { filed: { prompt: '{file:./prompts/filed.md}' } } }));
+
+    config.relocateField('agent', 'filed', 'prompt', 'md');
+
+    expect(fs.readFileSync(path.join(AGENT_DIR, 'filed.md'), 'utf8')).toContain('From the file.');
+    expect(readJson().agent).toBeUndefined();
+    expect(fs.existsSync(path.join(CONFIG_DIR, 'prompts', 'filed.md'))).toBe(true);
+  });
+
+  it('restores the md file when the json write fails', () => {
+    writeFile(path.join(AGENT_DIR, 'split.md'), '---\ndescription: d\n---\n');
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { split: { model: 'a/b' } } }));
+    const mdBefore = fs.readFileSync(path.join(AGENT_DIR, 'split.md'), 'utf8');
+    unregister.push(config.registerPreWriteHook(() => {
+      throw new Error('disk full');
+    }));
+
+    expect(() => config.relocateField('agent', 'split', 'model', 'md')).toThrow('disk full');
+    expect(fs.readFileSync(path.join(AGENT_DIR, 'split.md'), 'utf8')).toBe(mdBefore);
+  });
+
+  it('refuses a stale ifMatch', () => {
+    config.createAgent('tagged', { description: 'd', model: 'a/b', prompt: 'p' });
+
+    expect(() => config.relocateField('agent', 'tagged', 'model', 'json', undefined, { ifMatch: 'stale' })).toThrow({
+      code: config.CONFIG_ERROR.PRECONDITION_FAILED
+    });
+    expect(fs.existsSync(CONFIG_FILE)).toBe(false);
+  });
+});
+
+describe('collectReferencedModels', () => {
+  it('counts agents that inherit the default model', () => {
+    writeFile(CONFIG_FILE, JSON.stringify({ model: 'a/default' }));
+    writeFile(path.join(AGENT_DIR, 'inheriting.md'), '---\ndescription: d\n---\nBody\n');
+    writeFile(path.join(AGENT_DIR, 'pinned.md'), '---\ndescription: d\nmodel: b/own\n---\nBody\n');
+
+    const references = config.collectReferencedModels();
+    expect(references.find((reference) => reference.model === 'a/default').owners).toEqual(
+      expect.arrayContaining([{ kind: 'default', name: null }, { kind: 'agent', name: 'inheriting' }])
+    );
+    expect(references.find((reference) => reference.model === 'b/own').owners).toEqual([{ kind: 'agent', name: 'pinned' }]);
+  });
+});
+
+describe('name-only listings', () => {
+  // Paths of .md files read or opened while `run` executes
+  const collectMdReads = (run) => {
+    const { openSync, readFileSync } = fs;
+    const opened = [];
+    const track = (target) => {
+      if (typeof target === 'string' && target.endsWith('.md')) opened.push(target);
+    };
+    fs.openSync = (target, ...rest) => {
+      track(target);
+      return openSync(target, ...rest);
+    };
+    fs.readFileSync = (target, ...rest) => {
+      track(target);
+      return readFileSync(target, ...rest);
+    };
+    try {
+      return { result: run(), opened };
+    } finally {
+      fs.openSync = openSync;
+      fs.readFileSync = readFileSync;
+    }
+  };
+
+  it('lists agent and command names without opening any .md file', () => {
+    writeFile(path.join(AGENT_DIR, 'from-md.md'), '---\ndescription: d\n---\nBody\n');
+    writeFile(path.join(CONFIG_DIR, 'commands', 'nested', 'deep.md'), '---\ndescription: d\n---\nBody\n');
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { 'from-json': { description: 'd' } }, command: { json: { template: 't' } } }));
+
+    const agents = collectMdReads(() => config.listAgentNames());
+    const commands = collectMdReads(() => config.listCommandNames());
+
+    expect(agents.result).toEqual(expect.arrayContaining(['from-json', 'from-md']));
+    expect(commands.result.map((command) => command.name)).toEqual(expect.arrayContaining(['json', 'nested/deep']));
+    expect([...agents.opened, ...commands.opened]).toEqual([]);
+  });
+});
+
+describe('read-only agent search dirs', () => {
+  const sharedDir = path.join(HOME, 'shared-agents');
+
+  beforeEach(() => {
+    fs.rmSync(sharedDir, { recursive: true, force: true });
+    config.setAgentSearchPath([sharedDir]);
+  });
+
+  afterEach(() => {
+    config.setAgentSearchPath(null);
+  });
+
+  it('refuses to delete an agent defined only in a shared dir', () => {
+    const sharedPath = path.join(sharedDir, 'shared.md');
+    writeFile(sharedPath, '---\ndescription: d\n---\nBody\n');
+
+    expect(() => config.deleteAgent('shared')).toThrow({ code: config.CONFIG_ERROR.NOT_WRITABLE, path: sharedPath });
+    expect(fs.existsSync(sharedPath)).toBe(true);
+    expect(fs.existsSync(CONFIG_FILE)).toBe(false);
+  });
+});
+
+describe('setAllBuiltinsEnabled', () => {
+  it('removes disables from the project config too when enabling', () => {
+    const project = makeProject();
+    const projectConfig = path.join(project, 'opencode.json');
+    writeFile(CONFIG_FILE, JSON.stringify({ agent: { build: { disable: true } } }));
+    writeFile(projectConfig, JSON.stringify({ agent: { plan: { disable: true, model: 'a/b' } } }));
+
+    expect(config.setAllBuiltinsEnabled(true, ['build', 'plan'], project)).toEqual(['build', 'plan']);
+    expect(readJson()).toEqual({});
+    expect(readJson(projectConfig)).toEqual({ agent: { plan: { model: 'a/b' } } });
+  });
+
+  it('disables in the user config only', () => {
+    const project = makeProject();
+
+    expect(config.setAllBuiltinsEnabled(false, ['build'], project)).toEqual(['build']);
+    expect(readJson()).toEqual({ agent: { build: { disable: true } } });
+    expect(fs.existsSync(path.join(project, 'opencode.json'))).toBe(false);
+  });
+});
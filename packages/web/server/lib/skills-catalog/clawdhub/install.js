@@ -10,14 +10,15 @@ import os from 'os';
 import path from 'path';
 import AdmZip from 'adm-zip';
 
+import { configPaths } from '../../opencode-config.js';
 import { downloadClawdHubSkill, fetchClawdHubSkillInfo } from './api.js';
 
 const SKILL_NAME_PATTERN = /^[a-z0-9][a-z0-9-]*[a-z0-9]$|^[a-z0-9]$/;
 
 function normalizeUserSkillDir(userSkillDir) {
   if (!userSkillDir) return null;
-  const legacySkillDir = path.join(os.homedir(), '.config', 'opencode', 'skill');
-  const pluralSkillDir = path.join(os.homedir(), '.config', 'opencode', 'skills');
+  const { configDir, skillDir: pluralSkillDir } = configPaths();
+  const legacySkillDir = path.join(configDir, 'skill');
   if (userSkillDir === legacySkillDir) {
     if (fs.existsSync(legacySkillDir) && !fs.existsSync(pluralSkillDir)) return legacySkillDir;
     return pluralSkillDir;